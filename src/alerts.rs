@@ -0,0 +1,302 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::persistence::SQLitePersistence;
+use crate::util::BtcAddressType;
+use crate::utxo_store::BlockDelta;
+use crate::AppError;
+
+/// How urgently an alert needs attention.  Ordered from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub const ALL: &'static [Severity] = &[Severity::Info, Severity::Warning, Severity::Critical];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(format!("Unknown severity: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An alert raised by a rule for a scanned block, as served by `/api/alerts`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Alert {
+    pub block_height: usize,
+    /// Unix timestamp of the block.
+    pub block_time: i64,
+    pub address_type: String,
+    /// Name of the rule that raised the alert.  ie: "early_spend"
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    /// Satoshis moved by the spends that triggered the rule.
+    pub value_sats: i64,
+}
+
+/// A rule evaluated against the changes every scanned block makes to an address type.
+#[derive(Clone, Debug)]
+pub enum AlertRule {
+    /// More than `min_sats` held by outputs created below `created_before_height` spent in one block.
+    EarlySpend { created_before_height: u64, min_sats: i64 },
+    /// The satoshis spent in one block exceed `multiplier` times the average of the `window_blocks` blocks before it.
+    SpendRateSpike { multiplier: f64, window_blocks: u64 },
+    /// Any spend of an output in the cohort loaded from COHORT_FILE.
+    CohortSpend,
+}
+
+impl AlertRule {
+    /// Reads the thresholds of the rules from the ALERT_* environment variables.
+    pub fn from_env() -> Result<Vec<AlertRule>, AppError> {
+        let early_spend_btc: f64 = env_or("ALERT_EARLY_SPEND_BTC", 50.0)?;
+        Ok(vec![
+            AlertRule::EarlySpend {
+                created_before_height: env_or("ALERT_EARLY_SPEND_CREATED_BEFORE_HEIGHT", 100_000)?,
+                min_sats: (early_spend_btc * 100_000_000.0) as i64,
+            },
+            AlertRule::SpendRateSpike {
+                multiplier: env_or("ALERT_SPEND_RATE_MULTIPLIER", 10.0)?,
+                window_blocks: env_or("ALERT_SPEND_RATE_WINDOW_BLOCKS", 1000)?,
+            },
+            AlertRule::CohortSpend,
+        ])
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertRule::EarlySpend { .. } => "early_spend",
+            AlertRule::SpendRateSpike { .. } => "spend_rate_spike",
+            AlertRule::CohortSpend => "cohort_spend",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            AlertRule::EarlySpend { .. } => Severity::Critical,
+            AlertRule::SpendRateSpike { .. } => Severity::Warning,
+            AlertRule::CohortSpend => Severity::Critical,
+        }
+    }
+
+    /// Returns the message of the alert the rule raises for the block and the satoshis moved
+    /// by the spends that triggered it, if it is triggered.
+    /// Must be called before the block is committed.
+    async fn evaluate(
+        &self,
+        sqlite: &SQLitePersistence,
+        address_type: BtcAddressType,
+        delta: &BlockDelta,
+    ) -> anyhow::Result<Option<(String, i64)>> {
+        let height = delta.block_height;
+        match self {
+            AlertRule::EarlySpend { created_before_height, min_sats } => {
+                let spent_sats: i64 = delta
+                    .spend_events
+                    .iter()
+                    .filter(|spend| spend.created_height.is_some_and(|created| (created as u64) < *created_before_height))
+                    .map(|spend| spend.value)
+                    .sum();
+                Ok((spent_sats > *min_sats).then(|| {
+                    let message = format!(
+                        "{} BTC of {} outputs created before block {} spent in block {}",
+                        btc(spent_sats), address_type, created_before_height, height
+                    );
+                    (message, spent_sats)
+                }))
+            }
+            AlertRule::SpendRateSpike { multiplier, window_blocks } => {
+                // Not enough history to average over
+                if *window_blocks == 0 || height < *window_blocks {
+                    return Ok(None);
+                }
+                let spent_sats: i64 = delta.spend_events.iter().map(|spend| spend.value).sum();
+                let window_sats = sqlite
+                    .get_spent_sats_between(address_type.as_str().to_string(), height - window_blocks, height - 1)
+                    .await?;
+                let average = window_sats as f64 / *window_blocks as f64;
+                // Nothing to compare with after a window without spends
+                if average == 0.0 || (spent_sats as f64) <= multiplier * average {
+                    return Ok(None);
+                }
+                let message = format!(
+                    "{} BTC of {} outputs spent in block {}, {:.1}x the average of the previous {} blocks",
+                    btc(spent_sats),
+                    address_type,
+                    height,
+                    spent_sats as f64 / average,
+                    window_blocks
+                );
+                Ok(Some((message, spent_sats)))
+            }
+            AlertRule::CohortSpend => {
+                let spends = delta.spend_events.iter().filter(|spend| spend.high_priority);
+                let (count, spent_sats) = spends.fold((0, 0), |(count, sats), spend| (count + 1, sats + spend.value));
+                Ok((count > 0).then(|| {
+                    let message = format!(
+                        "{} {} outputs of the cohort holding {} BTC spent in block {}",
+                        count, address_type, btc(spent_sats), height
+                    );
+                    (message, spent_sats)
+                }))
+            }
+        }
+    }
+}
+
+/// Evaluates the rules against the changes a block makes to an address type.
+/// Returns the alerts raised, which are persisted when the block is committed.
+pub async fn evaluate_rules(
+    rules: &[AlertRule],
+    sqlite: &SQLitePersistence,
+    address_type: BtcAddressType,
+    delta: &BlockDelta,
+) -> Result<Vec<Alert>, AppError> {
+    let mut alerts = Vec::new();
+    for rule in rules {
+        if let Some((message, value_sats)) = rule.evaluate(sqlite, address_type, delta).await? {
+            alerts.push(Alert {
+                block_height: delta.block_height as usize,
+                block_time: delta.block_time as i64,
+                address_type: address_type.as_str().to_string(),
+                rule: rule.name().to_string(),
+                severity: rule.severity(),
+                message,
+                value_sats,
+            });
+        }
+    }
+    Ok(alerts)
+}
+
+/// Reads a number from the environment, or returns `default` if the variable isn't set.
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, AppError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| AppError::CustomError(format!("{} must be a number: {}", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+pub fn btc(sats: i64) -> f64 {
+    sats as f64 / 100_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    use nakamoto::common::bitcoin::{Block, OutPoint, Script};
+    use tokio::sync::broadcast;
+
+    use crate::block_source::{BlockSource, VecBlockSource};
+    use crate::test_fixtures::{self, chain, coinbase, output, p2pk_script, process, spend};
+    use crate::util::Cohort;
+
+    /// The 50 BTC output paid by the coinbase of the fixture block at `height`.
+    fn coinbase_output(height: u64) -> OutPoint {
+        OutPoint::new(coinbase(height, vec![output(5_000_000_000, p2pk_script())]).txid(), 0)
+    }
+
+    /// A chain whose blocks also spend the coinbase outputs of the heights `spends` lists for them,
+    /// to a script that isn't tracked.
+    fn chain_spending(length: u64, spends: &[(u64, &[u64])]) -> Vec<Block> {
+        chain(length, |height| {
+            spends
+                .iter()
+                .filter(|(spending_height, _)| *spending_height == height)
+                .map(|(_, spent)| {
+                    let inputs = spent.iter().map(|spent| coinbase_output(*spent)).collect::<Vec<_>>();
+                    spend(&inputs, vec![output(spent.len() as u64 * 5_000_000_000, Script::new())])
+                })
+                .collect()
+        })
+    }
+
+    /// Processes the blocks at `heights` one at a time, evaluating `rules`.  Every third block triggers
+    /// a chart capture, so those heights are skipped; a new run doesn't check that blocks build on each other.
+    async fn process_heights(sqlite: &SQLitePersistence, blocks: &[Block], heights: &[u64], rules: &[AlertRule]) {
+        for height in heights {
+            let block_source = VecBlockSource::new(blocks.to_vec());
+            block_source.request_blocks(*height..=*height).unwrap();
+            process(block_source, sqlite, broadcast::channel(100).0, rules).await;
+        }
+    }
+
+    /// The height, rule and value of the persisted P2PK alerts, oldest first.
+    async fn persisted_alerts(sqlite: &SQLitePersistence) -> Vec<(usize, String, i64)> {
+        let alerts = sqlite.get_alerts(BtcAddressType::P2PK, Severity::Info, 100, 0).await.unwrap();
+        alerts.into_iter().rev().map(|alert| (alert.block_height, alert.rule, alert.value_sats)).collect()
+    }
+
+    #[tokio::test]
+    async fn early_spend_alerts_above_the_threshold_for_outputs_created_before_the_cutoff() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let rules = [AlertRule::EarlySpend { created_before_height: 5, min_sats: 5_000_000_000 }];
+        // Block 4 spends 50 BTC, which doesn't exceed the threshold.  Block 7 spends 100 BTC created before
+        // block 5, and block 8 spends 100 BTC created at and after it.
+        let blocks = chain_spending(9, &[(4, &[1]), (7, &[2, 4]), (8, &[5, 7])]);
+
+        process_heights(&sqlite, &blocks, &[1, 2, 4, 5, 7, 8], &rules).await;
+        assert_eq!(persisted_alerts(&sqlite).await, vec![(7, "early_spend".to_string(), 10_000_000_000)]);
+    }
+
+    #[tokio::test]
+    async fn spend_rate_spike_alerts_above_the_average_of_the_window() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let rules = [AlertRule::SpendRateSpike { multiplier: 2.0, window_blocks: 2 }];
+        // Nothing was spent in the window of block 4, so there's no average to compare with.  The window of
+        // block 5 averages 25 BTC, so its 50 BTC spend is at the limit, while block 7 spends 100 BTC.
+        let blocks = chain_spending(8, &[(4, &[1]), (5, &[2]), (7, &[4, 5])]);
+
+        process_heights(&sqlite, &blocks, &[1, 2, 4, 5, 7], &rules).await;
+        assert_eq!(persisted_alerts(&sqlite).await, vec![(7, "spend_rate_spike".to_string(), 10_000_000_000)]);
+    }
+
+    #[tokio::test]
+    async fn cohort_spend_alerts_on_spends_of_cohort_outputs() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let rules = [AlertRule::CohortSpend];
+        let blocks = chain_spending(6, &[(4, &[2]), (5, &[1])]);
+
+        process_heights(&sqlite, &blocks, &[1, 2], &rules).await;
+        let cohort = Cohort {
+            heights: HashSet::from([1]),
+            outpoints: HashSet::new(),
+        };
+        sqlite.tag_cohort(BtcAddressType::P2PK.as_str().to_string(), &cohort).await.unwrap();
+
+        process_heights(&sqlite, &blocks, &[4, 5], &rules).await;
+        assert_eq!(persisted_alerts(&sqlite).await, vec![(5, "cohort_spend".to_string(), 5_000_000_000)]);
+    }
+}
@@ -1,22 +1,17 @@
-use crate::{persistence::SQLitePersistence, util::{self, BlockAggregateOutput, BtcAddressType}};
+use crate::{alerts::{Alert, Severity}, persistence::SQLitePersistence, util::{self, BlockAggregateOutput, BlockValueBandsOutput, BlockVintagesOutput, BtcAddressType, SpendOutput, StreamEvent, UtxoOutput, UtxoSubset}};
 use axum::{
-    extract::{Path, Query, State}, response::{sse::Event, Sse}, Json
+    extract::{OriginalUri, Path, Query, State}, http::HeaderMap, response::{sse::Event, Sse}, Json
 };
 use futures::{stream, Stream};
+use log::{error, warn};
 
 use serde::Serialize;
 use std::{convert::Infallible, sync::Arc, time::Duration};
-use tokio::sync::broadcast;
-use std::collections::HashMap;
+use tokio::sync::broadcast::{self, error::RecvError};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use serde_json::json;
 use crate::ApiError;
 
-#[derive(Serialize)]
-pub struct AggregateResponse {
-    total_utxos: i64,
-    total_sats: f64,
-}
-
 #[derive(Serialize)]
 pub struct BlockResponse {
     date: String,
@@ -24,93 +19,818 @@ pub struct BlockResponse {
     block_hash: String,
     total_utxos: u32,
     total_sats: f64,
+    coin_days_destroyed: Option<f64>,
+}
+
+impl From<BlockAggregateOutput> for BlockResponse {
+    fn from(block: BlockAggregateOutput) -> Self {
+        BlockResponse {
+            date: block.date,
+            block_height: block.block_height,
+            block_hash: block.block_hash_big_endian,
+            total_utxos: block.total_utxos,
+            total_sats: block.total_sats,
+            coin_days_destroyed: block.coin_days_destroyed,
+        }
+    }
 }
 
 pub struct AppState {
     pub(crate) db: SQLitePersistence,
-    pub(crate) sender: broadcast::Sender<BlockAggregateOutput>
+    pub(crate) sender: broadcast::Sender<StreamEvent>
 }
 
+/// Most blocks replayed from SQLite to an SSE client, whether it reconnects with `Last-Event-ID`
+/// or lags behind the broadcast channel.  Clients missing more should reload the aggregates from `/api/blocks/latest`.
+const MAX_SSE_REPLAY_BLOCKS: i64 = 1000;
+
+/// Messages sent to an SSE client: events of the topics it subscribed to, and notices about the connection itself,
+/// which are sent whatever the topics.
+#[derive(Debug)]
+enum SseMessage {
+    Event(StreamEvent),
+    /// The client fell behind the broadcast channel and missed `skipped` events.  The aggregates it missed follow,
+    /// but its other events are lost.
+    Lagged { skipped: u64 },
+    /// More than `MAX_SSE_REPLAY_BLOCKS` blocks were missed, so the aggregates of blocks `from_height`
+    /// to `to_height` aren't replayed.
+    ReplayTruncated { from_height: usize, to_height: usize },
+}
+
+impl SseMessage {
+    fn to_sse_event(&self) -> Event {
+        match self {
+            SseMessage::Event(event) => {
+                let sse_event = Event::default().event(event.topic()).data(event.data().to_string());
+                match event {
+                    StreamEvent::Aggregate(_, block) => sse_event.id(block.block_height.to_string()),
+                    StreamEvent::Reorg(reorg) => sse_event.id(reorg.fork_height.to_string()),
+                    _ => sse_event,
+                }
+            }
+            SseMessage::Lagged { skipped } => {
+                Event::default().event("lagged").data(json!({ "skipped": skipped }).to_string())
+            }
+            SseMessage::ReplayTruncated { from_height, to_height } => Event::default()
+                .event("replay_truncated")
+                .data(json!({ "from_height": from_height, "to_height": to_height }).to_string()),
+        }
+    }
+}
+
+/// Events of one SSE connection: the aggregates it missed, read back from SQLite, followed by live events.
+struct BlockStream {
+    db: SQLitePersistence,
+    rx: broadcast::Receiver<StreamEvent>,
+    /// Topics the client subscribed to.  Events of other topics are skipped.
+    topics: Vec<&'static str>,
+    /// Height of the last block sent.  Live aggregates at or below it were already replayed and are skipped.
+    last_height: Option<usize>,
+    /// Notices to send ahead of the replayed aggregates.
+    notices: VecDeque<SseMessage>,
+    replay: VecDeque<BlockAggregateOutput>,
+}
+
+impl BlockStream {
+    /// Queues the stored P2PK aggregates above `height`, or all of them if `height` is None, for replay.
+    /// Only the latest `MAX_SSE_REPLAY_BLOCKS` are, with a notice of the blocks left out.
+    async fn replay_after(&mut self, height: Option<usize>) {
+        let after = height.map_or(-1, |height| height as i64);
+        // One more block than is replayed tells whether any are left out
+        let mut blocks = match self
+            .db
+            .get_block_aggregates_after(BtcAddressType::P2PK.as_str().to_string(), after, MAX_SSE_REPLAY_BLOCKS + 1)
+            .await
+        {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                error!("Failed to read the blocks to replay after height {:?}: {}", height, e);
+                return;
+            }
+        };
+        if blocks.len() as i64 > MAX_SSE_REPLAY_BLOCKS {
+            blocks.remove(0);
+            let from_height = (after + 1) as usize;
+            let to_height = blocks[0].block_height - 1;
+            warn!(
+                "SSE client missed more than {} blocks; blocks {} to {} aren't replayed",
+                MAX_SSE_REPLAY_BLOCKS, from_height, to_height
+            );
+            self.notices.push_back(SseMessage::ReplayTruncated { from_height, to_height });
+        }
+        self.replay.extend(blocks);
+    }
+
+    /// Returns the next notice or event of a subscribed topic, or None once the broadcast channel is closed.
+    async fn next_message(&mut self) -> Option<SseMessage> {
+        loop {
+            if let Some(notice) = self.notices.pop_front() {
+                return Some(notice);
+            }
+            let event = match self.replay.pop_front() {
+                Some(block) => {
+                    self.last_height = Some(block.block_height);
+                    StreamEvent::Aggregate(BtcAddressType::P2PK, block)
+                }
+                None => match self.rx.recv().await {
+                    Ok(StreamEvent::Aggregate(address_type, block)) => {
+                        // The stream carries P2PK aggregates only
+                        if address_type != BtcAddressType::P2PK
+                            || self.last_height.is_some_and(|height| block.block_height <= height)
+                        {
+                            continue;
+                        }
+                        self.last_height = Some(block.block_height);
+                        StreamEvent::Aggregate(address_type, block)
+                    }
+                    Ok(StreamEvent::Reorg(reorg)) => {
+                        // The blocks of the new chain follow from the fork
+                        self.last_height = Some(reorg.fork_height);
+                        StreamEvent::Reorg(reorg)
+                    }
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Every block is committed before it is broadcast, so the skipped aggregates can be read
+                        // back.  Other skipped events are lost, which the client is told about
+                        warn!("SSE client lagged behind by {} events; replaying the missed aggregates from SQLite", skipped);
+                        self.notices.push_back(SseMessage::Lagged { skipped });
+                        self.replay_after(self.last_height).await;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+            if self.topics.contains(&event.topic()) {
+                return Some(SseMessage::Event(event));
+            }
+        }
+    }
+}
+
+/// Streams the events of new blocks as SSE, each named after its topic.  The `topics` query parameter, a comma
+/// separated list of topics, subscribes to a subset of them; all topics are sent by default.
+///
+/// Aggregate and reorg events carry the height of their block (the fork height for reorgs) as their id.
+/// A client reconnecting with `Last-Event-ID` is first sent the aggregates stored since that block,
+/// up to `MAX_SSE_REPLAY_BLOCKS`, and a `replay_truncated` event, whatever its topics, if it missed more.
+/// A client too slow to keep up is sent a `lagged` event, then the aggregates it missed.
 pub(crate) async fn stream_blocks(
     State(state): State<Arc<AppState>>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let topics = match params.get("topics") {
+        Some(topics) => topics
+            .split(',')
+            .map(|topic| {
+                StreamEvent::TOPICS
+                    .iter()
+                    .find(|known| **known == topic.trim())
+                    .copied()
+                    .ok_or_else(|| {
+                        ApiError::bad_request(
+                            "topics",
+                            format!(
+                                "topics must be a comma separated list of {}: {}",
+                                StreamEvent::TOPICS.join(", "),
+                                topic
+                            ),
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => StreamEvent::TOPICS.to_vec(),
+    };
+
+    // Subscribe before reading SQLite, so that no block falls between the replay and the live events
     let rx = state.sender.subscribe();
+    let last_stored_height = state.db
+        .get_last_block_height(BtcAddressType::P2PK.as_str().to_string())
+        .await
+        .map_err(ApiError::internal)?
+        .map(|height| height as usize);
+    let last_event_id = match headers.get("last-event-id") {
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.trim().parse::<usize>().ok())
+                .ok_or_else(|| ApiError::bad_request("Last-Event-ID", "Last-Event-ID must be a block height"))?,
+        ),
+        None => None,
+    };
 
-    let stream = stream::unfold(rx, move |mut rx| async move {
-        let msg = rx.recv().await.ok()?;
-        let event = Event::default().data(serde_json::to_string(&msg).unwrap());
-        Some((Ok(event), rx))
+    let mut blocks = BlockStream {
+        db: state.db.clone(),
+        rx,
+        topics,
+        last_height: last_stored_height,
+        notices: VecDeque::new(),
+        replay: VecDeque::new(),
+    };
+    if let Some(last_event_id) = last_event_id {
+        // Blocks above the last stored one were rolled back while the client was away
+        blocks.last_height = last_stored_height.map(|height| height.min(last_event_id));
+        blocks.replay_after(blocks.last_height).await;
+    }
+
+    let stream = stream::unfold(blocks, |mut blocks| async move {
+        let message = blocks.next_message().await?;
+        Some((Ok(message.to_sse_event()), blocks))
     });
 
-    Sse::new(stream).keep_alive(
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive-text"),
-    )
+    ))
 }
 
 pub async fn get_latest_block_aggregates(
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
-) -> Json<Vec<BlockAggregateOutput>> {
-    // Parse address_type from query params, default to None (which will be P2PK)
-    let address_type = params.get("address_type")
-        .and_then(|s| s.parse::<BtcAddressType>().ok());
-    
-    // Parse num_latest_blocks from query params, default to None (which returns all blocks)
-    let num_latest_blocks = params.get("num_latest_blocks")
-        .and_then(|s| s.parse::<i64>().ok());
-
-    // Parse result_sampling_interval from query params, default to None (which returns every 10th result)
-    let result_sampling_interval = params.get("result_sampling_interval")
-        .and_then(|s| s.parse::<i64>().ok());
+) -> Result<Json<Vec<BlockAggregateOutput>>, ApiError> {
+    let address_type = parse_address_type_param(&params)?;
+
+    // num_latest_blocks defaults to None (which returns all blocks)
+    // and result_sampling_interval to None (which returns every 10th result)
+    let num_latest_blocks = parse_integer_param(&params, "num_latest_blocks", 0)?;
+    let result_sampling_interval = parse_integer_param(&params, "result_sampling_interval", 1)?;
+
+    // Parse origin from query params, default to None (which returns the totals of all coins)
+    let origin = match params.get("origin").map(String::as_str) {
+        Some("coinbase") => Some(UtxoSubset::Coinbase),
+        Some("non_coinbase") => Some(UtxoSubset::NonCoinbase),
+        Some(_) => return Err(ApiError::bad_request("origin", "origin must be coinbase or non_coinbase")),
+        None => None,
+    };
+
+    // Parse cohort from query params; cohort=true returns the totals of the cohort loaded from COHORT_FILE instead
+    let subset = match params.get("cohort").map(String::as_str) {
+        Some("true") => Some(UtxoSubset::Cohort),
+        Some("false") | None => origin,
+        Some(_) => return Err(ApiError::bad_request("cohort", "cohort must be true or false")),
+    };
 
     let aggregates = state.db
-        .get_latest_block_aggregates(address_type, num_latest_blocks, result_sampling_interval)
+        .get_latest_block_aggregates(Some(address_type), num_latest_blocks, result_sampling_interval)
+        .await
+        .map_err(ApiError::internal)?;
+
+    // Blocks whose aggregates don't split out the subset are left out
+    let aggregates = match subset {
+        Some(subset) => aggregates.into_iter().filter_map(|aggregate| aggregate.only_subset(subset)).collect(),
+        None => aggregates,
+    };
+
+    Ok(Json(aggregates))
+}
+
+pub async fn get_latest_block_vintages(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<BlockVintagesOutput>>, ApiError> {
+    let address_type = parse_address_type_param(&params)?;
+    if !address_type.tracks_vintages() {
+        return Err(ApiError::not_found(format!("Vintages are not tracked for address type: {}", address_type)));
+    }
+
+    let num_latest_blocks = parse_integer_param(&params, "num_latest_blocks", 0)?;
+    let result_sampling_interval = parse_integer_param(&params, "result_sampling_interval", 1)?;
+
+    let vintages = state.db
+        .get_latest_block_vintages(address_type, num_latest_blocks, result_sampling_interval)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(vintages))
+}
+
+pub async fn get_latest_block_value_bands(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<BlockValueBandsOutput>>, ApiError> {
+    let address_type = parse_address_type_param(&params)?;
+
+    let num_latest_blocks = parse_integer_param(&params, "num_latest_blocks", 0)?;
+    let result_sampling_interval = parse_integer_param(&params, "result_sampling_interval", 1)?;
+
+    let value_bands = state.db
+        .get_latest_block_value_bands(address_type, num_latest_blocks, result_sampling_interval)
         .await
-        .unwrap_or_default();
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(value_bands))
+}
+
+/// A block looked up by the path of a request, either by hash or by height.
+enum BlockId {
+    Hash(String),
+    Height(i64),
+}
+
+impl BlockId {
+    fn parse_hash(hash: &str) -> Result<Self, ApiError> {
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ApiError::bad_request("hash", "hash must be 64 hexadecimal characters"));
+        }
+        Ok(BlockId::Hash(hash.to_lowercase()))
+    }
+
+    fn parse_height(height: &str) -> Result<Self, ApiError> {
+        height
+            .parse::<i64>()
+            .ok()
+            .filter(|height| *height >= 0)
+            .map(BlockId::Height)
+            .ok_or_else(|| ApiError::bad_request("height", "height must be a non-negative integer"))
+    }
+
+    /// Returns the aggregates of the block for one address type, if it has been scanned.
+    async fn find(
+        &self,
+        db: &SQLitePersistence,
+        address_type: BtcAddressType,
+    ) -> Result<Option<BlockAggregateOutput>, ApiError> {
+        let table_type = address_type.as_str().to_string();
+        match self {
+            BlockId::Hash(hash) => db.get_block_by_hash(table_type, hash).await,
+            BlockId::Height(height) => db.get_block_by_height(table_type, *height).await,
+        }
+        .map_err(ApiError::internal)
+    }
 
-    Json(aggregates)
+    fn not_found(&self) -> ApiError {
+        match self {
+            BlockId::Hash(hash) => ApiError::not_found(format!("No block found with hash: {}", hash)),
+            BlockId::Height(height) => ApiError::not_found(format!("No block found at height: {}", height)),
+        }
+    }
+}
+
+/// Totals of one address type in `CombinedBlockResponse`.
+#[derive(Serialize)]
+pub struct AddressTypeTotals {
+    total_utxos: u32,
+    total_sats: f64,
+    coin_days_destroyed: Option<f64>,
+}
+
+/// Aggregates of a block for every tracked address type.
+#[derive(Serialize)]
+pub struct CombinedBlockResponse {
+    date: String,
+    block_height: usize,
+    block_hash: String,
+    /// Sums over the address types, which don't overlap.
+    total_utxos: u32,
+    total_sats: f64,
+    /// Totals of each tracked address type, null for those without aggregates of the block.
+    address_types: BTreeMap<&'static str, Option<AddressTypeTotals>>,
+}
+
+async fn find_block(
+    state: &AppState,
+    address_type: BtcAddressType,
+    block: BlockId,
+) -> Result<Json<BlockResponse>, ApiError> {
+    let aggregate = block.find(&state.db, address_type).await?.ok_or_else(|| block.not_found())?;
+    Ok(Json(BlockResponse::from(aggregate)))
+}
+
+async fn find_combined_block(state: &AppState, block: BlockId) -> Result<Json<CombinedBlockResponse>, ApiError> {
+    let mut aggregates = Vec::new();
+    for address_type in BtcAddressType::TRACKED {
+        aggregates.push((address_type.as_str(), block.find(&state.db, *address_type).await?));
+    }
+
+    let found = || aggregates.iter().filter_map(|(_, aggregate)| aggregate.as_ref());
+    let first = found().next().ok_or_else(|| block.not_found())?;
+    let response = CombinedBlockResponse {
+        date: first.date.clone(),
+        block_height: first.block_height,
+        block_hash: first.block_hash_big_endian.clone(),
+        total_utxos: found().map(|aggregate| aggregate.total_utxos).sum(),
+        total_sats: found().map(|aggregate| aggregate.total_sats).sum(),
+        address_types: aggregates
+            .iter()
+            .map(|(address_type, aggregate)| {
+                let totals = aggregate.as_ref().map(|aggregate| AddressTypeTotals {
+                    total_utxos: aggregate.total_utxos,
+                    total_sats: aggregate.total_sats,
+                    coin_days_destroyed: aggregate.coin_days_destroyed,
+                });
+                (*address_type, totals)
+            })
+            .collect(),
+    };
+
+    Ok(Json(response))
 }
 
 pub async fn get_block_by_hash(
     State(state): State<Arc<AppState>>,
     Path(hash): Path<String>,
-) -> Json<Option<BlockResponse>> {
-    let block = state.db.get_block_by_hash(BtcAddressType::P2PK.as_str().to_string(), &hash).await.unwrap();
-
-    Json(block.map(|b| BlockResponse {
-        date: b.date,
-        block_height: b.block_height,
-        block_hash: b.block_hash_big_endian,
-        total_utxos: b.total_utxos as u32,
-        total_sats: b.total_sats,
-    }))
+) -> Result<Json<BlockResponse>, ApiError> {
+    find_block(&state, BtcAddressType::P2PK, BlockId::parse_hash(&hash)?).await
 }
 
 pub async fn get_block_by_height(
     State(state): State<Arc<AppState>>,
-    Path(height): Path<i64>,
-) -> Json<Option<BlockResponse>> {
-    let block = state.db.get_block_by_height(BtcAddressType::P2PK.as_str().to_string(), height).await.unwrap();
+    Path(height): Path<String>,
+) -> Result<Json<BlockResponse>, ApiError> {
+    find_block(&state, BtcAddressType::P2PK, BlockId::parse_height(&height)?).await
+}
 
-    Json(block.map(|b| BlockResponse {
-        date: b.date,
-        block_height: b.block_height,
-        block_hash: b.block_hash_big_endian,
-        total_utxos: b.total_utxos as u32,
-        total_sats: b.total_sats,
-    }))
+pub async fn get_address_type_block_by_hash(
+    State(state): State<Arc<AppState>>,
+    Path((address_type, hash)): Path<(String, String)>,
+) -> Result<Json<BlockResponse>, ApiError> {
+    find_block(&state, parse_address_type(&address_type)?, BlockId::parse_hash(&hash)?).await
+}
+
+pub async fn get_address_type_block_by_height(
+    State(state): State<Arc<AppState>>,
+    Path((address_type, height)): Path<(String, String)>,
+) -> Result<Json<BlockResponse>, ApiError> {
+    find_block(&state, parse_address_type(&address_type)?, BlockId::parse_height(&height)?).await
+}
+
+pub async fn get_combined_block_by_hash(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+) -> Result<Json<CombinedBlockResponse>, ApiError> {
+    find_combined_block(&state, BlockId::parse_hash(&hash)?).await
+}
+
+pub async fn get_combined_block_by_height(
+    State(state): State<Arc<AppState>>,
+    Path(height): Path<String>,
+) -> Result<Json<CombinedBlockResponse>, ApiError> {
+    find_combined_block(&state, BlockId::parse_height(&height)?).await
+}
+
+/// Number of records returned by `get_unspent_outputs`, `get_spends` and `get_alerts` when no limit is given.
+const DEFAULT_UTXO_LIMIT: i64 = 100;
+/// Upper bound on the limit accepted by `get_unspent_outputs`, `get_spends` and `get_alerts`.
+const MAX_UTXO_LIMIT: i64 = 1000;
+
+/// Parses an optional integer query parameter, which must be at least `min`.
+fn parse_integer_param(params: &HashMap<String, String>, name: &str, min: i64) -> Result<Option<i64>, ApiError> {
+    match params.get(name) {
+        Some(value) => match value.parse::<i64>() {
+            Ok(value) if value >= min => Ok(Some(value)),
+            _ if min == 0 => Err(ApiError::bad_request(name, format!("{} must be a non-negative integer", name))),
+            _ if min == 1 => Err(ApiError::bad_request(name, format!("{} must be a positive integer", name))),
+            _ => Err(ApiError::bad_request(name, format!("{} must be an integer of at least {}", name, min))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parses an optional non-negative integer query parameter.
+fn parse_non_negative_param(params: &HashMap<String, String>, name: &str, default: i64) -> Result<i64, ApiError> {
+    Ok(parse_integer_param(params, name, 0)?.unwrap_or(default))
+}
+
+/// Parses the address_type query parameter, which defaults to p2pk and must be a tracked address type.
+fn parse_address_type_param(params: &HashMap<String, String>) -> Result<BtcAddressType, ApiError> {
+    parse_address_type(params.get("address_type").map(String::as_str).unwrap_or("p2pk"))
+}
+
+/// Parses an address_type parameter, which must be a tracked address type.
+fn parse_address_type(address_type: &str) -> Result<BtcAddressType, ApiError> {
+    match address_type.parse::<BtcAddressType>() {
+        Ok(parsed) if BtcAddressType::TRACKED.contains(&parsed) => Ok(parsed),
+        _ => Err(ApiError::bad_request(
+            "address_type",
+            format!(
+                "address_type must be one of {}: {}",
+                BtcAddressType::TRACKED.iter().map(BtcAddressType::as_str).collect::<Vec<_>>().join(", "),
+                address_type
+            ),
+        )),
+    }
+}
+
+pub async fn get_unspent_outputs(
+    State(state): State<Arc<AppState>>,
+    Path(address_type): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<UtxoOutput>>, ApiError> {
+    let address_type = parse_address_type(&address_type)?;
+
+    let min_value = parse_non_negative_param(&params, "min_value_sats", 0)?;
+    let limit = parse_non_negative_param(&params, "limit", DEFAULT_UTXO_LIMIT)?.min(MAX_UTXO_LIMIT);
+    let offset = parse_non_negative_param(&params, "offset", 0)?;
+
+    let utxos = state.db
+        .get_unspent_outputs(address_type.as_str().to_string(), min_value, limit, offset)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(utxos))
+}
+
+pub async fn get_spends(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<SpendOutput>>, ApiError> {
+    let address_type = parse_address_type_param(&params)?;
+
+    let min_value = parse_non_negative_param(&params, "min_value_sats", 0)?;
+    let limit = parse_non_negative_param(&params, "limit", DEFAULT_UTXO_LIMIT)?.min(MAX_UTXO_LIMIT);
+    let offset = parse_non_negative_param(&params, "offset", 0)?;
+
+    let spends = state.db
+        .get_spends(address_type.as_str().to_string(), min_value, limit, offset)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(spends))
+}
+
+pub async fn get_alerts(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<Alert>>, ApiError> {
+    let address_type = parse_address_type_param(&params)?;
+
+    let min_severity = match params.get("min_severity") {
+        Some(min_severity) => min_severity.parse::<Severity>().map_err(|_| {
+            ApiError::bad_request("min_severity", "min_severity must be one of info, warning or critical")
+        })?,
+        None => Severity::Info,
+    };
+    let limit = parse_non_negative_param(&params, "limit", DEFAULT_UTXO_LIMIT)?.min(MAX_UTXO_LIMIT);
+    let offset = parse_non_negative_param(&params, "offset", 0)?;
+
+    let alerts = state.db
+        .get_alerts(address_type, min_severity, limit, offset)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(alerts))
 }
 
 pub async fn generate_latest_p2pk_chart(
     State(_state): State<Arc<AppState>>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
 
-    util::capture_p2pk_blocks_graph(0).await.unwrap();
+    util::capture_p2pk_blocks_graph(0).await.map_err(ApiError::internal)?;
 
     // Create a JSON object with a single element
     let response = json!({ "Result": "Check logs for status of chart generation" });
 
     Ok(Json(response))
 }
+
+/// Answers requests to `/api` paths that match no route.
+pub async fn route_not_found(OriginalUri(uri): OriginalUri) -> ApiError {
+    ApiError::not_found(format!("No API route matches: {}", uri.path()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::RangeInclusive;
+
+    use axum::body::BodyDataStream;
+    use axum::response::IntoResponse;
+    use futures::StreamExt;
+    use serde_json::Value;
+
+    use crate::test_fixtures::{self, GENESIS_TIME};
+    use crate::util::SyncProgressOutput;
+    use crate::utxo_store::{BlockCommit, BlockDelta};
+
+    fn aggregate(height: u64) -> BlockAggregateOutput {
+        let date = "2009-01-09 03:54:25".to_string();
+        BlockAggregateOutput::new(date, height as usize, format!("{:064x}", height), 1, 1000.0)
+    }
+
+    /// Commits P2PK aggregates of blocks without any outputs at `heights`.
+    async fn commit_empty_blocks(db: &SQLitePersistence, heights: RangeInclusive<u64>) {
+        for height in heights {
+            let commit = BlockCommit {
+                address_type: BtcAddressType::P2PK,
+                delta: BlockDelta::new(height, GENESIS_TIME + height as u32 * 600),
+                aggregate: aggregate(height),
+                vintages: BTreeMap::new(),
+                value_bands: BTreeMap::new(),
+                alerts: Vec::new(),
+            };
+            db.commit_block(&[commit], &[]).await.unwrap();
+        }
+    }
+
+    fn sync_progress(height: usize) -> StreamEvent {
+        StreamEvent::SyncProgress(SyncProgressOutput {
+            block_height: height,
+            tip_height: height,
+            progress_percent: 100.0,
+        })
+    }
+
+    /// A client of `/api/blocks/stream`.
+    struct SseClient {
+        body: BodyDataStream,
+        buffer: String,
+    }
+
+    impl SseClient {
+        async fn connect(state: &Arc<AppState>, topics: Option<&str>, last_event_id: Option<&str>) -> Self {
+            let params = topics.map(|topics| HashMap::from([("topics".to_string(), topics.to_string())]));
+            let mut headers = HeaderMap::new();
+            if let Some(last_event_id) = last_event_id {
+                headers.insert("last-event-id", last_event_id.parse().unwrap());
+            }
+            let params = Query(params.unwrap_or_default());
+            let sse = stream_blocks(State(Arc::clone(state)), params, headers).await.unwrap();
+            SseClient {
+                body: sse.into_response().into_body().into_data_stream(),
+                buffer: String::new(),
+            }
+        }
+
+        /// Returns the name, data and id of the next event, skipping keep-alive comments.
+        async fn next_event(&mut self) -> (String, Value, Option<String>) {
+            loop {
+                if let Some(end) = self.buffer.find("\n\n") {
+                    let message = self.buffer.drain(..end + 2).collect::<String>();
+                    let (mut name, mut data, mut id) = (String::new(), Value::Null, None);
+                    for line in message.lines() {
+                        match line.split_once(':') {
+                            Some(("event", value)) => name = value.trim().to_string(),
+                            Some(("data", value)) => data = serde_json::from_str(value.trim()).unwrap(),
+                            Some(("id", value)) => id = Some(value.trim().to_string()),
+                            _ => {}
+                        }
+                    }
+                    if !name.is_empty() {
+                        return (name, data, id);
+                    }
+                    continue;
+                }
+                let chunk = tokio::time::timeout(Duration::from_secs(5), self.body.next())
+                    .await
+                    .expect("no SSE event within 5 seconds")
+                    .unwrap()
+                    .unwrap();
+                self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        }
+
+        /// Returns the name and id of the next event.
+        async fn next_id(&mut self) -> (String, Option<String>) {
+            let (name, _, id) = self.next_event().await;
+            (name, id)
+        }
+    }
+
+    fn app_state(db: &SQLitePersistence, capacity: usize) -> Arc<AppState> {
+        Arc::new(AppState {
+            db: db.clone(),
+            sender: broadcast::channel(capacity).0,
+        })
+    }
+
+    fn named(name: &str, id: u64) -> (String, Option<String>) {
+        (name.to_string(), Some(id.to_string()))
+    }
+
+    #[tokio::test]
+    async fn last_event_id_replays_the_aggregates_stored_since_that_block() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        commit_empty_blocks(&db, 1..=3).await;
+        let state = app_state(&db, 100);
+
+        let mut client = SseClient::connect(&state, None, Some("1")).await;
+        // Block 3 is broadcast after the client read it back, so only block 4 is new
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(3))).unwrap();
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(4))).unwrap();
+
+        let (name, data, id) = client.next_event().await;
+        assert_eq!((name.as_str(), id.as_deref()), ("aggregate", Some("2")));
+        assert_eq!(data["block_height"], 2);
+        assert_eq!(client.next_id().await, named("aggregate", 3));
+        assert_eq!(client.next_id().await, named("aggregate", 4));
+    }
+
+    #[tokio::test]
+    async fn replay_of_more_than_the_maximum_blocks_is_truncated_with_a_notice() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        let tip = MAX_SSE_REPLAY_BLOCKS as u64 + 3;
+        commit_empty_blocks(&db, 1..=tip).await;
+        let state = app_state(&db, 100);
+
+        let mut client = SseClient::connect(&state, None, Some("1")).await;
+        // The latest blocks are replayed, after a notice of the older ones left out
+        let (name, data, id) = client.next_event().await;
+        assert_eq!((name.as_str(), id), ("replay_truncated", None));
+        assert_eq!(data, json!({ "from_height": 2, "to_height": 3 }));
+        for height in 4..=tip {
+            assert_eq!(client.next_id().await, named("aggregate", height));
+        }
+    }
+
+    #[tokio::test]
+    async fn lagged_clients_are_replayed_the_aggregates_they_missed() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        commit_empty_blocks(&db, 1..=2).await;
+        let state = app_state(&db, 1);
+
+        let mut client = SseClient::connect(&state, None, None).await;
+        // Only the last event fits in the channel
+        commit_empty_blocks(&db, 3..=4).await;
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(3))).unwrap();
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(4))).unwrap();
+        state.sender.send(sync_progress(4)).unwrap();
+
+        let (name, data, _) = client.next_event().await;
+        assert_eq!((name.as_str(), data), ("lagged", json!({ "skipped": 2 })));
+        assert_eq!(client.next_id().await, named("aggregate", 3));
+        assert_eq!(client.next_id().await, named("aggregate", 4));
+        assert_eq!(client.next_id().await, ("sync_progress".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn topics_subscribe_to_a_subset_of_the_events() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        commit_empty_blocks(&db, 1..=1).await;
+        let state = app_state(&db, 2);
+
+        let mut client = SseClient::connect(&state, Some("sync_progress, reorg"), None).await;
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(2))).unwrap();
+        state.sender.send(sync_progress(2)).unwrap();
+        assert_eq!(client.next_id().await, ("sync_progress".to_string(), None));
+
+        // Lagging clients are told whatever their topics, and aren't sent the replayed aggregates
+        commit_empty_blocks(&db, 2..=3).await;
+        state.sender.send(StreamEvent::Aggregate(BtcAddressType::P2PK, aggregate(3))).unwrap();
+        state.sender.send(sync_progress(3)).unwrap();
+        state.sender.send(sync_progress(3)).unwrap();
+        let (name, data, _) = client.next_event().await;
+        assert_eq!((name.as_str(), data), ("lagged", json!({ "skipped": 1 })));
+        assert_eq!(client.next_id().await, ("sync_progress".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn unknown_topics_are_rejected() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        let state = app_state(&db, 1);
+        let params = HashMap::from([("topics".to_string(), "aggregate,blocks".to_string())]);
+
+        let error = match stream_blocks(State(state), Query(params), HeaderMap::new()).await {
+            Ok(_) => panic!("expected the topics to be rejected"),
+            Err(error) => error,
+        };
+        assert_eq!(error.status, axum::http::StatusCode::BAD_REQUEST);
+        assert_eq!(error.param.as_deref(), Some("topics"));
+        assert!(error.message.ends_with(": blocks"), "{}", error.message);
+    }
+
+    /// Returns the status and JSON body an error is answered with.
+    async fn error_response(error: ApiError) -> (u16, Value) {
+        let response = error.into_response();
+        let status = response.status().as_u16();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_are_answered_with_their_status_message_and_param() {
+        let (db, dir) = test_fixtures::temporary_sqlite().await;
+        commit_empty_blocks(&db, 1..=1).await;
+        let state = app_state(&db, 1);
+
+        let params = HashMap::from([("num_latest_blocks".to_string(), "ten".to_string())]);
+        let error = get_latest_block_aggregates(State(Arc::clone(&state)), Query(params)).await.err().unwrap();
+        let body = json!({
+            "error": "num_latest_blocks must be a non-negative integer",
+            "status": 400,
+            "param": "num_latest_blocks",
+        });
+        assert_eq!(error_response(error).await, (400, body));
+
+        let path = Path(("p2sh".to_string(), "1".to_string()));
+        let error = get_address_type_block_by_height(State(Arc::clone(&state)), path).await.err().unwrap();
+        let body = json!({
+            "error": "address_type must be one of p2pk, p2tr, reused_pkh, p2ms: p2sh",
+            "status": 400,
+            "param": "address_type",
+        });
+        assert_eq!(error_response(error).await, (400, body));
+
+        let error = get_block_by_height(State(Arc::clone(&state)), Path("5".to_string())).await.err().unwrap();
+        let body = json!({ "error": "No block found at height: 5", "status": 404, "param": null });
+        assert_eq!(error_response(error).await, (404, body));
+
+        // Reading the block fails once its table is gone
+        let connection = rusqlite::Connection::open(dir.path().join("gabriel.db")).unwrap();
+        connection.execute("DROP TABLE p2pk_utxo_block_aggregates", []).unwrap();
+        let error = get_block_by_height(State(Arc::clone(&state)), Path("1".to_string())).await.err().unwrap();
+        let (status, body) = error_response(error).await;
+        assert_eq!((status, &body["status"], &body["param"]), (500, &json!(500), &Value::Null));
+        assert!(body["error"].as_str().unwrap().contains("no such table"), "{}", body);
+    }
+}
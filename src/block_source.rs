@@ -0,0 +1,101 @@
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{Receiver, Sender};
+use log::{error, info};
+use nakamoto::client::traits::Handle as _;
+use nakamoto::common::bitcoin::{Block, BlockHash};
+use nakamoto::net::Waker;
+
+use crate::AppError;
+
+mod bitcoin_rpc;
+mod blk_files;
+#[cfg(test)]
+mod vec_blocks;
+
+pub use bitcoin_rpc::BitcoinRpcBlockSource;
+pub use blk_files::BlkFilesBlockSource;
+#[cfg(test)]
+pub use vec_blocks::VecBlockSource;
+
+/// Sending half of a block channel, shared between clones of a block source
+/// so that `shutdown` can disconnect the channel for all of them.
+type SharedBlockSender = Arc<Mutex<Option<Sender<(Block, u64)>>>>;
+
+/// A backend that feeds blocks to `process_blocks`.
+///
+/// Blocks are requested by height with `request_blocks` and delivered, in the
+/// order they were requested, on the channel returned by `blocks`.
+pub trait BlockSource: Clone + Send + 'static {
+    /// Returns the height of the best chain tip known to the source.
+    fn get_tip(&self) -> Result<u64, AppError>;
+
+    /// Returns the hash of the block at `height` on the source's best chain.
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, AppError>;
+
+    /// Requests the blocks in `heights`.
+    /// Heights the source doesn't know about are logged and skipped, so the number of
+    /// blocks that will actually be delivered is returned.
+    fn request_blocks(&self, heights: RangeInclusive<u64>) -> Result<usize, AppError>;
+
+    /// Returns the channel on which requested blocks are delivered along with their heights.
+    fn blocks(&self) -> Receiver<(Block, u64)>;
+
+    /// Stops the source.  Once stopped, the `blocks` channel disconnects.
+    fn shutdown(self) -> Result<(), AppError>;
+}
+
+/// Block source backed by a Nakamoto light-client connected to the P2P network.
+#[derive(Clone)]
+pub struct NakamotoBlockSource<W: Waker> {
+    handle: nakamoto::client::Handle<W>,
+}
+
+impl<W: Waker> NakamotoBlockSource<W> {
+    pub fn new(handle: nakamoto::client::Handle<W>) -> Self {
+        NakamotoBlockSource { handle }
+    }
+}
+
+impl<W: Waker + 'static> BlockSource for NakamotoBlockSource<W> {
+    fn get_tip(&self) -> Result<u64, AppError> {
+        let (tip_height, _) = self.handle.get_tip()?;
+        Ok(tip_height)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, AppError> {
+        Ok(self
+            .handle
+            .get_block_by_height(height)?
+            .map(|header| header.block_hash()))
+    }
+
+    fn request_blocks(&self, heights: RangeInclusive<u64>) -> Result<usize, AppError> {
+        let mut requested = 0;
+        for height in heights {
+            let block_hash = match self.handle.get_block_by_height(height)? {
+                Some(header) => header.block_hash(),
+                None => {
+                    error!("No block found at height {}", height);
+                    continue;
+                }
+            };
+
+            info!("Block {} hash: {:?}", height, block_hash);
+
+            self.handle.get_block(&block_hash)?;
+            requested += 1;
+        }
+        Ok(requested)
+    }
+
+    fn blocks(&self) -> Receiver<(Block, u64)> {
+        self.handle.blocks()
+    }
+
+    fn shutdown(self) -> Result<(), AppError> {
+        self.handle.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use std::env;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use crossbeam_channel::{unbounded, Receiver};
+use log::{debug, info, warn};
+use nakamoto::common::bitcoin::consensus::encode::deserialize;
+use nakamoto::common::bitcoin::hashes::hex::FromHex;
+use nakamoto::common::bitcoin::{Block, BlockHash};
+use serde_json::{json, Value};
+
+use super::{BlockSource, SharedBlockSender};
+use crate::AppError;
+
+/// Code of the RPC error bitcoind answers `getblockhash` with for a height above its tip.
+const RPC_INVALID_PARAMETER: i64 = -8;
+/// Default time to wait for the connection to bitcoind to open.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time to wait for each read of a response, so that a hung node doesn't stall the scanner.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Block source that pulls blocks from a Bitcoin Core node over JSON-RPC.
+///
+/// Blocks are fetched with `getblockhash` followed by `getblock` at verbosity 0 (raw hex).
+#[derive(Clone)]
+pub struct BitcoinRpcBlockSource {
+    agent: ureq::Agent,
+    url: String,
+    authorization: String,
+    blocks_tx: SharedBlockSender,
+    blocks_rx: Receiver<(Block, u64)>,
+}
+
+impl BitcoinRpcBlockSource {
+    /// Creates a block source for the node at `url`, authenticating with `user` and `password`.
+    pub fn new(url: String, user: &str, password: &str) -> Self {
+        let (blocks_tx, blocks_rx) = unbounded();
+        BitcoinRpcBlockSource {
+            agent: rpc_agent(DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT),
+            url,
+            authorization: format!("Basic {}", BASE64.encode(format!("{}:{}", user, password))),
+            blocks_tx: Arc::new(Mutex::new(Some(blocks_tx))),
+            blocks_rx,
+        }
+    }
+
+    /// Creates a block source configured from the environment:
+    /// - BITCOIN_RPC_URL: defaults to http://127.0.0.1:8332
+    /// - BITCOIN_RPC_COOKIE_FILE: path to bitcoind's `.cookie` file
+    /// - BITCOIN_RPC_USER / BITCOIN_RPC_PASSWORD: used when no cookie file is set
+    /// - BITCOIN_RPC_CONNECT_TIMEOUT_SECONDS / BITCOIN_RPC_READ_TIMEOUT_SECONDS: default to 10 and 60
+    pub fn from_env() -> Result<Self, AppError> {
+        let url = env::var("BITCOIN_RPC_URL").unwrap_or_else(|_| "http://127.0.0.1:8332".to_string());
+
+        let (user, password) = match env::var("BITCOIN_RPC_COOKIE_FILE") {
+            Ok(cookie_file) => read_cookie_file(&cookie_file)?,
+            Err(_) => match (env::var("BITCOIN_RPC_USER"), env::var("BITCOIN_RPC_PASSWORD")) {
+                (Ok(user), Ok(password)) => (user, password),
+                _ => {
+                    return Err(AppError::CustomError(
+                        "Set BITCOIN_RPC_COOKIE_FILE or BITCOIN_RPC_USER and BITCOIN_RPC_PASSWORD".to_owned(),
+                    ))
+                }
+            },
+        };
+
+        let connect_timeout = env_seconds("BITCOIN_RPC_CONNECT_TIMEOUT_SECONDS", DEFAULT_CONNECT_TIMEOUT)?;
+        let read_timeout = env_seconds("BITCOIN_RPC_READ_TIMEOUT_SECONDS", DEFAULT_READ_TIMEOUT)?;
+
+        info!("Using Bitcoin Core JSON-RPC at {}", url);
+        Ok(Self::new(url, &user, &password).with_timeouts(connect_timeout, read_timeout))
+    }
+
+    /// Replaces how long to wait for the connection to the node to open, and for each read of a response.
+    pub fn with_timeouts(mut self, connect_timeout: Duration, read_timeout: Duration) -> Self {
+        self.agent = rpc_agent(connect_timeout, read_timeout);
+        self
+    }
+
+    /// Performs a JSON-RPC call and returns its `result`.
+    fn call(&self, method: &str, params: Value) -> Result<Value, AppError> {
+        self.try_call(method, params)?
+            .map_err(|error| AppError::BitcoinRpcError(format!("{}: {}", method, error)))
+    }
+
+    /// Performs a JSON-RPC call and returns its `result`, or the `error` object the node answered with.
+    fn try_call(&self, method: &str, params: Value) -> Result<Result<Value, Value>, AppError> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "gabriel",
+            "method": method,
+            "params": params,
+        });
+        debug!("RPC request: {}", request);
+
+        // bitcoind answers RPC errors with a non-200 status and a JSON body describing the error
+        let body = match self
+            .agent
+            .post(&self.url)
+            .set("Authorization", &self.authorization)
+            .set("Content-Type", "application/json")
+            .send_string(&request.to_string())
+        {
+            Ok(response) => read_body(method, response)?,
+            Err(ureq::Error::Status(code, response)) => {
+                let body = read_body(method, response)?;
+                if body.is_empty() {
+                    return Err(AppError::BitcoinRpcError(format!(
+                        "{} returned HTTP status {}",
+                        method, code
+                    )));
+                }
+                body
+            }
+            Err(e) => return Err(AppError::BitcoinRpcError(format!("{}: {}", method, e))),
+        };
+
+        let mut response: Value = serde_json::from_str(&body)
+            .map_err(|e| AppError::BitcoinRpcError(format!("{}: invalid response: {}", method, e)))?;
+
+        if !response["error"].is_null() {
+            return Ok(Err(response["error"].take()));
+        }
+
+        Ok(Ok(response["result"].take()))
+    }
+
+    /// Fetches the raw block at `height`, or returns None if the node has no block at that height.
+    fn fetch_block(&self, height: u64) -> Result<Option<Block>, AppError> {
+        let block_hash = match self.get_block_hash(height)? {
+            Some(block_hash) => block_hash,
+            None => return Ok(None),
+        };
+        info!("Block {} hash: {}", height, block_hash);
+
+        let block_hex = self.call("getblock", json!([block_hash.to_string(), 0]))?;
+        let block_hex = block_hex.as_str().ok_or_else(|| {
+            AppError::BitcoinRpcError(format!("getblock returned a non-string result for {}", block_hash))
+        })?;
+
+        let block_bytes = Vec::<u8>::from_hex(block_hex)
+            .map_err(|e| AppError::BitcoinRpcError(format!("getblock returned invalid hex: {}", e)))?;
+        deserialize(&block_bytes)
+            .map(Some)
+            .map_err(|e| AppError::BitcoinRpcError(format!("getblock returned an invalid block: {}", e)))
+    }
+}
+
+fn rpc_agent(connect_timeout: Duration, read_timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(connect_timeout)
+        .timeout_read(read_timeout)
+        .build()
+}
+
+/// Reads the body of a response, which fails if the node stops sending it for longer than the read timeout.
+fn read_body(method: &str, response: ureq::Response) -> Result<String, AppError> {
+    response
+        .into_string()
+        .map_err(|e| AppError::BitcoinRpcError(format!("{}: failed to read response: {}", method, e)))
+}
+
+/// Reads a number of seconds from the environment, or returns `default` if the variable isn't set.
+fn env_seconds(name: &str, default: Duration) -> Result<Duration, AppError> {
+    match env::var(name) {
+        Ok(seconds) => seconds
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| AppError::CustomError(format!("{} must be a number of seconds: {}", name, seconds))),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads the user and password that bitcoind writes to its `.cookie` file as `user:password`.
+fn read_cookie_file(cookie_file: &str) -> Result<(String, String), AppError> {
+    let cookie = std::fs::read_to_string(cookie_file)?;
+    match cookie.trim().split_once(':') {
+        Some((user, password)) => Ok((user.to_string(), password.to_string())),
+        None => Err(AppError::CustomError(format!(
+            "Malformed bitcoind cookie file: {}",
+            cookie_file
+        ))),
+    }
+}
+
+impl BlockSource for BitcoinRpcBlockSource {
+    fn get_tip(&self) -> Result<u64, AppError> {
+        let block_count = self.call("getblockcount", json!([]))?;
+        block_count.as_u64().ok_or_else(|| {
+            AppError::BitcoinRpcError(format!("getblockcount returned {}", block_count))
+        })
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, AppError> {
+        let block_hash = match self.try_call("getblockhash", json!([height]))? {
+            Ok(block_hash) => block_hash,
+            // The node's chain doesn't reach that height
+            Err(error) if error["code"].as_i64() == Some(RPC_INVALID_PARAMETER) => return Ok(None),
+            Err(error) => return Err(AppError::BitcoinRpcError(format!("getblockhash: {}", error))),
+        };
+        let block_hash = block_hash.as_str().ok_or_else(|| {
+            AppError::BitcoinRpcError(format!("getblockhash returned {}", block_hash))
+        })?;
+        BlockHash::from_str(block_hash)
+            .map(Some)
+            .map_err(|e| AppError::BitcoinRpcError(format!("getblockhash returned an invalid hash: {}", e)))
+    }
+
+    fn request_blocks(&self, heights: RangeInclusive<u64>) -> Result<usize, AppError> {
+        let blocks_tx = match self.blocks_tx.lock().unwrap().clone() {
+            Some(blocks_tx) => blocks_tx,
+            None => return Err(AppError::CustomError("Block source has been shut down".to_owned())),
+        };
+
+        let mut requested = 0;
+        for height in heights {
+            let block = match self.fetch_block(height)? {
+                Some(block) => block,
+                None => {
+                    warn!("Node has no block at height {}, skipping it", height);
+                    continue;
+                }
+            };
+            if blocks_tx.send((block, height)).is_err() {
+                return Err(AppError::CustomError("Block channel disconnected".to_owned()));
+            }
+            requested += 1;
+        }
+        Ok(requested)
+    }
+
+    fn blocks(&self) -> Receiver<(Block, u64)> {
+        self.blocks_rx.clone()
+    }
+
+    fn shutdown(self) -> Result<(), AppError> {
+        // Dropping the only sender disconnects the blocks channel
+        self.blocks_tx.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, serve_http, HttpRequest};
+    use nakamoto::common::bitcoin::consensus::encode::serialize_hex;
+
+    const USER: &str = "__cookie__";
+    const PASSWORD: &str = "secret";
+
+    /// Serves `chain` the way bitcoind does, to clients authenticating with USER and PASSWORD.
+    fn serve_chain(chain: Vec<Block>) -> String {
+        let authorization = format!("Basic {}", BASE64.encode(format!("{}:{}", USER, PASSWORD)));
+        serve_http(move |request: &HttpRequest| {
+            // bitcoind answers requests with bad credentials with an empty body
+            if request.headers.get("authorization") != Some(&authorization) {
+                return (401, String::new());
+            }
+            if (request.method.as_str(), request.path.as_str()) != ("POST", "/") {
+                return (404, String::new());
+            }
+            let request: Value = serde_json::from_str(&request.body).unwrap();
+            let params = &request["params"];
+            let result = match request["method"].as_str().unwrap() {
+                "getblockcount" => Ok(json!(chain.len() - 1)),
+                "getblockhash" => chain
+                    .get(params[0].as_u64().unwrap() as usize)
+                    .map(|block| json!(block.block_hash().to_string()))
+                    .ok_or((-8, "Block height out of range")),
+                "getblock" => chain
+                    .iter()
+                    .find(|block| block.block_hash().to_string() == params[0].as_str().unwrap())
+                    .map(|block| json!(serialize_hex(block)))
+                    .ok_or((-5, "Block not found")),
+                _ => Err((-32601, "Method not found")),
+            };
+            match result {
+                Ok(result) => (200, json!({ "result": result, "error": null, "id": request["id"] }).to_string()),
+                Err((code, message)) => (
+                    500,
+                    json!({ "result": null, "error": { "code": code, "message": message }, "id": request["id"] })
+                        .to_string(),
+                ),
+            }
+        })
+    }
+
+    fn source(chain: Vec<Block>) -> BitcoinRpcBlockSource {
+        BitcoinRpcBlockSource::new(serve_chain(chain), USER, PASSWORD)
+    }
+
+    #[test]
+    fn call_returns_the_result_or_the_rpc_error() {
+        let source = source(test_fixtures::chain(3, |_| Vec::new()));
+
+        assert_eq!(source.call("getblockcount", json!([])).unwrap(), json!(2));
+
+        let error = source.call("getnetworkinfo", json!([])).unwrap_err().to_string();
+        assert!(error.contains("getnetworkinfo"), "{}", error);
+        assert!(error.contains("Method not found"), "{}", error);
+    }
+
+    #[test]
+    fn call_reports_the_status_of_empty_error_responses() {
+        let chain = test_fixtures::chain(1, |_| Vec::new());
+        let source = BitcoinRpcBlockSource::new(serve_chain(chain), USER, "wrong");
+
+        let error = source.call("getblockcount", json!([])).unwrap_err().to_string();
+        assert!(error.contains("HTTP status 401"), "{}", error);
+    }
+
+    #[test]
+    fn get_tip_and_get_block_hash_follow_the_node() {
+        let chain = test_fixtures::chain(3, |_| Vec::new());
+        let source = source(chain.clone());
+
+        assert_eq!(source.get_tip().unwrap(), 2);
+        assert_eq!(source.get_block_hash(1).unwrap(), Some(chain[1].block_hash()));
+        // Heights above the node's tip are unknown rather than an error
+        assert_eq!(source.get_block_hash(3).unwrap(), None);
+    }
+
+    #[test]
+    fn fetch_block_decodes_raw_blocks() {
+        let chain = test_fixtures::chain(3, |_| Vec::new());
+        let source = source(chain.clone());
+
+        assert_eq!(source.fetch_block(2).unwrap(), Some(chain[2].clone()));
+        assert_eq!(source.fetch_block(5).unwrap(), None);
+
+        assert_eq!(source.request_blocks(0..=1).unwrap(), 2);
+        let blocks = source.blocks();
+        assert_eq!(blocks.recv().unwrap(), (chain[0].clone(), 0));
+        assert_eq!(blocks.recv().unwrap(), (chain[1].clone(), 1));
+    }
+
+    #[test]
+    fn request_blocks_skips_heights_above_the_node_tip() {
+        let chain = test_fixtures::chain(3, |_| Vec::new());
+        let source = source(chain.clone());
+
+        assert_eq!(source.request_blocks(1..=4).unwrap(), 2);
+        source.clone().shutdown().unwrap();
+        let delivered = source.blocks().iter().map(|(_, height)| height).collect::<Vec<_>>();
+        assert_eq!(delivered, vec![1, 2]);
+    }
+
+    #[test]
+    fn get_block_hash_fails_on_other_rpc_errors() {
+        let url = serve_http(|_| {
+            (500, json!({ "result": null, "error": { "code": -28, "message": "Loading block index" } }).to_string())
+        });
+        let source = BitcoinRpcBlockSource::new(url, USER, PASSWORD);
+
+        let error = source.get_block_hash(1).unwrap_err();
+        assert!(matches!(&error, AppError::BitcoinRpcError(message) if message.contains("Loading block index")));
+    }
+
+    #[test]
+    fn call_times_out_on_a_hung_node() {
+        let url = serve_http(|_| {
+            std::thread::sleep(Duration::from_secs(2));
+            (200, json!({ "result": 0, "error": null }).to_string())
+        });
+        let source = BitcoinRpcBlockSource::new(url, USER, PASSWORD)
+            .with_timeouts(Duration::from_secs(1), Duration::from_millis(200));
+
+        let error = source.get_tip().unwrap_err();
+        assert!(matches!(&error, AppError::BitcoinRpcError(message) if message.contains("timed out")), "{}", error);
+    }
+
+    #[test]
+    fn cookie_file_credentials_authenticate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join(".cookie");
+        std::fs::write(&cookie_file, format!("{}:{}\n", USER, PASSWORD)).unwrap();
+
+        let (user, password) = read_cookie_file(cookie_file.to_str().unwrap()).unwrap();
+        assert_eq!((user.as_str(), password.as_str()), (USER, PASSWORD));
+        let source = BitcoinRpcBlockSource::new(serve_chain(test_fixtures::chain(1, |_| Vec::new())), &user, &password);
+        assert_eq!(source.get_tip().unwrap(), 0);
+
+        std::fs::write(&cookie_file, "no separator").unwrap();
+        assert!(read_cookie_file(cookie_file.to_str().unwrap()).is_err());
+        assert!(read_cookie_file(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{unbounded, Receiver};
+use log::{info, warn};
+use nakamoto::common::bitcoin::blockdata::constants::genesis_block;
+use nakamoto::common::bitcoin::consensus::encode::deserialize;
+use nakamoto::common::bitcoin::util::uint::Uint256;
+use nakamoto::common::bitcoin::{Block, BlockHash, BlockHeader, Network};
+
+use super::{BlockSource, SharedBlockSender};
+use crate::AppError;
+
+/// Network magic that prefixes every block record in mainnet blk*.dat files.
+const MAINNET_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+/// Serialized size of a block header.
+const HEADER_SIZE: usize = 80;
+
+/// Where a block's serialized bytes live on disk.
+#[derive(Clone, Copy, Debug)]
+struct BlockLocation {
+    hash: BlockHash,
+    file: usize,
+    offset: u64,
+    size: u32,
+}
+
+/// A block found in the blk*.dat files, with what is needed to place it in the block tree.
+#[derive(Clone, Copy, Debug)]
+struct IndexedBlock {
+    prev_blockhash: BlockHash,
+    /// Work of the block alone, from its difficulty target.
+    work: Uint256,
+    location: BlockLocation,
+}
+
+/// Block source that reads raw blocks from Bitcoin Core's `blocks` directory.
+///
+/// Bitcoin Core stores blocks in blk*.dat files in the order they were downloaded,
+/// which is not height order and may include stale blocks.  On startup every file is
+/// scanned for block headers and the chain with the most work is reconstructed from the
+/// genesis block by following `prev_blockhash` links.  Files written with an obfuscation key (`xor.dat`)
+/// are de-obfuscated as they are read.
+///
+/// bitcoind should not be running while its blocks directory is read.
+#[derive(Clone)]
+pub struct BlkFilesBlockSource {
+    blk_files: Arc<Vec<PathBuf>>,
+    xor_key: [u8; 8],
+    chain: Arc<Vec<BlockLocation>>,
+    blocks_tx: SharedBlockSender,
+    blocks_rx: Receiver<(Block, u64)>,
+}
+
+impl BlkFilesBlockSource {
+    /// Indexes the blk*.dat files in `blocks_dir`.
+    pub fn new(blocks_dir: &Path) -> Result<Self, AppError> {
+        let xor_key = read_xor_key(blocks_dir)?;
+        let blk_files = list_blk_files(blocks_dir)?;
+        info!(
+            "Indexing {} blk*.dat file(s) in {}...",
+            blk_files.len(),
+            blocks_dir.display()
+        );
+
+        let mut headers: HashMap<BlockHash, IndexedBlock> = HashMap::new();
+        for (file_index, path) in blk_files.iter().enumerate() {
+            index_blk_file(file_index, path, &xor_key, &mut headers)?;
+        }
+        info!("Found {} block(s) in blk*.dat files", headers.len());
+
+        let chain = best_chain(&headers)?;
+        info!("Reconstructed chain up to height {}", chain.len() - 1);
+
+        let (blocks_tx, blocks_rx) = unbounded();
+        Ok(BlkFilesBlockSource {
+            blk_files: Arc::new(blk_files),
+            xor_key,
+            chain: Arc::new(chain),
+            blocks_tx: Arc::new(Mutex::new(Some(blocks_tx))),
+            blocks_rx,
+        })
+    }
+
+    /// Creates a block source for the directory in BITCOIN_BLOCKS_DIR (defaults to ~/.bitcoin/blocks).
+    pub fn from_env() -> Result<Self, AppError> {
+        let blocks_dir = match env::var("BITCOIN_BLOCKS_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => {
+                let home = env::var("HOME").map_err(|_| {
+                    AppError::CustomError("Set BITCOIN_BLOCKS_DIR to Bitcoin Core's blocks directory".to_owned())
+                })?;
+                PathBuf::from(home).join(".bitcoin").join("blocks")
+            }
+        };
+        Self::new(&blocks_dir)
+    }
+
+    /// Reads and decodes the block at `location`.
+    /// `open_file` keeps the last blk file read open, as consecutive blocks are mostly stored in the same file.
+    fn read_block(
+        &self,
+        location: &BlockLocation,
+        open_file: &mut Option<(usize, File)>,
+    ) -> Result<Block, AppError> {
+        let file = match open_file {
+            Some((file_index, file)) if *file_index == location.file => file,
+            _ => &mut open_file.insert((location.file, File::open(&self.blk_files[location.file])?)).1,
+        };
+        let bytes = read_at(file, location.offset, location.size as usize, &self.xor_key)?;
+        deserialize(&bytes).map_err(|e| {
+            AppError::CustomError(format!(
+                "Invalid block in {} at offset {}: {}",
+                self.blk_files[location.file].display(),
+                location.offset,
+                e
+            ))
+        })
+    }
+}
+
+impl BlockSource for BlkFilesBlockSource {
+    fn get_tip(&self) -> Result<u64, AppError> {
+        Ok((self.chain.len() - 1) as u64)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, AppError> {
+        Ok(self.chain.get(height as usize).map(|location| location.hash))
+    }
+
+    fn request_blocks(&self, heights: RangeInclusive<u64>) -> Result<usize, AppError> {
+        let blocks_tx = match self.blocks_tx.lock().unwrap().clone() {
+            Some(blocks_tx) => blocks_tx,
+            None => return Err(AppError::CustomError("Block source has been shut down".to_owned())),
+        };
+
+        let mut open_file = None;
+        let mut requested = 0;
+        for height in heights {
+            let location = match self.chain.get(height as usize) {
+                Some(location) => location,
+                None => {
+                    warn!("No block found at height {}", height);
+                    continue;
+                }
+            };
+            let block = self.read_block(location, &mut open_file)?;
+            if blocks_tx.send((block, height)).is_err() {
+                return Err(AppError::CustomError("Block channel disconnected".to_owned()));
+            }
+            requested += 1;
+        }
+        Ok(requested)
+    }
+
+    fn blocks(&self) -> Receiver<(Block, u64)> {
+        self.blocks_rx.clone()
+    }
+
+    fn shutdown(self) -> Result<(), AppError> {
+        // Dropping the only sender disconnects the blocks channel
+        self.blocks_tx.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+/// Reads the obfuscation key written by Bitcoin Core 28+.  Older nodes don't obfuscate.
+fn read_xor_key(blocks_dir: &Path) -> Result<[u8; 8], AppError> {
+    let mut xor_key = [0u8; 8];
+    match File::open(blocks_dir.join("xor.dat")) {
+        Ok(mut file) => {
+            file.read_exact(&mut xor_key)?;
+            if xor_key != [0u8; 8] {
+                info!("blk*.dat files are obfuscated, de-obfuscating with xor.dat");
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(xor_key)
+}
+
+/// Lists blk*.dat files ordered by their file number.
+fn list_blk_files(blocks_dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut blk_files = Vec::new();
+    for entry in std::fs::read_dir(blocks_dir)? {
+        let path = entry?.path();
+        let is_blk_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("blk") && name.ends_with(".dat"))
+            .unwrap_or(false);
+        if is_blk_file {
+            blk_files.push(path);
+        }
+    }
+    // File numbers are zero-padded, so lexical order is numeric order
+    blk_files.sort();
+
+    if blk_files.is_empty() {
+        return Err(AppError::CustomError(format!(
+            "No blk*.dat files found in {}",
+            blocks_dir.display()
+        )));
+    }
+    Ok(blk_files)
+}
+
+/// Reads `len` bytes at `offset`, undoing the XOR obfuscation.
+/// The key is applied relative to the start of the file.
+fn read_at(file: &mut File, offset: u64, len: usize, xor_key: &[u8; 8]) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= xor_key[((offset + i as u64) % 8) as usize];
+    }
+    Ok(bytes)
+}
+
+/// Records the header and location of every block stored in the blk file at `path`.
+fn index_blk_file(
+    file_index: usize,
+    path: &Path,
+    xor_key: &[u8; 8],
+    headers: &mut HashMap<BlockHash, IndexedBlock>,
+) -> Result<(), AppError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut offset = 0u64;
+
+    // Each record is: 4 byte network magic, 4 byte little-endian block size, block
+    while offset + 8 + HEADER_SIZE as u64 <= file_len {
+        let record = read_at(&mut file, offset, 8, xor_key)?;
+        if record[..4] != MAINNET_MAGIC {
+            // Files are pre-allocated, so the remainder of the last file is zero-filled
+            if record[..4] != [0u8; 4] {
+                warn!("Unexpected bytes at offset {} of {}, skipping rest of file", offset, path.display());
+            }
+            break;
+        }
+
+        let size = u32::from_le_bytes(record[4..8].try_into().unwrap());
+        let block_offset = offset + 8;
+        if block_offset + size as u64 > file_len {
+            warn!("Truncated block at offset {} of {}", offset, path.display());
+            break;
+        }
+
+        let header_bytes = read_at(&mut file, block_offset, HEADER_SIZE, xor_key)?;
+        let header: BlockHeader = deserialize(&header_bytes).map_err(|e| {
+            AppError::CustomError(format!("Invalid block header in {}: {}", path.display(), e))
+        })?;
+        headers.insert(
+            header.block_hash(),
+            IndexedBlock {
+                prev_blockhash: header.prev_blockhash,
+                work: header.work(),
+                location: BlockLocation {
+                    hash: header.block_hash(),
+                    file: file_index,
+                    offset: block_offset,
+                    size,
+                },
+            },
+        );
+
+        offset = block_offset + size as u64;
+    }
+
+    Ok(())
+}
+
+/// Reconstructs the chain with the most cumulative work starting at the genesis block.
+/// Tips with equal work are decided by the lowest hash, so that the same files always give the same chain.
+/// Returns the location of each block, indexed by height.
+fn best_chain(headers: &HashMap<BlockHash, IndexedBlock>) -> Result<Vec<BlockLocation>, AppError> {
+    let genesis_hash = genesis_block(Network::Bitcoin).block_hash();
+    let genesis = headers.get(&genesis_hash).ok_or_else(|| {
+        AppError::CustomError("Genesis block not found in blk*.dat files".to_owned())
+    })?;
+
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    for (hash, block) in headers.iter() {
+        children.entry(block.prev_blockhash).or_default().push(*hash);
+    }
+
+    // Walk the block tree breadth-first, summing the work of each branch
+    let mut connected = 0;
+    let mut queue = VecDeque::from([(genesis_hash, genesis.work, 0u64)]);
+    let mut tip = (genesis_hash, genesis.work, 0u64);
+    while let Some((hash, chainwork, height)) = queue.pop_front() {
+        connected += 1;
+        let (tip_hash, tip_chainwork, _) = tip;
+        if chainwork > tip_chainwork || (chainwork == tip_chainwork && hash.to_string() < tip_hash.to_string()) {
+            tip = (hash, chainwork, height);
+        }
+        for child in children.get(&hash).into_iter().flatten() {
+            queue.push_back((*child, chainwork + headers[child].work, height + 1));
+        }
+    }
+
+    let orphaned = headers.len() - connected;
+    if orphaned > 0 {
+        warn!("{} block(s) in blk*.dat files don't connect to the genesis block", orphaned);
+    }
+
+    // Follow the tip back to genesis
+    let (mut hash, _, tip_height) = tip;
+    let mut chain = Vec::with_capacity(tip_height as usize + 1);
+    loop {
+        let block = headers[&hash];
+        chain.push(block.location);
+        if hash == genesis_hash {
+            break;
+        }
+        hash = block.prev_blockhash;
+    }
+    chain.reverse();
+
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{block_with_bits, coinbase, output, p2pk_script, REGTEST_BITS};
+    use nakamoto::common::bitcoin::consensus::encode::serialize;
+
+    /// Difficulty of the genesis block, with far more work per block than REGTEST_BITS.
+    const MAINNET_BITS: u32 = 0x1d00ffff;
+
+    /// Writes `files` of blocks to blk00000.dat, blk00001.dat... in a temporary blocks directory.
+    fn write_blocks_dir(files: &[Vec<&Block>]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (file_index, blocks) in files.iter().enumerate() {
+            let mut bytes = Vec::new();
+            for block in blocks {
+                let block_bytes = serialize(*block);
+                bytes.extend_from_slice(&MAINNET_MAGIC);
+                bytes.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&block_bytes);
+            }
+            // Files are pre-allocated and zero-filled past the last block
+            bytes.extend_from_slice(&[0u8; 128]);
+            std::fs::write(dir.path().join(format!("blk{:05}.dat", file_index)), bytes).unwrap();
+        }
+        dir
+    }
+
+    /// A block at `height` building on `prev`, whose coinbase pays `value` so that siblings differ.
+    fn child(prev: &Block, height: u64, bits: u32, value: u64) -> Block {
+        block_with_bits(prev.block_hash(), height, bits, vec![coinbase(height, vec![output(value, p2pk_script())])])
+    }
+
+    fn chain_hashes(source: &BlkFilesBlockSource) -> Vec<BlockHash> {
+        (0..=source.get_tip().unwrap())
+            .map(|height| source.get_block_hash(height).unwrap().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn best_chain_follows_the_most_work_rather_than_the_most_blocks() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let long_1 = child(&genesis, 1, REGTEST_BITS, 1);
+        let long_2 = child(&long_1, 2, REGTEST_BITS, 1);
+        let heavy_1 = child(&genesis, 1, MAINNET_BITS, 2);
+        let dir = write_blocks_dir(&[vec![&genesis, &long_1, &long_2], vec![&heavy_1]]);
+
+        let source = BlkFilesBlockSource::new(dir.path()).unwrap();
+        assert_eq!(chain_hashes(&source), vec![genesis.block_hash(), heavy_1.block_hash()]);
+    }
+
+    #[test]
+    fn best_chain_breaks_ties_by_lowest_hash_whatever_the_file_order() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let tip_a = child(&genesis, 1, MAINNET_BITS, 1);
+        let tip_b = child(&genesis, 1, MAINNET_BITS, 2);
+        let lowest = if tip_a.block_hash().to_string() < tip_b.block_hash().to_string() { &tip_a } else { &tip_b };
+
+        for files in [vec![vec![&genesis, &tip_a, &tip_b]], vec![vec![&genesis, &tip_b], vec![&tip_a]]] {
+            let dir = write_blocks_dir(&files);
+            let source = BlkFilesBlockSource::new(dir.path()).unwrap();
+            assert_eq!(chain_hashes(&source), vec![genesis.block_hash(), lowest.block_hash()]);
+        }
+    }
+
+    #[test]
+    fn request_blocks_reads_blocks_across_files_in_height_order() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let block_1 = child(&genesis, 1, MAINNET_BITS, 1);
+        let block_2 = child(&block_1, 2, MAINNET_BITS, 1);
+        let stale_2 = child(&block_1, 2, REGTEST_BITS, 2);
+        // Blocks are stored out of order, as they were downloaded
+        let dir = write_blocks_dir(&[vec![&genesis, &block_2], vec![&stale_2, &block_1]]);
+
+        let source = BlkFilesBlockSource::new(dir.path()).unwrap();
+        assert_eq!(source.request_blocks(0..=3).unwrap(), 3);
+        let blocks = source.blocks();
+        assert_eq!(blocks.recv().unwrap(), (genesis, 0));
+        assert_eq!(blocks.recv().unwrap(), (block_1, 1));
+        assert_eq!(blocks.recv().unwrap(), (block_2, 2));
+    }
+
+    #[test]
+    fn obfuscated_blk_files_are_read_with_the_xor_key() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let block_1 = child(&genesis, 1, MAINNET_BITS, 1);
+        let block_2 = child(&block_1, 2, MAINNET_BITS, 1);
+        let stale_2 = child(&block_1, 2, REGTEST_BITS, 2);
+        let dir = write_blocks_dir(&[vec![&genesis, &block_2], vec![&stale_2, &block_1]]);
+        // Records after the genesis block start mid-way through the key
+        assert_ne!((8 + serialize(&genesis).len()) % 8, 0);
+
+        // Obfuscate as bitcoind does: each byte is XORed with the key byte at its file offset modulo 8
+        let xor_key = [0x3a, 0x00, 0xff, 0x81, 0x5c, 0x07, 0xe2, 0x19];
+        for file_index in 0..2 {
+            let path = dir.path().join(format!("blk{:05}.dat", file_index));
+            let bytes = std::fs::read(&path).unwrap();
+            let obfuscated = bytes.iter().enumerate().map(|(i, byte)| byte ^ xor_key[i % 8]).collect::<Vec<_>>();
+            std::fs::write(&path, obfuscated).unwrap();
+        }
+        std::fs::write(dir.path().join("xor.dat"), xor_key).unwrap();
+
+        let source = BlkFilesBlockSource::new(dir.path()).unwrap();
+        assert_eq!(chain_hashes(&source), vec![genesis.block_hash(), block_1.block_hash(), block_2.block_hash()]);
+        assert_eq!(source.request_blocks(0..=2).unwrap(), 3);
+        let blocks = source.blocks();
+        assert_eq!(blocks.recv().unwrap(), (genesis, 0));
+        assert_eq!(blocks.recv().unwrap(), (block_1, 1));
+        assert_eq!(blocks.recv().unwrap(), (block_2, 2));
+    }
+}
@@ -0,0 +1,80 @@
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{unbounded, Receiver};
+use nakamoto::common::bitcoin::{Block, BlockHash};
+
+use super::{BlockSource, SharedBlockSender};
+use crate::AppError;
+
+/// Block source serving a chain held in memory, for driving `process_blocks` from tests.
+///
+/// The block at index `i` is the block at height `i`.  The chain can be replaced with `set_chain`
+/// to simulate a reorganization; blocks already requested stay on the channel.
+#[derive(Clone)]
+pub struct VecBlockSource {
+    chain: Arc<Mutex<Vec<Block>>>,
+    blocks_tx: SharedBlockSender,
+    blocks_rx: Receiver<(Block, u64)>,
+}
+
+impl VecBlockSource {
+    pub fn new(chain: Vec<Block>) -> Self {
+        let (blocks_tx, blocks_rx) = unbounded();
+        VecBlockSource {
+            chain: Arc::new(Mutex::new(chain)),
+            blocks_tx: Arc::new(Mutex::new(Some(blocks_tx))),
+            blocks_rx,
+        }
+    }
+
+    /// Replaces the chain served from now on.
+    pub fn set_chain(&self, chain: Vec<Block>) {
+        *self.chain.lock().unwrap() = chain;
+    }
+}
+
+impl BlockSource for VecBlockSource {
+    fn get_tip(&self) -> Result<u64, AppError> {
+        let chain = self.chain.lock().unwrap();
+        chain
+            .len()
+            .checked_sub(1)
+            .map(|tip_height| tip_height as u64)
+            .ok_or_else(|| AppError::CustomError("Block source has no blocks".to_owned()))
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, AppError> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain.get(height as usize).map(Block::block_hash))
+    }
+
+    fn request_blocks(&self, heights: RangeInclusive<u64>) -> Result<usize, AppError> {
+        let blocks_tx = match self.blocks_tx.lock().unwrap().clone() {
+            Some(blocks_tx) => blocks_tx,
+            None => return Err(AppError::CustomError("Block source has been shut down".to_owned())),
+        };
+
+        let chain = self.chain.lock().unwrap();
+        let mut requested = 0;
+        for height in heights {
+            if let Some(block) = chain.get(height as usize) {
+                if blocks_tx.send((block.clone(), height)).is_err() {
+                    return Err(AppError::CustomError("Block channel disconnected".to_owned()));
+                }
+                requested += 1;
+            }
+        }
+        Ok(requested)
+    }
+
+    fn blocks(&self) -> Receiver<(Block, u64)> {
+        self.blocks_rx.clone()
+    }
+
+    fn shutdown(self) -> Result<(), AppError> {
+        // Dropping the only sender disconnects the blocks channel
+        self.blocks_tx.lock().unwrap().take();
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde_json::json;
+
+use crate::alerts::{btc, Alert, Severity};
+use crate::webhooks::{OutboxMessage, WebhookEvent};
+use crate::AppError;
+
+/// Prefix of the outbox URLs of alert emails, followed by the comma separated recipients.
+pub const MAILTO_PREFIX: &str = "mailto:";
+
+const DEFAULT_SUBJECT: &str = "[Gabriel] {severity} {rule}: {value_btc} BTC of {address_type} moved in block {height}";
+const DEFAULT_BLOCK_URL: &str = "http://localhost:3000/{address_type}/block/{height}";
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with STARTTLS, which the server must support.
+    StartTls,
+    /// Send without encryption.  Only meant for relays on the local host or network.
+    Plain,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "plain" => Ok(SmtpSecurity::Plain),
+            _ => Err(format!("SMTP_SECURITY must be \"starttls\" or \"plain\": {}", s)),
+        }
+    }
+}
+
+/// Where and how alerts are emailed.  Read from the SMTP_* and ALERT_EMAIL_* environment variables.
+#[derive(Clone, Debug)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub security: SmtpSecurity,
+    /// Username and password, if the server requires authentication.
+    pub credentials: Option<(String, String)>,
+    pub from: Option<Mailbox>,
+    pub recipients: Vec<Mailbox>,
+    /// Alerts less severe than this aren't emailed.
+    pub min_severity: Severity,
+    /// Subject of the emails, with placeholders replaced by the values of the alert.
+    pub subject_template: String,
+    /// URL of the block, with placeholders replaced by the values of the alert.
+    pub block_url_template: String,
+    /// Emails are given up after this many failed attempts.
+    pub max_attempts: u32,
+    /// Alerts of blocks older than this, such as those scanned while catching up with the chain, aren't emailed.
+    pub max_block_age_seconds: Option<i64>,
+}
+
+impl EmailConfig {
+    /// Reads ALERT_EMAIL_TO, a comma separated list of recipients, along with ALERT_EMAIL_FROM,
+    /// ALERT_EMAIL_MIN_SEVERITY, ALERT_EMAIL_SUBJECT, ALERT_EMAIL_BLOCK_URL, ALERT_EMAIL_MAX_ATTEMPTS,
+    /// ALERT_EMAIL_MAX_BLOCK_AGE_HOURS, SMTP_HOST, SMTP_PORT, SMTP_SECURITY, SMTP_USERNAME and SMTP_PASSWORD.
+    /// Emails are disabled when ALERT_EMAIL_TO isn't set.
+    pub fn from_env() -> Result<Self, AppError> {
+        let recipients = match env::var("ALERT_EMAIL_TO") {
+            Ok(recipients) => recipients
+                .split(',')
+                .map(str::trim)
+                .filter(|recipient| !recipient.is_empty())
+                .map(|recipient| parse_mailbox("ALERT_EMAIL_TO", recipient))
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        let from = match env::var("ALERT_EMAIL_FROM") {
+            Ok(from) => Some(parse_mailbox("ALERT_EMAIL_FROM", from.trim())?),
+            Err(_) if recipients.is_empty() => None,
+            Err(_) => {
+                return Err(AppError::CustomError(
+                    "ALERT_EMAIL_FROM must be set when ALERT_EMAIL_TO is".to_string(),
+                ))
+            }
+        };
+
+        let security = match env::var("SMTP_SECURITY") {
+            Ok(security) => security.trim().parse().map_err(AppError::CustomError)?,
+            Err(_) => SmtpSecurity::StartTls,
+        };
+        let smtp_port = match env::var("SMTP_PORT") {
+            Ok(port) => port
+                .trim()
+                .parse()
+                .map_err(|_| AppError::CustomError(format!("SMTP_PORT must be a port number: {}", port)))?,
+            Err(_) if security == SmtpSecurity::StartTls => 587,
+            Err(_) => 25,
+        };
+        let credentials = match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            (Ok(_), Err(_)) => {
+                return Err(AppError::CustomError(
+                    "SMTP_PASSWORD must be set when SMTP_USERNAME is".to_string(),
+                ))
+            }
+            _ => None,
+        };
+
+        let min_severity = match env::var("ALERT_EMAIL_MIN_SEVERITY") {
+            Ok(severity) => severity.trim().parse().map_err(AppError::CustomError)?,
+            Err(_) => Severity::Critical,
+        };
+
+        let max_attempts = match env::var("ALERT_EMAIL_MAX_ATTEMPTS") {
+            Ok(max_attempts) => max_attempts.trim().parse().map_err(|_| {
+                AppError::CustomError(format!("ALERT_EMAIL_MAX_ATTEMPTS must be a number: {}", max_attempts))
+            })?,
+            Err(_) => 10,
+        };
+        let max_block_age_hours: i64 = match env::var("ALERT_EMAIL_MAX_BLOCK_AGE_HOURS") {
+            Ok(hours) => hours.trim().parse().map_err(|_| {
+                AppError::CustomError(format!("ALERT_EMAIL_MAX_BLOCK_AGE_HOURS must be a number: {}", hours))
+            })?,
+            Err(_) => 24,
+        };
+
+        Ok(EmailConfig {
+            smtp_host: env::var("SMTP_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            smtp_port,
+            security,
+            credentials,
+            from,
+            recipients,
+            min_severity,
+            subject_template: env::var("ALERT_EMAIL_SUBJECT").unwrap_or_else(|_| DEFAULT_SUBJECT.to_string()),
+            block_url_template: env::var("ALERT_EMAIL_BLOCK_URL").unwrap_or_else(|_| DEFAULT_BLOCK_URL.to_string()),
+            max_attempts,
+            // 0 emails the alerts of every block
+            max_block_age_seconds: (max_block_age_hours > 0).then_some(max_block_age_hours * 3600),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.recipients.is_empty()
+    }
+
+    /// Returns true if a block with timestamp `block_time` is recent enough for its alerts to be emailed.
+    pub fn is_recent(&self, block_time: u32) -> bool {
+        self.max_block_age_seconds
+            .is_none_or(|max_age| chrono::Utc::now().timestamp() - block_time as i64 <= max_age)
+    }
+
+    /// Returns the message emailing an alert to every recipient, or none if the alert isn't severe enough.
+    /// The email is rendered when it is queued, so that it doesn't change if the templates do.
+    pub fn messages(&self, alert: &Alert) -> Vec<OutboxMessage> {
+        if !self.is_enabled() || alert.severity < self.min_severity {
+            return Vec::new();
+        }
+        let block_url = self.render(&self.block_url_template, alert, "");
+        let subject = self.render(&self.subject_template, alert, &block_url);
+        let block_time = Utc
+            .timestamp_opt(alert.block_time, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default();
+        let text = format!(
+            "{}\n\nRule: {}\nSeverity: {}\nAddress type: {}\nBlock: {} ({})\nValue moved: {} BTC\n\n{}\n",
+            alert.message,
+            alert.rule,
+            alert.severity,
+            alert.address_type,
+            alert.block_height,
+            block_time,
+            btc(alert.value_sats),
+            block_url
+        );
+
+        vec![OutboxMessage {
+            url: self.url(),
+            event: WebhookEvent::Alert.as_str().to_string(),
+            body: json!({ "subject": subject, "text": text }).to_string(),
+        }]
+    }
+
+    /// Returns the outbox URL of the emails: a `mailto:` URL of the comma separated recipients.
+    pub fn url(&self) -> String {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|recipient| recipient.email.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{}{}", MAILTO_PREFIX, recipients)
+    }
+
+    /// Replaces the {height}, {severity}, {rule}, {address_type}, {value_btc}, {value_sats} and {link}
+    /// placeholders of a template.
+    fn render(&self, template: &str, alert: &Alert, block_url: &str) -> String {
+        template
+            .replace("{height}", &alert.block_height.to_string())
+            .replace("{severity}", alert.severity.as_str())
+            .replace("{rule}", &alert.rule)
+            .replace("{address_type}", &alert.address_type)
+            .replace("{value_btc}", &btc(alert.value_sats).to_string())
+            .replace("{value_sats}", &alert.value_sats.to_string())
+            .replace("{link}", block_url)
+    }
+
+    /// Returns the SMTP client sending the emails.
+    pub fn transport(&self) -> Result<SmtpTransport, AppError> {
+        let builder = match self.security {
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&self.smtp_host)
+                .map_err(|e| AppError::CustomError(format!("Invalid SMTP_HOST {}: {}", self.smtp_host, e)))?,
+            SmtpSecurity::Plain => SmtpTransport::builder_dangerous(&self.smtp_host),
+        };
+        let mut builder = builder.port(self.smtp_port).timeout(Some(Duration::from_secs(10)));
+        if let Some((username, password)) = &self.credentials {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Sends an email queued in the outbox to the recipients of its URL.
+pub fn send(transport: &SmtpTransport, config: &EmailConfig, message: &OutboxMessage) -> Result<(), String> {
+    let from = config.from.clone().ok_or("ALERT_EMAIL_FROM isn't set")?;
+    let body: serde_json::Value = serde_json::from_str(&message.body).map_err(|e| e.to_string())?;
+
+    let mut email = Message::builder()
+        .from(from)
+        .subject(body["subject"].as_str().unwrap_or_default())
+        .header(ContentType::TEXT_PLAIN);
+    let recipients = message.url.strip_prefix(MAILTO_PREFIX).unwrap_or(&message.url);
+    for recipient in recipients.split(',') {
+        email = email.to(recipient.parse().map_err(|e| format!("Invalid recipient {}: {}", recipient, e))?);
+    }
+    let email = email
+        .body(body["text"].as_str().unwrap_or_default().to_string())
+        .map_err(|e| e.to_string())?;
+
+    transport.send(&email).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn parse_mailbox(name: &str, mailbox: &str) -> Result<Mailbox, AppError> {
+    mailbox
+        .parse()
+        .map_err(|e| AppError::CustomError(format!("{} must list email addresses: {} ({})", name, mailbox, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::test_fixtures::{serve_smtp, SmtpEmail};
+
+    fn email_config(smtp_port: u16) -> EmailConfig {
+        EmailConfig {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port,
+            security: SmtpSecurity::Plain,
+            credentials: None,
+            from: Some("Gabriel <gabriel@example.com>".parse().unwrap()),
+            recipients: vec!["oncall@example.com".parse().unwrap(), "backup@example.com".parse().unwrap()],
+            min_severity: Severity::Warning,
+            subject_template: DEFAULT_SUBJECT.to_string(),
+            block_url_template: DEFAULT_BLOCK_URL.to_string(),
+            max_attempts: 10,
+            max_block_age_seconds: None,
+        }
+    }
+
+    fn alert(severity: Severity) -> Alert {
+        Alert {
+            block_height: 830000,
+            block_time: 1713571767,
+            address_type: "p2pk".to_string(),
+            rule: "early_spend".to_string(),
+            severity,
+            message: "100 BTC of outputs created before block 100000 spent".to_string(),
+            value_sats: 10_000_000_000,
+        }
+    }
+
+    #[test]
+    fn messages_skip_alerts_below_the_minimum_severity() {
+        let config = email_config(25);
+        assert!(config.messages(&alert(Severity::Info)).is_empty());
+        assert_eq!(config.messages(&alert(Severity::Warning)).len(), 1);
+    }
+
+    #[test]
+    fn send_delivers_the_rendered_alert_to_every_recipient() {
+        let received: Arc<Mutex<Vec<SmtpEmail>>> = Arc::default();
+        let sink = Arc::clone(&received);
+        let config = email_config(serve_smtp(move |email| {
+            sink.lock().unwrap().push(email);
+            true
+        }));
+
+        let messages = config.messages(&alert(Severity::Critical));
+        assert_eq!(messages[0].url, "mailto:oncall@example.com,backup@example.com");
+        send(&config.transport().unwrap(), &config, &messages[0]).unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].from, "gabriel@example.com");
+        assert_eq!(received[0].to, vec!["oncall@example.com", "backup@example.com"]);
+        // Long headers are folded onto several lines
+        let data = received[0].data.replace("\r\n ", " ");
+        assert!(data.contains("Subject: [Gabriel] critical early_spend: 100 BTC of p2pk moved in block 830000"), "{}", data);
+        assert!(data.contains("http://localhost:3000/p2pk/block/830000"), "{}", data);
+        assert!(data.contains("Block: 830000 (2024-04-20 00:09:27 UTC)"), "{}", data);
+    }
+
+    #[test]
+    fn messages_link_to_the_block_of_the_alert_address_type() {
+        let config = email_config(25);
+        let mut alert = alert(Severity::Critical);
+        alert.address_type = "p2tr".to_string();
+
+        let messages = config.messages(&alert);
+        let body: serde_json::Value = serde_json::from_str(&messages[0].body).unwrap();
+        let text = body["text"].as_str().unwrap();
+        assert!(text.ends_with("\n\nhttp://localhost:3000/p2tr/block/830000\n"), "{}", text);
+    }
+
+    #[test]
+    fn send_fails_when_the_server_rejects_the_email() {
+        let config = email_config(serve_smtp(|_| false));
+        let messages = config.messages(&alert(Severity::Critical));
+        assert!(send(&config.transport().unwrap(), &config, &messages[0]).is_err());
+    }
+}
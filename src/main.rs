@@ -1,4 +1,5 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     env, net,
     sync::LazyLock,
     sync::{mpsc, Arc},
@@ -13,28 +14,50 @@ use axum::{
 };
 use chrono::{TimeZone, Utc};
 use crossbeam_channel::bounded;
-use log::{debug, error, info};
+use log::{error, info, warn};
 use nakamoto::client::{
     network::{Network, Services},
     traits::Handle,
     Client, Config,
 };
+use nakamoto::common::bitcoin::hashes::{hash160, Hash};
+use nakamoto::common::bitcoin::OutPoint;
 use serde_json::json;
 use std::fmt;
 use std::net::SocketAddr;
 use thiserror::Error;
 use tokio::signal;
 use tokio::sync::broadcast;
-use tower_http::services::ServeDir;
+use tower_http::services::{ServeDir, ServeFile};
 use tower_http::cors::{CorsLayer, Any};
-use env_logger;
 
-use crate::util::{capture_p2pk_blocks_graph, BlockAggregateOutput, BtcAddressType};
+use crate::alerts::AlertRule;
+use crate::block_source::{
+    BitcoinRpcBlockSource, BlkFilesBlockSource, BlockSource, NakamotoBlockSource,
+};
+use crate::util::{
+    capture_p2pk_blocks_graph, script_type, spent_pubkey, BlockAggregateOutput, BtcAddressType, Cohort,
+    Multisig, ReorgOutput, SpendOutput, SpendStreamOutput, StreamEvent, SyncProgressOutput, UtxoSubset,
+    ValueBand, Vintage, VintageBuckets,
+};
+use crate::utxo_store::{
+    migrate_sled_to_sqlite, BlockCommit, BlockDelta, TrackedOutput, UtxoStore, MAX_REORG_DEPTH,
+};
+use crate::email::EmailConfig;
+use crate::webhooks::{WebhookConfig, WebhookEvent};
 use api::AppState;
 
+mod alerts;
 mod api;
+mod block_source;
+mod email;
 mod persistence;
+#[cfg(test)]
+mod test_fixtures;
 mod util;
+mod utxo_store;
+mod webhooks;
+mod ws;
 
 /// The network reactor we're going to use.
 type Reactor = nakamoto::net::poll::Reactor<net::TcpStream>;
@@ -50,11 +73,13 @@ pub enum AppError {
     #[error(transparent)]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>),
     #[error("channel send error")]
-    ChannelSend(#[from] crossbeam_channel::SendError<u32>),
+    ChannelSend(#[from] crossbeam_channel::SendError<BlockProcessed>),
     #[error(transparent)]
     SledError(#[from] sled::Error),
     #[error(transparent)]
     SqliteError(#[from] anyhow::Error),
+    #[error("bitcoin rpc error: {0}")]
+    BitcoinRpcError(String),
     #[error("{0}")]
     CustomError(String),
 }
@@ -67,6 +92,29 @@ static CAPTURE_FREQUENCY: LazyLock<usize> = LazyLock::new(|| {
         .expect("CHART_CAPTURE_FREQUENCY_BLOCKS must be a valid number")
 });
 
+// Get the vintage buckets from VINTAGE_HEIGHT_RANGES or default to calendar years
+static VINTAGE_BUCKETS: LazyLock<VintageBuckets> =
+    LazyLock::new(|| VintageBuckets::from_env().expect("VINTAGE_HEIGHT_RANGES must be a list of heights"));
+
+// Get the cohort from the file named by COHORT_FILE or default to an empty cohort
+static COHORT: LazyLock<Cohort> =
+    LazyLock::new(|| Cohort::from_env().expect("COHORT_FILE must list block heights or outpoints"));
+
+// Get the alert rules, with thresholds from the ALERT_* environment variables or their defaults
+static ALERT_RULES: LazyLock<Vec<AlertRule>> =
+    LazyLock::new(|| AlertRule::from_env().expect("ALERT_* thresholds must be valid numbers"));
+
+// Get the webhook settings from the WEBHOOK_* environment variables.  Webhooks are disabled unless WEBHOOK_URLS is set
+static WEBHOOKS: LazyLock<WebhookConfig> =
+    LazyLock::new(|| WebhookConfig::from_env().expect("WEBHOOK_* settings must be valid"));
+
+// Get the SMTP settings and recipients of alert emails.  Emails are disabled unless ALERT_EMAIL_TO is set
+static EMAIL: LazyLock<EmailConfig> =
+    LazyLock::new(|| EmailConfig::from_env().expect("SMTP_* and ALERT_EMAIL_* settings must be valid"));
+
+/// Directory of the sled key-value store used when UTXO_STORE=sled
+const SLED_DB_PATH: &str = "db";
+
 /// Function to spawn a thread and handle errors asynchronously
 fn spawn_thread<F>(task: F) -> mpsc::Receiver<Result<(), Box<dyn std::error::Error + Send + Sync>>>
 where
@@ -80,89 +128,492 @@ where
     rx
 }
 
+/// Signals sent from `process_blocks` back to the loop that requests blocks.
+#[derive(Debug)]
+pub enum BlockProcessed {
+    /// The block at this height was connected to the tip.
+    Connected(u32),
+    /// A chain reorganization was detected.  Blocks above this height were rolled back and must be requested again.
+    RolledBack(u32),
+}
+
+/// Number of unspent outpoints in a subset of a tracked address type, and the satoshis they hold.
+#[derive(Clone, Copy, Debug, Default)]
+struct UtxoTotals {
+    utxos: i64,
+    sats: i64,
+}
+
+impl UtxoTotals {
+    fn apply(&mut self, (utxo_count_change, satoshis_change): (i64, i64)) {
+        self.utxos += utxo_count_change;
+        self.sats += satoshis_change;
+    }
+}
+
+/// Scanning progress of one tracked address type.
+///
+/// Address types are tracked independently, so a type added to a database that was already
+/// synced further is scanned from genesis while the other types skip the blocks they already have.
+#[derive(Debug)]
+struct TrackedType {
+    address_type: BtcAddressType,
+    /// Height of the last block whose aggregates are persisted for this type.
+    last_height: Option<u64>,
+    utxo_count: i64,
+    satoshis: i64,
+    /// Totals of the subsets split out in the block aggregates of this type.
+    subsets: HashMap<UtxoSubset, UtxoTotals>,
+    /// Number of unspent outpoints of each vintage and the satoshis they hold, for types that track vintages.
+    vintages: BTreeMap<Vintage, (i64, i64)>,
+    /// Number of unspent outpoints of each value band and the satoshis they hold.
+    value_bands: BTreeMap<ValueBand, (i64, i64)>,
+}
+
+impl TrackedType {
+    /// Returns true if the block at `height` hasn't been scanned for this type yet.
+    fn needs_block(&self, height: u64) -> bool {
+        self.last_height.is_none_or(|last_height| height > last_height)
+    }
+}
+
+/// Loads the scanning progress of every tracked address type from SQLite.
+async fn load_tracked_types(
+    sqlite_persistence: &persistence::SQLitePersistence,
+) -> Result<Vec<TrackedType>, AppError> {
+    let mut tracked_types = Vec::new();
+    for address_type in BtcAddressType::TRACKED {
+        let last_height = sqlite_persistence
+            .get_last_block_height(address_type.as_str().to_string())
+            .await?;
+        let last_block = match last_height {
+            Some(height) => {
+                sqlite_persistence
+                    .get_block_by_height(address_type.as_str().to_string(), height)
+                    .await?
+            }
+            None => None,
+        };
+
+        let mut subsets = HashMap::new();
+        for subset in UtxoSubset::split_out_for(*address_type) {
+            // Cohort totals are counted from the outpoint tags, so that changes to the cohort apply right away
+            if *subset == UtxoSubset::Cohort {
+                if !COHORT.is_empty() {
+                    let (utxos, sats) = sqlite_persistence
+                        .get_unspent_subset_totals(address_type.as_str().to_string(), *subset)
+                        .await?;
+                    subsets.insert(*subset, UtxoTotals { utxos, sats });
+                }
+                continue;
+            }
+            let totals = match last_block.as_ref().map(|block| block.subset_totals(*subset)) {
+                Some(Some((utxos, sats))) => UtxoTotals {
+                    utxos: utxos as i64,
+                    sats: sats as i64,
+                },
+                // Aggregates persisted before the subset was split out don't have its totals
+                Some(None) => {
+                    let (utxos, sats) = sqlite_persistence
+                        .get_unspent_subset_totals(address_type.as_str().to_string(), *subset)
+                        .await?;
+                    UtxoTotals { utxos, sats }
+                }
+                None => UtxoTotals::default(),
+            };
+            subsets.insert(*subset, totals);
+        }
+
+        // Vintages are counted from the outpoint records, so that changes to the buckets apply right away
+        let mut vintages: BTreeMap<Vintage, (i64, i64)> = BTreeMap::new();
+        if address_type.tracks_vintages() {
+            let origins = sqlite_persistence
+                .get_unspent_origins(address_type.as_str().to_string())
+                .await?;
+            for (block_height, block_time, value) in origins {
+                if let (Some(block_height), Some(block_time)) = (block_height, block_time) {
+                    let totals = vintages
+                        .entry(VINTAGE_BUCKETS.vintage(block_height as u64, block_time as u32))
+                        .or_default();
+                    totals.0 += 1;
+                    totals.1 += value;
+                }
+            }
+        }
+
+        // Value bands are seeded from the last block's totals, and counted from the outpoint records
+        // only for blocks scanned before value bands were recorded
+        let mut unspent_value_bands = match last_height {
+            Some(height) => {
+                sqlite_persistence
+                    .get_block_value_band_totals(address_type.as_str().to_string(), height)
+                    .await?
+            }
+            None => HashMap::new(),
+        };
+        if last_height.is_some() && unspent_value_bands.is_empty() {
+            unspent_value_bands = sqlite_persistence
+                .get_unspent_value_band_totals(address_type.as_str().to_string())
+                .await?;
+        }
+        let value_bands = ValueBand::ALL
+            .iter()
+            .map(|band| (*band, unspent_value_bands.get(band).copied().unwrap_or_default()))
+            .collect();
+
+        tracked_types.push(TrackedType {
+            address_type: *address_type,
+            last_height: last_height.map(|height| height as u64),
+            utxo_count: last_block.as_ref().map_or(0, |block| block.total_utxos as i64),
+            satoshis: last_block.as_ref().map_or(0, |block| block.total_sats as i64),
+            subsets,
+            vintages,
+            value_bands,
+        });
+    }
+    Ok(tracked_types)
+}
+
+/// Returns the persisted hash of the block at `height`, from any address type that has scanned it.
+async fn get_scanned_block_hash(
+    sqlite_persistence: &persistence::SQLitePersistence,
+    height: u64,
+) -> Result<Option<String>, AppError> {
+    for address_type in BtcAddressType::TRACKED {
+        if let Some(block) = sqlite_persistence
+            .get_block_by_height(address_type.as_str().to_string(), height as i64)
+            .await?
+        {
+            return Ok(Some(block.block_hash_big_endian));
+        }
+    }
+    Ok(None)
+}
+
+/// Walks back from `height` to the highest block that the persisted chain shares with the block source.
+async fn find_fork_height(
+    block_source: &impl BlockSource,
+    sqlite_persistence: &persistence::SQLitePersistence,
+    height: u64,
+) -> Result<u64, AppError> {
+    let mut fork_height = height;
+    loop {
+        let persisted_hash = get_scanned_block_hash(sqlite_persistence, fork_height).await?;
+        let source_hash = block_source.get_block_hash(fork_height)?;
+        if let (Some(persisted_hash), Some(source_hash)) = (persisted_hash, source_hash) {
+            if persisted_hash == source_hash.to_string() {
+                return Ok(fork_height);
+            }
+        }
+
+        if fork_height == 0 || height - fork_height >= MAX_REORG_DEPTH {
+            return Err(AppError::CustomError(format!(
+                "No common ancestor found within {} blocks of height {}",
+                MAX_REORG_DEPTH, height
+            )));
+        }
+        fork_height -= 1;
+    }
+}
+
 /// Processes blocks and persists data to SQLite database
+///
+/// `last_block` is the height and hash of the block the first received block must build on.
 async fn process_blocks(
-    block_handle: impl Handle,
-    db: Arc<sled::Db>,
-    sqlite_persistence: persistence::SQLitePersistence,
-    block_processed_tx: crossbeam_channel::Sender<u32>,
-    sse_sender: broadcast::Sender<BlockAggregateOutput>,
-    initial_p2pk_addresses: i32,
-    initial_p2pk_coins: i64,
+    block_source: impl BlockSource,
+    utxo_store: UtxoStore,
+    alert_rules: &[AlertRule],
+    block_processed_tx: crossbeam_channel::Sender<BlockProcessed>,
+    sse_sender: broadcast::Sender<StreamEvent>,
+    mut tracked_types: Vec<TrackedType>,
+    mut last_block: Option<(u64, String)>,
 ) -> Result<(), AppError> {
-    let mut p2pk_tx_count: i32 = initial_p2pk_addresses;
-    let mut p2pk_satoshis: i64 = initial_p2pk_coins;
-
     info!("Starting block processing...");
+    let sqlite_persistence = utxo_store.sqlite().clone();
 
-    for (block, height) in block_handle.blocks() {
+    for (block, height) in block_source.blocks() {
         info!(
             "Processing Block {}: {} transactions",
             height,
             block.txdata.len()
         );
 
-        // Scan the block for P2PK transactions
+        // Check that the block builds on the last processed block
+        if let Some((tip_height, tip_hash)) = &last_block {
+            if block.header.prev_blockhash.to_string() != *tip_hash {
+                let tip_height = *tip_height;
+                warn!(
+                    "Block {} does not build on block {} ({}), chain reorganization detected",
+                    height, tip_height, tip_hash
+                );
+
+                let fork_height = find_fork_height(&block_source, &sqlite_persistence, tip_height).await?;
+                let reorg = ReorgOutput {
+                    fork_height: fork_height as usize,
+                    stale_tip_height: tip_height as usize,
+                    stale_tip_hash_big_endian: tip_hash.clone(),
+                };
+
+                // Have webhook consumers discard the stale blocks too
+                let outbox = WEBHOOKS.messages(WebhookEvent::Reorg, None, &reorg);
+                info!("Rolling back blocks {} to {}", fork_height + 1, tip_height);
+                utxo_store.rollback_blocks_above(fork_height, &outbox).await?;
+
+                tracked_types = load_tracked_types(&sqlite_persistence).await?;
+                last_block = get_scanned_block_hash(&sqlite_persistence, fork_height)
+                    .await?
+                    .map(|hash| (fork_height, hash));
+
+                // Have the rolled back blocks requested again from the new chain
+                block_processed_tx.send(BlockProcessed::RolledBack(fork_height as u32))?;
+
+                // Send SSE notification so clients can discard the stale blocks
+                if let Err(err) = sse_sender.send(StreamEvent::Reorg(reorg)) {
+                    error!("Failed to send SSE: {:?}", err);
+                }
+                continue;
+            }
+        }
+
+        // Address types that were already scanned past this block skip it
+        let mut deltas: HashMap<BtcAddressType, BlockDelta> = tracked_types
+            .iter()
+            .filter(|tracked_type| tracked_type.needs_block(height))
+            .map(|tracked_type| (tracked_type.address_type, BlockDelta::new(height, block.header.time)))
+            .collect();
+
+        // Scan the block for outputs of the tracked address types
         for tx in block.txdata.iter() {
             let txid = tx.txid();
 
             for (i, output) in tx.output.iter().enumerate() {
-                if output.script_pubkey.is_p2pk() {
-                    db.insert(
-                        format!("{}:{}", txid, i).as_bytes(),
-                        output.value.to_le_bytes().to_vec(),
-                    )?;
-
-                    p2pk_tx_count += 1;
-                    p2pk_satoshis += output.value as i64;
+                let address_type = match BtcAddressType::from_script(&output.script_pubkey) {
+                    Some(address_type) => address_type,
+                    None => continue,
+                };
+                if let Some(delta) = deltas.get_mut(&address_type) {
+                    let outpoint = OutPoint::new(txid, i as u32);
+                    delta.create(
+                        outpoint,
+                        TrackedOutput {
+                            value: output.value as i64,
+                            script_pubkey: output.script_pubkey.to_bytes(),
+                            is_coinbase: tx.is_coin_base(),
+                            pubkey: address_type.exposed_pubkey(&output.script_pubkey).map(<[u8]>::to_vec),
+                            pubkey_hash: address_type.pubkey_hash(&output.script_pubkey).map(<[u8]>::to_vec),
+                            multisig: match address_type {
+                                BtcAddressType::P2MS => Multisig::from_script(&output.script_pubkey),
+                                _ => None,
+                            },
+                            in_cohort: COHORT.contains(&outpoint, tx.is_coin_base().then_some(height)),
+                        },
+                    );
                 }
             }
 
-            for input in tx.input.iter() {
-                let input_txid = input.previous_output.txid;
-                let input_vout = input.previous_output.vout;
-                let input_key = format!("{}:{}", input_txid, input_vout);
-                if let Some(value_bytes) = db.get(input_key.as_bytes())? {
-                    let value = i64::from_le_bytes(value_bytes.as_ref().try_into().unwrap());
-                    p2pk_tx_count -= 1;
-                    p2pk_satoshis -= value;
-                    db.remove(input_key.as_bytes())?;
+            if tx.is_coin_base() {
+                continue;
+            }
+
+            for (input_index, input) in tx.input.iter().enumerate() {
+                for (address_type, delta) in deltas.iter_mut() {
+                    let spent = match utxo_store.get(*address_type, delta, &input.previous_output).await? {
+                        Some(spent) => spent,
+                        None => continue,
+                    };
+                    delta.spend(input.previous_output, spent.clone());
+
+                    // Spending from a P2PKH or P2WPKH address reveals its public key.  Only spends from
+                    // addresses whose public key was revealed before are of interest.  Spends that don't
+                    // carry a public key reveal nothing, and are recorded like any other.
+                    if let (BtcAddressType::ReusedPkh, Some(pubkey)) = (*address_type, spent_pubkey(input)) {
+                        let pubkey_hash = hash160::Hash::hash(pubkey).to_vec();
+                        if !delta.revealed.contains_key(&pubkey_hash)
+                            && !sqlite_persistence
+                                .is_pubkey_revealed(address_type.as_str().to_string(), &pubkey_hash)
+                                .await?
+                        {
+                            delta.revealed.insert(pubkey_hash, pubkey.to_vec());
+                            break;
+                        }
+                    }
+
+                    if spent.in_cohort {
+                        warn!(
+                            "Cohort {} output {} holding {} satoshis spent by {}",
+                            address_type, input.previous_output, spent.value, txid
+                        );
+                    } else {
+                        info!(
+                            "{} output {} holding {} satoshis spent by {}",
+                            address_type, input.previous_output, spent.value, txid
+                        );
+                    }
+                    let created_height = spent.block_height.map(|created_height| created_height as i64);
+                    delta.spend_events.push(SpendOutput {
+                        spending_txid: txid.to_string(),
+                        input_index: input_index as u32,
+                        block_height: height as usize,
+                        block_time: block.header.time as i64,
+                        txid: input.previous_output.txid.to_string(),
+                        vout: input.previous_output.vout,
+                        value: spent.value,
+                        created_height,
+                        age_blocks: created_height.map(|created_height| height as i64 - created_height),
+                        age_days: spent
+                            .block_time
+                            .map(|created_time| (block.header.time as i64 - created_time as i64) as f64 / 86400.0),
+                        destination_script_types: tx
+                            .output
+                            .iter()
+                            .map(|output| script_type(&output.script_pubkey).to_string())
+                            .collect(),
+                        high_priority: spent.in_cohort,
+                    });
+                    break;
                 }
             }
         }
 
-        info!(
-            "P2PK Transactions: {}, P2PK Satoshis: {}",
-            p2pk_tx_count, p2pk_satoshis
-        );
+        let date = Utc
+            .timestamp_opt(block.header.time as i64, 0)
+            .unwrap()
+            .format("%Y-%m-%d %H:%M:%S UTC")
+            .to_string();
+        let block_hash = block.block_hash().to_string();
+
+        let mut commits = Vec::new();
+        for tracked_type in tracked_types.iter_mut() {
+            let delta = match deltas.remove(&tracked_type.address_type) {
+                Some(delta) => delta,
+                None => continue,
+            };
+            let value_band_changes = match tracked_type.address_type {
+                // Only outpoints at addresses whose public key has been revealed are counted
+                BtcAddressType::ReusedPkh => sqlite_persistence
+                    .get_exposure_change(tracked_type.address_type.as_str().to_string(), &delta)
+                    .await?,
+                _ => delta.value_band_changes(),
+            };
+            let mut utxo_count_change = 0;
+            let mut satoshis_change = 0;
+            for (value_band, (band_utxo_count_change, band_satoshis_change)) in value_band_changes {
+                let totals = tracked_type.value_bands.entry(value_band).or_default();
+                totals.0 += band_utxo_count_change;
+                totals.1 += band_satoshis_change;
+                utxo_count_change += band_utxo_count_change;
+                satoshis_change += band_satoshis_change;
+            }
+            tracked_type.utxo_count += utxo_count_change;
+            tracked_type.satoshis += satoshis_change;
 
-        // Persist the block data to the SQLite database
-        let block_data = BlockAggregateOutput {
-            date: Utc
-                .timestamp_opt(block.header.time as i64, 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S UTC")
-                .to_string(),
-            block_height: height as usize,
-            block_hash_big_endian: block.block_hash().to_string(),
-            total_utxos: p2pk_tx_count as u32,
-            total_sats: p2pk_satoshis as f64,
-        };
+            for (subset, totals) in tracked_type.subsets.iter_mut() {
+                totals.apply(delta.subset_change(*subset));
+            }
+            if tracked_type.address_type.tracks_vintages() {
+                for (vintage, (utxo_count_change, satoshis_change)) in delta.vintage_changes(&VINTAGE_BUCKETS) {
+                    let totals = tracked_type.vintages.entry(vintage).or_default();
+                    totals.0 += utxo_count_change;
+                    totals.1 += satoshis_change;
+                }
+                // Vintages whose outpoints have all been spent aren't stored
+                tracked_type.vintages.retain(|_, (utxos, _)| *utxos > 0);
+            }
+            tracked_type.last_height = Some(height);
+
+            info!(
+                "{} UTXOs: {}, {} Satoshis: {}",
+                tracked_type.address_type, tracked_type.utxo_count, tracked_type.address_type, tracked_type.satoshis
+            );
+
+            let mut aggregate = BlockAggregateOutput::new(
+                date.clone(),
+                height as usize,
+                block_hash.clone(),
+                tracked_type.utxo_count as u32,
+                tracked_type.satoshis as f64,
+            );
+            for (subset, totals) in tracked_type.subsets.iter() {
+                aggregate.set_subset_totals(*subset, totals.utxos as u32, totals.sats as f64);
+            }
+            aggregate.coin_days_destroyed = Some(delta.coin_days_destroyed());
+
+            let alerts =
+                alerts::evaluate_rules(alert_rules, &sqlite_persistence, tracked_type.address_type, &delta).await?;
+
+            commits.push(BlockCommit {
+                address_type: tracked_type.address_type,
+                delta,
+                aggregate,
+                vintages: tracked_type.vintages.clone(),
+                value_bands: tracked_type.value_bands.clone(),
+                alerts,
+            });
+        }
 
-        sqlite_persistence
-            .persist_block_aggregates(BtcAddressType::P2PK.as_str().to_string(), &block_data)
-            .await?;
+        // Queue the block's events for delivery to webhooks
+        let mut outbox = Vec::new();
+        if WEBHOOKS.delivers_block(block.header.time) {
+            for commit in commits.iter() {
+                let address_type = Some(commit.address_type);
+                outbox.extend(WEBHOOKS.messages(WebhookEvent::Aggregate, address_type, &commit.aggregate));
+                for spend in commit.delta.spend_events.iter() {
+                    outbox.extend(WEBHOOKS.messages(WebhookEvent::Spend, address_type, spend));
+                }
+                for alert in commit.alerts.iter() {
+                    outbox.extend(WEBHOOKS.messages(WebhookEvent::Alert, address_type, alert));
+                }
+            }
+        }
 
-        // Signal that we've processed this block
-        block_processed_tx.send(height as u32)?;
+        // Queue emails of the alerts severe enough to notify on-call about
+        if EMAIL.is_enabled() && EMAIL.is_recent(block.header.time) {
+            outbox.extend(
+                commits
+                    .iter()
+                    .flat_map(|commit| commit.alerts.iter())
+                    .flat_map(|alert| EMAIL.messages(alert)),
+            );
+        }
+
+        // Commit the block's UTXO changes together with its aggregates and the outbox messages of its events
+        utxo_store.commit_block(&commits, &outbox).await?;
+        last_block = Some((height, block_hash));
+
+        for alert in commits.iter().flat_map(|commit| commit.alerts.iter()) {
+            warn!("{} {} alert: {}", alert.severity, alert.rule, alert.message);
+        }
 
-        // Send SSE notification
-        if let Err(err) = sse_sender.send(block_data.clone()) {
-            error!("Failed to send SSE: {:?}", err);
+        // Stream the block's spends and alerts ahead of its aggregates, which carry the id SSE clients resume from
+        for commit in commits.iter() {
+            for spend in commit.delta.spend_events.iter() {
+                let spend = SpendStreamOutput {
+                    address_type: commit.address_type.as_str().to_string(),
+                    spend: spend.clone(),
+                };
+                if let Err(err) = sse_sender.send(StreamEvent::Spend(spend)) {
+                    error!("Failed to send SSE: {:?}", err);
+                }
+            }
+            for alert in commit.alerts.iter() {
+                if let Err(err) = sse_sender.send(StreamEvent::Alert(alert.clone())) {
+                    error!("Failed to send SSE: {:?}", err);
+                }
+            }
+        }
+
+        for commit in commits.iter() {
+            if let Err(err) = sse_sender.send(StreamEvent::Aggregate(commit.address_type, commit.aggregate.clone())) {
+                error!("Failed to send SSE: {:?}", err);
+            }
         }
 
-        // Capture the chart as an image
-        if height % *CAPTURE_FREQUENCY as u64 == 0 {
+        // Signal that we've processed this block
+        block_processed_tx.send(BlockProcessed::Connected(height as u32))?;
+
+        // Capture the chart of P2PK aggregates as an image
+        let tracks_p2pk = commits.iter().any(|commit| commit.address_type == BtcAddressType::P2PK);
+        if tracks_p2pk && height % *CAPTURE_FREQUENCY as u64 == 0 {
             capture_p2pk_blocks_graph(height as usize).await?;
         }
     }
@@ -170,8 +621,29 @@ async fn process_blocks(
     Ok(())
 }
 
+/// Routes of the REST API, served under /api.
+fn api_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/blocks/latest", get(api::get_latest_block_aggregates))
+        .route("/block/hash/:hash", get(api::get_block_by_hash))
+        .route("/block/height/:height", get(api::get_block_by_height))
+        .route("/all/block/hash/:hash", get(api::get_combined_block_by_hash))
+        .route("/all/block/height/:height", get(api::get_combined_block_by_height))
+        .route("/:address_type/block/hash/:hash", get(api::get_address_type_block_by_hash))
+        .route("/:address_type/block/height/:height", get(api::get_address_type_block_by_height))
+        .route("/blocks/stream", get(api::stream_blocks))
+        .route("/ws", get(ws::stream_events))
+        .route("/vintages/latest", get(api::get_latest_block_vintages))
+        .route("/value_bands/latest", get(api::get_latest_block_value_bands))
+        .route("/alerts", get(api::get_alerts))
+        .route("/utxos/:address_type", get(api::get_unspent_outputs))
+        .route("/spends", get(api::get_spends))
+        .route("/chart/p2pk/generate/latest", put(api::generate_latest_p2pk_chart))
+        .fallback(api::route_not_found)
+}
+
 async fn run_apis_and_web_app(
-    sender: broadcast::Sender<BlockAggregateOutput>,
+    sender: broadcast::Sender<StreamEvent>,
 ) -> anyhow::Result<()> {
 
     // Create a SQLite persistence instance with a connection pool
@@ -179,7 +651,7 @@ async fn run_apis_and_web_app(
 
     let app_state = Arc::new(AppState {
         db: sqlite_persistence,
-        sender: sender
+        sender
     });
 
     // Determine socket that web_app will bind top
@@ -195,17 +667,15 @@ async fn run_apis_and_web_app(
         .allow_headers(Any);
 
     // Define your API routes with CORS enabled
-    let api_routes = Router::new()
-        .route("/blocks/latest", get(api::get_latest_block_aggregates))
-        .route("/block/hash/:hash", get(api::get_block_by_hash))
-        .route("/block/height/:height", get(api::get_block_by_height))
-        .route("/blocks/stream", get(api::stream_blocks))
-        .route("/chart/p2pk/generate/latest", put(api::generate_latest_p2pk_chart))
-        .layer(cors_layer.clone()); // Apply CORS layer to API routes
+    let api_routes = api_routes().layer(cors_layer.clone()); // Apply CORS layer to API routes
 
-    // Define the router for static files
+    // Define the router for static files.  Paths that aren't files, such as /p2pk/block/830000, are routed by the
+    // web app itself
+    let static_files = ServeDir::new("web/build")
+        .append_index_html_on_directories(true)
+        .fallback(ServeFile::new("web/build/index.html"));
     let static_files_router = Router::new()
-        .nest_service("/", ServeDir::new("web/build").append_index_html_on_directories(true))
+        .nest_service("/", static_files)
         .layer(cors_layer.clone());
 
     // Combine the routers
@@ -260,17 +730,43 @@ async fn main() -> Result<(), AppError> {
     // Initialize the logger with a default configuration that can be overridden by RUST_LOG
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info,p2p=warn")).init();
 
-    // Create a broadcast channel for SSE events and start the API server
-    let (tx, _rx) = broadcast::channel(100);
+    // Create a broadcast channel for SSE events and start the API server.
+    // It holds the spends of a busy block, so that clients keeping up with new blocks don't lag
+    let (tx, _rx) = broadcast::channel(1000);
     run_apis_and_web_app(tx.clone()).await?;
 
+    // Deliver queued webhook events and alert emails in the background, including those left over from the last run
+    if WEBHOOKS.is_enabled() || EMAIL.is_enabled() {
+        let sqlite_persistence = persistence::SQLitePersistence::new(1).await?;
+        tokio::spawn(webhooks::run_delivery(sqlite_persistence, &WEBHOOKS, &EMAIL));
+    }
+
     // Check if we should run the Nakamoto analysis (defaults to true)
     let run_analysis = env::var("RUN_NAKAMOTO_ANALYSIS")
         .map(|val| val.to_lowercase() != "false")
         .unwrap_or(true);
 
+    // Select where blocks come from (defaults to the Nakamoto P2P client)
+    let block_source = env::var("BLOCK_SOURCE")
+        .unwrap_or_else(|_| "nakamoto".to_string())
+        .to_lowercase();
+
     if run_analysis {
-        run_nakamoto_analysis(tx.clone()).await?;
+        match block_source.as_str() {
+            "nakamoto" => run_nakamoto_analysis(tx.clone()).await?,
+            "bitcoind_rpc" => {
+                run_block_analysis(BitcoinRpcBlockSource::from_env()?, tx.clone()).await?
+            }
+            "blk_files" => {
+                run_block_analysis(BlkFilesBlockSource::from_env()?, tx.clone()).await?
+            }
+            other => {
+                return Err(AppError::CustomError(format!(
+                    "Unknown BLOCK_SOURCE: {}",
+                    other
+                )))
+            }
+        }
     } else {
         // Wait for shutdown signal instead of pending forever
         shutdown_signal().await;
@@ -280,52 +776,8 @@ async fn main() -> Result<(), AppError> {
 }
 
 async fn run_nakamoto_analysis(
-    sse_sender: broadcast::Sender<BlockAggregateOutput>,
+    sse_sender: broadcast::Sender<StreamEvent>,
 ) -> Result<(), AppError> {
-    info!("Initializing sled key-value store to track P2PK transactions...");
-    let db = sled::open("db")?;
-    let db = Arc::new(db); // Wrap in Arc for thread-safe sharing
-
-    info!("Initializing sqlite to store block data");
-    let sqlite_persistence = persistence::SQLitePersistence::new(1)
-        .await
-        .map_err(|e| AppError::SqliteError(e))?;
-
-    // Get the last block height from the sqlite database
-    let resume_height = {
-        let last_height = sqlite_persistence
-            .get_last_block_height(BtcAddressType::P2PK.as_str().to_string())
-            .await?;
-        debug!("Last height from database: {:?}", last_height);
-        match last_height {
-            Some(height) => height.checked_add(1).unwrap_or(1) as u64,
-            None => 0, // If the database is empty, start from the first block
-        }
-    };
-
-    // Get the total utxos and sats from the last processed block
-    let (p2pk_addresses, p2pk_coins) = {
-        if resume_height > 0 {
-            let last_block = sqlite_persistence
-                .get_block_by_height(
-                    BtcAddressType::P2PK.as_str().to_string(),
-                    (resume_height - 1) as i64,
-                )
-                .await?;
-            match last_block {
-                Some(block) => (block.total_utxos as i32, block.total_sats as i64),
-                None => (0, 0),
-            }
-        } else {
-            (0, 0)
-        }
-    };
-
-    info!(
-        "Resuming from height {}, P2PK addresses: {}, P2PK satoshis: {}",
-        resume_height, p2pk_addresses, p2pk_coins
-    );
-
     info!("Configuring Nakamoto client...");
     let cfg = Config::new(Network::Mainnet);
 
@@ -333,11 +785,7 @@ async fn run_nakamoto_analysis(
     // Create a client using the above network reactor.
     let client = Client::<Reactor>::new()?;
     let header_handle = client.handle();
-    let block_handle = client.handle();
-
-    info!("Setting up block processed channel...");
-    // Create a channel to signal when a block has been processed.
-    let (block_processed_tx, block_processed_rx) = bounded::<u32>(1);
+    let block_source = NakamotoBlockSource::new(client.handle());
 
     info!("Spawning client thread...");
     // Spawn the client thread
@@ -357,23 +805,127 @@ async fn run_nakamoto_analysis(
     info!("Waiting for {} peer(s) to connect...", peer_count);
     header_handle.wait_for_peers(peer_count, Services::Chain)?;
 
+    // Processes blocks up to the tip and shuts the Nakamoto client down
+    run_block_analysis(block_source, sse_sender).await?;
+
+    // Handle potential errors from the client thread
+    let client_result = client_rx.recv();
+
+    // Check client thread result
+    if let Ok(Err(e)) = client_result {
+        error!("Client encountered an error: {}", e);
+        return Err(AppError::Other(e));
+    } else if let Ok(Ok(_)) = client_result {
+        info!("Client thread terminated gracefully.");
+        return Err(AppError::CustomError(
+            "Client thread terminated gracefully.".to_owned(),
+        ));
+    } else if let Err(e) = client_result {
+        error!("Failed to receive from client thread: {}", e);
+        return Err(AppError::CustomError(format!(
+            "Failed to receive from client thread: {}",
+            e
+        )));
+    }
+
+    info!("Program completed successfully.");
+    Ok(())
+}
+
+/// Drives `process_blocks` with blocks from `block_source`, from the last persisted height up to the tip.
+/// The block source is shut down once the tip has been processed.
+async fn run_block_analysis(
+    block_source: impl BlockSource,
+    sse_sender: broadcast::Sender<StreamEvent>,
+) -> Result<(), AppError> {
+    info!("Initializing sqlite to store block data");
+    let sqlite_persistence = persistence::SQLitePersistence::new(1)
+        .await
+        .map_err(AppError::SqliteError)?;
+
+    // Select where tracked outpoints are stored (defaults to sled)
+    let utxo_store = match env::var("UTXO_STORE")
+        .unwrap_or_else(|_| "sled".to_string())
+        .to_lowercase()
+        .as_str()
+    {
+        "sled" => {
+            info!("Initializing sled key-value store to track UTXOs...");
+            UtxoStore::open_sled(SLED_DB_PATH, &sqlite_persistence)?
+        }
+        "sqlite" => {
+            info!("Tracking UTXOs in sqlite...");
+            if let Ok(sled_path) = env::var("MIGRATE_SLED_DB_PATH") {
+                migrate_sled_to_sqlite(&sled_path, &sqlite_persistence).await?;
+            }
+            UtxoStore::Sqlite(sqlite_persistence.clone())
+        }
+        other => {
+            return Err(AppError::CustomError(format!(
+                "Unknown UTXO_STORE: {}",
+                other
+            )))
+        }
+    };
+
+    // Make sure the UTXO set and the aggregates end at the same block before resuming
+    utxo_store.reconcile().await?;
+
+    // Tag the outpoints of the cohort, which may have changed since the last run
+    for address_type in BtcAddressType::TRACKED {
+        let members = sqlite_persistence
+            .tag_cohort(address_type.as_str().to_string(), &COHORT)
+            .await?;
+        if members > 0 {
+            info!("{}: {} unspent outputs in the cohort", address_type, members);
+        }
+    }
+
+    // Resume from the first block that hasn't been scanned for every tracked address type
+    let tracked_types = load_tracked_types(&sqlite_persistence).await?;
+    let resume_height = tracked_types
+        .iter()
+        .map(|tracked_type| tracked_type.last_height.map_or(0, |height| height + 1))
+        .min()
+        .unwrap_or(0);
+    for tracked_type in tracked_types.iter() {
+        info!(
+            "{}: last block {:?}, UTXOs: {}, satoshis: {}",
+            tracked_type.address_type, tracked_type.last_height, tracked_type.utxo_count, tracked_type.satoshis
+        );
+    }
+    info!("Resuming from height {}", resume_height);
+
+    // The block that the first block processed must build on
+    let last_block = match resume_height.checked_sub(1) {
+        Some(height) => get_scanned_block_hash(&sqlite_persistence, height)
+            .await?
+            .map(|hash| (height, hash)),
+        None => None,
+    };
+
+    info!("Setting up block processed channel...");
+    // Create a channel to signal when a block has been processed.
+    let (block_processed_tx, block_processed_rx) = bounded::<BlockProcessed>(1);
+
     info!("Fetching initial tip height...");
-    let (mut tip_height, _) = header_handle.get_tip()?;
+    let mut tip_height = block_source.get_tip()?;
     info!("Initial tip height: {}", tip_height);
 
     info!("Spawning block processing thread...");
-    let db_clone = Arc::clone(&db);
+    let processor_source = block_source.clone();
+    let progress_sender = sse_sender.clone();
     let block_processor_rx = spawn_thread(move || {
         let runtime = tokio::runtime::Runtime::new()?;
         runtime.block_on(async {
             process_blocks(
-                block_handle,
-                db_clone,
-                sqlite_persistence,
+                processor_source,
+                utxo_store,
+                &ALERT_RULES,
                 block_processed_tx,
                 sse_sender,
-                p2pk_addresses,
-                p2pk_coins,
+                tracked_types,
+                last_block,
             )
             .await
             .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
@@ -385,32 +937,39 @@ async fn run_nakamoto_analysis(
         resume_height, tip_height
     );
 
-    #[allow(clippy::mut_range_bound)]
-    for i in resume_height..=tip_height {
-        info!("Fetching block at height {}...", i);
-        let block_header = header_handle.get_block_by_height(i)?;
-        let block_hash = match block_header {
-            Some(h) => h.block_hash(),
-            None => {
-                error!("No block found at height {}", i);
-                continue;
-            }
-        };
-
-        info!("Block {} hash: {:?}", i, block_hash);
+    let mut height = resume_height;
+    while height <= tip_height {
+        info!("Fetching block at height {}...", height);
 
         // Request the block.
-        header_handle.get_block(&block_hash)?;
+        if block_source.request_blocks(height..=height)? == 0 {
+            height += 1;
+            continue;
+        }
 
         // Wait for the block thread to process a block.
         match block_processed_rx.recv() {
-            Ok(height) => {
-                assert_eq!(
-                    height, i as u32,
-                    "Received block height {} doesn't match requested height {}",
-                    height, i
-                );
-                info!("Successfully processed block {}", height);
+            Ok(BlockProcessed::Connected(processed_height)) => {
+                if processed_height != height as u32 {
+                    return Err(AppError::CustomError(format!(
+                        "Received block height {} doesn't match requested height {}",
+                        processed_height, height
+                    )));
+                }
+                info!("Successfully processed block {}", processed_height);
+                let progress = SyncProgressOutput {
+                    block_height: height as usize,
+                    tip_height: tip_height as usize,
+                    progress_percent: if tip_height == 0 { 100.0 } else { height as f64 * 100.0 / tip_height as f64 },
+                };
+                if let Err(err) = progress_sender.send(StreamEvent::SyncProgress(progress)) {
+                    error!("Failed to send SSE: {:?}", err);
+                }
+                height += 1;
+            }
+            Ok(BlockProcessed::RolledBack(fork_height)) => {
+                info!("Chain rolled back to height {}, re-requesting blocks", fork_height);
+                height = fork_height as u64 + 1;
             }
             Err(e) => {
                 error!("Error waiting for block processing: {}", e);
@@ -419,7 +978,7 @@ async fn run_nakamoto_analysis(
         }
 
         // Update the tip height after processing each block
-        let (new_tip_height, _) = header_handle.get_tip()?;
+        let new_tip_height = block_source.get_tip()?;
         if new_tip_height > tip_height {
             info!("New tip height detected: {}", new_tip_height);
             tip_height = new_tip_height;
@@ -428,56 +987,66 @@ async fn run_nakamoto_analysis(
 
     info!("All blocks processed up to height {}.", tip_height);
 
-    info!("Shutting down Nakamoto client...");
-    // Ask the client to terminate.
-    header_handle.shutdown()?;
-    info!("Client shut down gracefully.");
-
-    // Handle potential errors from both threads simultaneously
-    let (client_result, block_processor_result) = (client_rx.recv(), block_processor_rx.recv());
-
-    // Check client thread result
-    if let Ok(Err(e)) = client_result {
-        error!("Client encountered an error: {}", e);
-        return Err(AppError::Other(e));
-    } else if let Ok(Ok(_)) = client_result {
-        info!("Client thread terminated gracefully.");
-        return Err(AppError::CustomError(
-            "Client thread terminated gracefully.".to_owned(),
-        ));
-    } else if let Err(e) = client_result {
-        error!("Failed to receive from client thread: {}", e);
-        return Err(AppError::CustomError(format!(
-            "Failed to receive from client thread: {}",
-            e
-        )));
-    }
+    info!("Shutting down block source...");
+    block_source.shutdown()?;
+    info!("Block source shut down gracefully.");
 
     // Check block processor thread result
-    if let Ok(Err(e)) = block_processor_result {
-        error!("Block processor encountered an error: {}", e);
-        return Err(AppError::Other(e));
-    } else if let Ok(Ok(_)) = block_processor_result {
-        info!("Block processor thread terminated gracefully.");
-        return Err(AppError::CustomError(
-            "Block processor thread terminated gracefully.".to_owned(),
-        ));
-    } else if let Err(e) = block_processor_result {
-        error!("Failed to receive from block processor thread: {}", e);
-        return Err(AppError::CustomError(format!(
-            "Failed to receive from block processor thread: {}",
-            e
-        )));
+    match block_processor_rx.recv() {
+        Ok(Ok(_)) => {
+            info!("Block processor thread terminated gracefully.");
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            error!("Block processor encountered an error: {}", e);
+            Err(AppError::Other(e))
+        }
+        Err(e) => {
+            error!("Failed to receive from block processor thread: {}", e);
+            Err(AppError::CustomError(format!(
+                "Failed to receive from block processor thread: {}",
+                e
+            )))
+        }
     }
-
-    info!("Program completed successfully.");
-    Ok(())
 }
 
+/// Error returned by the API handlers.  Serialized as `{"error": "...", "status": 400, "param": "limit"}`,
+/// where `param` names the invalid request parameter, or is null.
 #[derive(Debug)]
 pub struct ApiError {
     pub status: StatusCode,
     pub message: String,
+    pub param: Option<String>,
+}
+
+impl ApiError {
+    /// The request parameter `param` is invalid.
+    pub fn bad_request(param: &str, message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            param: Some(param.to_string()),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: message.into(),
+            param: None,
+        }
+    }
+
+    /// Reading the data requested failed.  The cause is logged as well as returned.
+    pub fn internal(error: impl fmt::Display) -> Self {
+        error!("API request failed: {}", error);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: error.to_string(),
+            param: None,
+        }
+    }
 }
 
 impl fmt::Display for ApiError {
@@ -488,7 +1057,449 @@ impl fmt::Display for ApiError {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let body = json!({ "error": self.message });
+        let body = json!({ "error": self.message, "status": self.status.as_u16(), "param": self.param });
         (self.status, axum::Json(body)).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use nakamoto::common::bitcoin::blockdata::script::Builder;
+    use nakamoto::common::bitcoin::hashes::hex::{FromHex, ToHex};
+    use nakamoto::common::bitcoin::{Block, BlockHash, PublicKey, Script, Transaction};
+
+    use crate::block_source::VecBlockSource;
+    use crate::test_fixtures::{
+        self, block, chain, coinbase, multisig_script, output, p2pk_script, p2pkh_script, p2tr_script, p2wpkh_script,
+        process, spend, uncompressed_p2pk_script,
+    };
+
+    /// A chain whose block 2 spends the P2PK coinbase output of block 1 to a new 49 BTC P2PK output.
+    fn chain_with_spend() -> Vec<Block> {
+        let spent = OutPoint::new(coinbase(1, vec![output(5_000_000_000, p2pk_script())]).txid(), 0);
+        chain(3, |height| match height {
+            2 => vec![spend(&[spent], vec![output(4_900_000_000, p2pk_script())])],
+            _ => Vec::new(),
+        })
+    }
+
+    /// Blocks 1 and 2 made of the given transactions, after an empty block 0.
+    fn blocks_of(block_1: Vec<Transaction>, block_2: Vec<Transaction>) -> Vec<Block> {
+        let block_0 = block(BlockHash::all_zeros(), 0, vec![coinbase(0, Vec::new())]);
+        let block_1 = block(block_0.block_hash(), 1, block_1);
+        let block_2 = block(block_1.block_hash(), 2, block_2);
+        vec![block_0, block_1, block_2]
+    }
+
+    /// Runs `process_blocks` over blocks 1 and 2, leaving out block 0 as every third block triggers a chart capture.
+    async fn process_blocks_1_and_2(sqlite: &persistence::SQLitePersistence, blocks: Vec<Block>) {
+        let block_source = VecBlockSource::new(blocks);
+        block_source.request_blocks(1..=2).unwrap();
+        process(block_source, sqlite, broadcast::channel(100).0, &[]).await;
+    }
+
+    /// Returns the aggregates of the block at `height` for `address_type`.
+    async fn aggregates(
+        sqlite: &persistence::SQLitePersistence,
+        address_type: BtcAddressType,
+        height: i64,
+    ) -> BlockAggregateOutput {
+        sqlite.get_block_by_height(address_type.as_str().to_string(), height).await.unwrap().unwrap()
+    }
+
+    /// Returns the totals of the block at `height` for `address_type`.
+    async fn totals(sqlite: &persistence::SQLitePersistence, address_type: BtcAddressType, height: i64) -> (u32, f64) {
+        let block = aggregates(sqlite, address_type, height).await;
+        (block.total_utxos, block.total_sats)
+    }
+
+    #[tokio::test]
+    async fn process_blocks_aggregates_outputs_and_spends() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let (sse_sender, mut sse_rx) = broadcast::channel(100);
+        let blocks = chain_with_spend();
+        let block_source = VecBlockSource::new(blocks.clone());
+
+        // Block 0 is left out, as every third block triggers a chart capture
+        assert_eq!(block_source.request_blocks(1..=2).unwrap(), 2);
+        let processed = process(block_source, &sqlite, sse_sender, &[]).await;
+        assert!(matches!(processed[..], [BlockProcessed::Connected(1), BlockProcessed::Connected(2)]));
+
+        let p2pk = BtcAddressType::P2PK.as_str().to_string();
+        let block_1 = sqlite.get_block_by_height(p2pk.clone(), 1).await.unwrap().unwrap();
+        assert_eq!((block_1.total_utxos, block_1.total_sats), (1, 5_000_000_000.0));
+        let block_2 = sqlite.get_block_by_height(p2pk.clone(), 2).await.unwrap().unwrap();
+        assert_eq!((block_2.total_utxos, block_2.total_sats), (2, 9_900_000_000.0));
+        assert_eq!(block_2.block_hash_big_endian, blocks[2].block_hash().to_string());
+        assert_eq!(block_2.coinbase_utxos, Some(1));
+        assert_eq!(block_2.non_coinbase_sats, Some(4_900_000_000.0));
+
+        let spends = sqlite.get_spends(p2pk, 0, 10, 0).await.unwrap();
+        assert_eq!(spends.len(), 1);
+        assert_eq!((spends[0].value, spends[0].created_height, spends[0].age_blocks), (5_000_000_000, Some(1), Some(1)));
+
+        // Other tracked types get aggregates of the same blocks
+        let p2tr = sqlite.get_block_by_height(BtcAddressType::P2TR.as_str().to_string(), 2).await.unwrap().unwrap();
+        assert_eq!(p2tr.total_utxos, 0);
+
+        let mut topics = Vec::new();
+        while let Ok(event) = sse_rx.try_recv() {
+            topics.push(event.topic());
+        }
+        assert!(topics.contains(&"spend"));
+        assert_eq!(topics.iter().filter(|topic| **topic == "aggregate").count(), 2 * BtcAddressType::TRACKED.len());
+    }
+
+    #[tokio::test]
+    async fn load_tracked_types_seeds_value_bands_from_the_last_block() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let block_source = VecBlockSource::new(chain_with_spend());
+        block_source.request_blocks(1..=2).unwrap();
+        process(block_source, &sqlite, broadcast::channel(100).0, &[]).await;
+
+        let p2pk = BtcAddressType::P2PK.as_str().to_string();
+        let recorded = sqlite.get_block_value_band_totals(p2pk.clone(), 2).await.unwrap();
+        assert_eq!(recorded[&ValueBand::FiftyBtcOrMore], (1, 5_000_000_000));
+        assert_eq!(recorded[&ValueBand::TenToFiftyBtc], (1, 4_900_000_000));
+
+        // The seeded totals match those counted from the outpoint records
+        let tracked_types = load_tracked_types(&sqlite).await.unwrap();
+        let p2pk_type = tracked_types.iter().find(|tracked_type| tracked_type.address_type == BtcAddressType::P2PK).unwrap();
+        let counted = sqlite.get_unspent_value_band_totals(p2pk).await.unwrap();
+        for (band, totals) in p2pk_type.value_bands.iter() {
+            assert_eq!(*totals, counted.get(band).copied().unwrap_or_default(), "{:?}", band);
+        }
+    }
+
+    #[tokio::test]
+    async fn tag_cohort_tags_only_the_coinbase_outputs_of_listed_blocks() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let block_source = VecBlockSource::new(chain_with_spend());
+        block_source.request_blocks(1..=2).unwrap();
+        process(block_source, &sqlite, broadcast::channel(100).0, &[]).await;
+
+        // Block 2 also creates the non-coinbase 49 BTC output, which stays out
+        let cohort = Cohort {
+            heights: HashSet::from([2]),
+            outpoints: HashSet::new(),
+        };
+        let p2pk = BtcAddressType::P2PK.as_str().to_string();
+        assert_eq!(sqlite.tag_cohort(p2pk.clone(), &cohort).await.unwrap(), 1);
+        let totals = sqlite.get_unspent_subset_totals(p2pk, UtxoSubset::Cohort).await.unwrap();
+        assert_eq!(totals, (1, 5_000_000_000));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_rolls_back_a_reorganized_block() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let (sse_sender, mut sse_rx) = broadcast::channel(100);
+        let blocks = chain_with_spend();
+        let block_source = VecBlockSource::new(blocks.clone());
+        block_source.request_blocks(1..=2).unwrap();
+
+        // Block 2 is replaced by one without the spend
+        let mut fork = blocks[..2].to_vec();
+        fork.push(block(blocks[1].block_hash(), 2, vec![coinbase(2, vec![output(5_000_000_000, p2pk_script())])]));
+        block_source.set_chain(fork.clone());
+        // The first new block 2 is dropped when the reorg is detected, and the second connects
+        block_source.request_blocks(2..=2).unwrap();
+        block_source.request_blocks(2..=2).unwrap();
+
+        let processed = process(block_source, &sqlite, sse_sender, &[]).await;
+        assert!(matches!(
+            processed[..],
+            [
+                BlockProcessed::Connected(1),
+                BlockProcessed::Connected(2),
+                BlockProcessed::RolledBack(1),
+                BlockProcessed::Connected(2)
+            ]
+        ));
+
+        let p2pk = BtcAddressType::P2PK.as_str().to_string();
+        let block_2 = sqlite.get_block_by_height(p2pk.clone(), 2).await.unwrap().unwrap();
+        assert_eq!(block_2.block_hash_big_endian, fork[2].block_hash().to_string());
+        assert_eq!((block_2.total_utxos, block_2.total_sats), (2, 10_000_000_000.0));
+        assert!(sqlite.get_spends(p2pk, 0, 10, 0).await.unwrap().is_empty());
+
+        let mut reorgs = Vec::new();
+        while let Ok(event) = sse_rx.try_recv() {
+            if let StreamEvent::Reorg(reorg) = event {
+                reorgs.push((reorg.fork_height, reorg.stale_tip_height));
+            }
+        }
+        assert_eq!(reorgs, vec![(1, 2)]);
+    }
+
+    /// Serves the API routes on a local port and returns their URL.
+    async fn serve_api(sqlite: &persistence::SQLitePersistence) -> String {
+        let state = Arc::new(AppState {
+            db: sqlite.clone(),
+            sender: broadcast::channel(100).0,
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api", listener.local_addr().unwrap());
+        let app = Router::new().nest("/api", api_routes()).with_state(state);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    /// Returns the status and JSON body answering a GET of `url`.
+    async fn get_json(url: String) -> (u16, serde_json::Value) {
+        tokio::task::spawn_blocking(move || {
+            let response = match ureq::get(&url).call() {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(e) => panic!("GET {} failed: {}", url, e),
+            };
+            (response.status(), serde_json::from_str(&response.into_string().unwrap()).unwrap())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn api_routes_resolve_block_lookups_for_one_or_every_address_type() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let blocks = chain_with_spend();
+        let block_source = VecBlockSource::new(blocks.clone());
+        block_source.request_blocks(1..=2).unwrap();
+        process(block_source, &sqlite, broadcast::channel(100).0, &[]).await;
+        let url = serve_api(&sqlite).await;
+        let hash = blocks[2].block_hash().to_string();
+
+        for path in [
+            "/block/height/2".to_string(),
+            format!("/block/hash/{}", hash),
+            "/all/block/height/2".to_string(),
+            format!("/all/block/hash/{}", hash),
+            "/p2tr/block/height/2".to_string(),
+            format!("/reused_pkh/block/hash/{}", hash),
+        ] {
+            let (status, body) = get_json(format!("{}{}", url, path)).await;
+            assert_eq!(status, 200, "{}: {}", path, body);
+            assert_eq!(body["block_height"], 2, "{}", path);
+            assert_eq!(body["block_hash"].as_str(), Some(&*hash), "{}", path);
+        }
+
+        // The P2PK routes and the combined route differ by their totals
+        let (_, p2pk) = get_json(format!("{}/block/height/2", url)).await;
+        assert_eq!(p2pk["total_sats"], 9_900_000_000.0);
+        let (_, all) = get_json(format!("{}/all/block/height/2", url)).await;
+        assert_eq!(all["address_types"]["p2pk"]["total_sats"], 9_900_000_000.0);
+        assert_eq!(all["address_types"]["p2tr"]["total_utxos"], 0);
+
+        let (status, latest) = get_json(format!("{}/blocks/latest?result_sampling_interval=1", url)).await;
+        assert_eq!(status, 200, "{}", latest);
+        assert_eq!(latest.as_array().map(Vec::len), Some(2));
+    }
+
+    #[tokio::test]
+    async fn api_routes_reject_untracked_address_types_and_unknown_paths() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let url = serve_api(&sqlite).await;
+
+        for path in ["/p2sh/block/height/2", "/p2pkh/block/hash/00"] {
+            let (status, body) = get_json(format!("{}{}", url, path)).await;
+            assert_eq!((status, &body["param"]), (400, &serde_json::json!("address_type")), "{}: {}", path, body);
+        }
+
+        let (status, body) = get_json(format!("{}/blocks/earliest", url)).await;
+        assert_eq!(status, 404, "{}", body);
+        assert_eq!(body["error"], "No API route matches: /api/blocks/earliest");
+    }
+
+    #[tokio::test]
+    async fn process_blocks_tracks_p2tr_outputs_by_their_output_key() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let p2tr_coinbase = coinbase(1, vec![output(100_000_000, p2tr_script(0x11)), output(200_000_000, p2tr_script(0x22))]);
+        let spent = OutPoint::new(p2tr_coinbase.txid(), 0);
+        let blocks = blocks_of(
+            vec![p2tr_coinbase],
+            vec![coinbase(2, Vec::new()), spend(&[spent], vec![output(100_000_000, Script::new())])],
+        );
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        assert_eq!(totals(&sqlite, BtcAddressType::P2TR, 1).await, (2, 300_000_000.0));
+        assert_eq!(totals(&sqlite, BtcAddressType::P2TR, 2).await, (1, 200_000_000.0));
+        assert_eq!(totals(&sqlite, BtcAddressType::P2PK, 2).await, (0, 0.0));
+
+        let p2tr = BtcAddressType::P2TR.as_str().to_string();
+        let unspent = sqlite.get_unspent_outputs(p2tr.clone(), 0, 10, 0).await.unwrap();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!((unspent[0].value, unspent[0].pubkey.clone()), (200_000_000, Some("22".repeat(32))));
+        let spends = sqlite.get_spends(p2tr, 0, 10, 0).await.unwrap();
+        assert_eq!((spends.len(), spends[0].value, spends[0].vout), (1, 100_000_000, 0));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_tracks_multisig_outputs_and_their_exposed_keys() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let compressed = p2pk_script().as_bytes()[1..34].to_vec();
+        let uncompressed = Vec::from_hex(test_fixtures::UNCOMPRESSED_GENERATOR).unwrap();
+        // Not a point on the curve, as in outputs that embed data in their keys
+        let not_a_key = [0x05; 33].to_vec();
+        let multisig_coinbase = coinbase(
+            1,
+            vec![
+                output(1_000, multisig_script(1, &[&compressed, &not_a_key])),
+                output(2_000, multisig_script(2, &[&compressed, &uncompressed, &compressed])),
+                // More required signatures than keys, so not a multisig script
+                output(4_000, multisig_script(3, &[&compressed, &compressed])),
+            ],
+        );
+        let spent = OutPoint::new(multisig_coinbase.txid(), 1);
+        let blocks = blocks_of(
+            vec![multisig_coinbase],
+            vec![coinbase(2, Vec::new()), spend(&[spent], vec![output(2_000, Script::new())])],
+        );
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        assert_eq!(totals(&sqlite, BtcAddressType::P2MS, 1).await, (2, 3_000.0));
+        assert_eq!(totals(&sqlite, BtcAddressType::P2MS, 2).await, (1, 1_000.0));
+
+        let p2ms = BtcAddressType::P2MS.as_str().to_string();
+        let unspent = sqlite.get_unspent_outputs(p2ms.clone(), 0, 10, 0).await.unwrap();
+        assert_eq!(unspent.len(), 1);
+        let multisig = &unspent[0];
+        assert_eq!((multisig.required_sigs, multisig.key_count, multisig.exposed_keys), (Some(1), Some(2), Some(1)));
+        assert_eq!(multisig.pubkey, Some(format!("{},{}", compressed.to_hex(), not_a_key.to_hex())));
+        let spends = sqlite.get_spends(p2ms, 0, 10, 0).await.unwrap();
+        assert_eq!((spends.len(), spends[0].value, spends[0].vout), (1, 2_000, 1));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_splits_p2pk_totals_by_key_encoding() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let p2pk_coinbase =
+            coinbase(1, vec![output(5_000_000_000, p2pk_script()), output(1_000_000_000, uncompressed_p2pk_script())]);
+        let spent = OutPoint::new(p2pk_coinbase.txid(), 1);
+        let blocks = blocks_of(
+            vec![p2pk_coinbase],
+            vec![coinbase(2, Vec::new()), spend(&[spent], vec![output(900_000_000, p2pk_script())])],
+        );
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        let split = |block: BlockAggregateOutput| {
+            (block.compressed_utxos, block.compressed_sats, block.uncompressed_utxos, block.uncompressed_sats)
+        };
+        assert_eq!(
+            split(aggregates(&sqlite, BtcAddressType::P2PK, 1).await),
+            (Some(1), Some(5_000_000_000.0), Some(1), Some(1_000_000_000.0))
+        );
+        assert_eq!(
+            split(aggregates(&sqlite, BtcAddressType::P2PK, 2).await),
+            (Some(2), Some(5_900_000_000.0), Some(0), Some(0.0))
+        );
+        // Only P2PK totals are split by key encoding
+        assert_eq!(split(aggregates(&sqlite, BtcAddressType::P2TR, 2).await), (None, None, None, None));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_splits_totals_by_coinbase_origin() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        process_blocks_1_and_2(&sqlite, chain_with_spend()).await;
+
+        let split = |block: BlockAggregateOutput| {
+            (block.coinbase_utxos, block.coinbase_sats, block.non_coinbase_utxos, block.non_coinbase_sats)
+        };
+        assert_eq!(
+            split(aggregates(&sqlite, BtcAddressType::P2PK, 1).await),
+            (Some(1), Some(5_000_000_000.0), Some(0), Some(0.0))
+        );
+        // Block 2 spends the coinbase output of block 1 to a non coinbase output
+        assert_eq!(
+            split(aggregates(&sqlite, BtcAddressType::P2PK, 2).await),
+            (Some(1), Some(5_000_000_000.0), Some(1), Some(4_900_000_000.0))
+        );
+        assert_eq!(split(aggregates(&sqlite, BtcAddressType::ReusedPkh, 2).await), (None, None, None, None));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_buckets_unspent_outputs_by_vintage() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let block_1_coinbase =
+            coinbase(1, vec![output(3_000_000_000, p2pk_script()), output(2_000_000_000, p2pk_script())]);
+        let spent = OutPoint::new(block_1_coinbase.txid(), 1);
+        let mut blocks = blocks_of(
+            vec![block_1_coinbase],
+            vec![
+                coinbase(2, vec![output(5_000_000_000, p2pk_script())]),
+                spend(&[spent], vec![output(1_900_000_000, p2pk_script())]),
+            ],
+        );
+        // 2010-01-02, so that block 2 starts a new vintage
+        blocks[2].header.time = 1262390400;
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        let vintages = sqlite.get_latest_block_vintages(BtcAddressType::P2PK, None, Some(1)).await.unwrap();
+        let vintages = vintages
+            .iter()
+            .map(|block| {
+                let vintages = block.vintages.iter().map(|v| (v.vintage.as_str(), v.total_utxos, v.total_sats));
+                (block.block_height, vintages.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vintages,
+            vec![
+                (1, vec![("2009", 2, 5_000_000_000.0)]),
+                (2, vec![("2009", 1, 3_000_000_000.0), ("2010", 2, 6_900_000_000.0)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn process_blocks_records_coin_days_destroyed() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let mut blocks = chain_with_spend();
+        // Block 2 spends the 50 BTC coinbase output of block 1 ten days after it was mined
+        blocks[2].header.time = blocks[1].header.time + 10 * 86_400;
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        assert_eq!(aggregates(&sqlite, BtcAddressType::P2PK, 1).await.coin_days_destroyed, Some(0.0));
+        assert_eq!(aggregates(&sqlite, BtcAddressType::P2PK, 2).await.coin_days_destroyed, Some(500.0));
+    }
+
+    #[tokio::test]
+    async fn process_blocks_counts_outputs_at_hashes_once_their_public_key_is_revealed() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let revealed = PublicKey::from_str(test_fixtures::COMPRESSED_GENERATOR).unwrap();
+        let hidden = PublicKey::from_str(test_fixtures::UNCOMPRESSED_GENERATOR).unwrap();
+        let block_1_coinbase = coinbase(
+            1,
+            vec![
+                output(1_000_000_000, p2pkh_script(&revealed)),
+                output(2_000_000_000, p2pkh_script(&revealed)),
+                output(500_000_000, p2wpkh_script(&revealed)),
+                output(300_000_000, p2pkh_script(&hidden)),
+            ],
+        );
+        // Reveals the public key of `revealed` in its scriptSig
+        let mut reveal =
+            spend(&[OutPoint::new(block_1_coinbase.txid(), 0)], vec![output(1_000_000_000, Script::new())]);
+        reveal.input[0].script_sig = Builder::new().push_slice(&[0x30; 71]).push_key(&revealed).into_script();
+        // Carries no public key, so reveals nothing
+        let no_pubkey = spend(&[OutPoint::new(block_1_coinbase.txid(), 3)], vec![output(300_000_000, Script::new())]);
+        let blocks = blocks_of(
+            vec![block_1_coinbase],
+            vec![coinbase(2, vec![output(100_000_000, p2pkh_script(&revealed))]), reveal, no_pubkey],
+        );
+        process_blocks_1_and_2(&sqlite, blocks).await;
+
+        // Outputs only count once the public key of their address is revealed
+        assert_eq!(totals(&sqlite, BtcAddressType::ReusedPkh, 1).await, (0, 0.0));
+        // Including the outputs created before the reveal, and those created by the revealing block
+        assert_eq!(totals(&sqlite, BtcAddressType::ReusedPkh, 2).await, (3, 2_600_000_000.0));
+        let reused_pkh = BtcAddressType::ReusedPkh.as_str().to_string();
+        let unspent = sqlite.get_unspent_outputs(reused_pkh.clone(), 0, 10, 0).await.unwrap();
+        assert!(unspent.iter().all(|utxo| utxo.pubkey == Some(revealed.to_string())));
+        assert!(sqlite.is_pubkey_revealed(reused_pkh.clone(), &revealed.pubkey_hash()[..]).await.unwrap());
+
+        // The revealing spend isn't of interest, but the spend without a public key is still recorded
+        let spends = sqlite.get_spends(reused_pkh, 0, 10, 0).await.unwrap();
+        assert_eq!((spends.len(), spends[0].value, spends[0].vout), (1, 300_000_000, 3));
+    }
+}
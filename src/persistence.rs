@@ -1,12 +1,76 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::str::FromStr;
 
 use log::{info, debug};
+use nakamoto::common::bitcoin::hashes::hex::{FromHex, ToHex};
+use nakamoto::common::bitcoin::{OutPoint, Txid};
 use sqlx::migrate::MigrateDatabase;
-use sqlx::{Pool, Row, Sqlite};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Executor, Pool, Row, Sqlite, SqliteConnection};
 
-use crate::util::{BlockAggregateOutput, BtcAddressType};
+use crate::alerts::{Alert, Severity};
+use crate::util::{
+    BlockAggregateOutput, BlockValueBandsOutput, BlockVintagesOutput, BtcAddressType, Cohort, SpendOutput,
+    UtxoOutput, UtxoSubset, ValueBand, ValueBandOutput, VintageOutput,
+};
+use crate::utxo_store::{BlockCommit, BlockDelta, MigratedOutput, UnspentOutput, MAX_REORG_DEPTH};
+use crate::webhooks::{OutboxMessage, QueuedWebhook};
 
-#[derive(Debug)]
+/// Columns of the block aggregate tables, in the order read by `block_aggregate_from_row`.
+const BLOCK_AGGREGATE_COLUMNS: &str = "date, block_height, block_hash_big_endian, total_utxos, total_sats,
+    compressed_utxos, compressed_sats, uncompressed_utxos, uncompressed_sats,
+    coinbase_utxos, coinbase_sats, non_coinbase_utxos, non_coinbase_sats, cohort_utxos, cohort_sats,
+    coin_days_destroyed";
+
+fn block_aggregate_from_row(row: &SqliteRow) -> BlockAggregateOutput {
+    BlockAggregateOutput {
+        date: row.get(0),
+        block_height: row.get::<i64, _>(1) as usize,
+        block_hash_big_endian: row.get(2),
+        total_utxos: row.get::<i64, _>(3) as u32,
+        total_sats: row.get(4),
+        compressed_utxos: row.get::<Option<i64>, _>(5).map(|utxos| utxos as u32),
+        compressed_sats: row.get(6),
+        uncompressed_utxos: row.get::<Option<i64>, _>(7).map(|utxos| utxos as u32),
+        uncompressed_sats: row.get(8),
+        coinbase_utxos: row.get::<Option<i64>, _>(9).map(|utxos| utxos as u32),
+        coinbase_sats: row.get(10),
+        non_coinbase_utxos: row.get::<Option<i64>, _>(11).map(|utxos| utxos as u32),
+        non_coinbase_sats: row.get(12),
+        cohort_utxos: row.get::<Option<i64>, _>(13).map(|utxos| utxos as u32),
+        cohort_sats: row.get(14),
+        coin_days_destroyed: row.get(15),
+    }
+}
+
+/// Columns of the spends tables, in the order read by `spend_from_row`.
+const SPEND_COLUMNS: &str = "spending_txid, input_index, block_height, block_time, txid, vout, value,
+    created_height, age_blocks, age_days, destination_script_types, high_priority = 1";
+
+fn spend_from_row(row: &SqliteRow) -> SpendOutput {
+    SpendOutput {
+        spending_txid: row.get(0),
+        input_index: row.get::<i64, _>(1) as u32,
+        block_height: row.get::<i64, _>(2) as usize,
+        block_time: row.get(3),
+        txid: row.get(4),
+        vout: row.get::<i64, _>(5) as u32,
+        value: row.get(6),
+        created_height: row.get(7),
+        age_blocks: row.get(8),
+        age_days: row.get(9),
+        destination_script_types: row
+            .get::<String, _>(10)
+            .split(',')
+            .filter(|script_type| !script_type.is_empty())
+            .map(String::from)
+            .collect(),
+        high_priority: row.get::<Option<bool>, _>(11).unwrap_or(false),
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct SQLitePersistence {
     pool: Pool<Sqlite>,
 }
@@ -40,20 +104,280 @@ impl SQLitePersistence {
         .execute(pool)
         .await?;
 
+        // Split of the P2PK totals by public key encoding, null for other address types
+        Self::add_column_if_missing(pool, &table_name, "compressed_utxos", "integer").await?;
+        Self::add_column_if_missing(pool, &table_name, "compressed_sats", "real").await?;
+        Self::add_column_if_missing(pool, &table_name, "uncompressed_utxos", "integer").await?;
+        Self::add_column_if_missing(pool, &table_name, "uncompressed_sats", "real").await?;
+        // Split of the totals by coinbase origin, null for reused_pkh
+        Self::add_column_if_missing(pool, &table_name, "coinbase_utxos", "integer").await?;
+        Self::add_column_if_missing(pool, &table_name, "coinbase_sats", "real").await?;
+        Self::add_column_if_missing(pool, &table_name, "non_coinbase_utxos", "integer").await?;
+        Self::add_column_if_missing(pool, &table_name, "non_coinbase_sats", "real").await?;
+        // Totals of the cohort loaded from COHORT_FILE, null when none is loaded
+        Self::add_column_if_missing(pool, &table_name, "cohort_utxos", "integer").await?;
+        Self::add_column_if_missing(pool, &table_name, "cohort_sats", "real").await?;
+        // Null for blocks scanned before it was recorded
+        Self::add_column_if_missing(pool, &table_name, "coin_days_destroyed", "real").await?;
+
+        // Tracked outpoints.  Looked up while scanning blocks when UTXO_STORE=sqlite.
+        // Spent outpoints are kept for MAX_REORG_DEPTH blocks so that reorgs can restore them.
+        // Only txid, vout and value are known for outpoints copied from sled; block_height is
+        // also known for those created within the last MAX_REORG_DEPTH blocks before the copy.
+        sqlx::query(&format!(
+            "create table if not exists {}_utxos (
+                txid text not null,
+                vout integer not null,
+                value integer not null,
+                block_height integer,
+                script_pubkey blob,
+                spent_height integer,
+                block_time integer,
+                is_coinbase integer,
+                pubkey text,
+                primary key (txid, vout)
+            )",
+            btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        // Every spend of a tracked outpoint.
+        // created_height, age_blocks and age_days are null for outpoints copied from sled without their history.
+        sqlx::query(&format!(
+            "create table if not exists {}_spends (
+                spending_txid text not null,
+                input_index integer not null,
+                block_height integer not null,
+                block_time integer not null,
+                txid text not null,
+                vout integer not null,
+                value integer not null,
+                created_height integer,
+                age_blocks integer,
+                age_days real,
+                destination_script_types text not null,
+                primary key (spending_txid, input_index)
+            )",
+            btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_spends_block_height ON {}_spends(block_height DESC)",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        // Alerts raised by the rules evaluated against each block
+        sqlx::query(&format!(
+            "create table if not exists {}_alerts (
+                id integer primary key autoincrement,
+                block_height integer not null,
+                block_time integer not null,
+                rule text not null,
+                severity text not null,
+                message text not null,
+                value_sats integer not null default 0
+            )",
+            btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_alerts_block_height ON {}_alerts(block_height DESC)",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        // Columns added after the table was first released
+        let utxos_table_name = format!("{}_utxos", btc_address_type);
+        Self::add_column_if_missing(pool, &utxos_table_name, "block_time", "integer").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "is_coinbase", "integer").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "pubkey", "text").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "pubkey_hash", "text").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "required_sigs", "integer").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "key_count", "integer").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "exposed_keys", "integer").await?;
+        Self::add_column_if_missing(pool, &utxos_table_name, "in_cohort", "integer").await?;
+        let spends_table_name = format!("{}_spends", btc_address_type);
+        Self::add_column_if_missing(pool, &spends_table_name, "high_priority", "integer").await?;
+        let alerts_table_name = format!("{}_alerts", btc_address_type);
+        Self::add_column_if_missing(pool, &alerts_table_name, "value_sats", "integer not null default 0").await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_utxos_block_height ON {}_utxos(block_height)",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_utxos_spent_height ON {}_utxos(spent_height)",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_utxos_pubkey_hash ON {}_utxos(pubkey_hash) WHERE pubkey_hash IS NOT NULL",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{}_utxos_in_cohort ON {}_utxos(in_cohort) WHERE in_cohort = 1",
+            btc_address_type, btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        // Per-block snapshots of the unspent outpoints bucketed by value.  Every band is stored, empty or not.
+        sqlx::query(&format!(
+            "create table if not exists {}_value_band_block_aggregates (
+                block_height integer not null,
+                value_band text not null,
+                min_value integer not null,
+                total_utxos integer not null,
+                total_sats real not null,
+                primary key (block_height, value_band)
+            )",
+            btc_address_type
+        ))
+        .execute(pool)
+        .await?;
+
+        if Self::tracks_vintages(&btc_address_type) {
+            // Per-block snapshots of the unspent outpoints bucketed by vintage.  Empty vintages aren't stored.
+            sqlx::query(&format!(
+                "create table if not exists {}_vintage_block_aggregates (
+                    block_height integer not null,
+                    vintage text not null,
+                    vintage_start integer not null,
+                    total_utxos integer not null,
+                    total_sats real not null,
+                    primary key (block_height, vintage)
+                )",
+                btc_address_type
+            ))
+            .execute(pool)
+            .await?;
+        }
+
+        if btc_address_type == BtcAddressType::ReusedPkh.as_str() {
+            // Public keys revealed by spends from P2PKH and P2WPKH addresses, keyed by their hash160.
+            // Outpoints at those addresses have the public key recorded in their pubkey column.
+            sqlx::query(&format!(
+                "create table if not exists {}_revealed_pubkeys (
+                    pubkey_hash text primary key,
+                    pubkey text not null,
+                    block_height integer not null
+                )",
+                btc_address_type
+            ))
+            .execute(pool)
+            .await?;
+
+            sqlx::query(&format!(
+                "CREATE INDEX IF NOT EXISTS idx_{}_revealed_pubkeys_block_height ON {}_revealed_pubkeys(block_height)",
+                btc_address_type, btc_address_type
+            ))
+            .execute(pool)
+            .await?;
+        }
+
         Ok(())
     }
 
+    fn tracks_vintages(btc_address_type: &str) -> bool {
+        btc_address_type
+            .parse::<BtcAddressType>()
+            .is_ok_and(|address_type| address_type.tracks_vintages())
+    }
+
+    /// Adds a column to an existing table unless the table already has it
+    async fn add_column_if_missing(
+        pool: &Pool<Sqlite>,
+        table_name: &str,
+        column_name: &str,
+        column_type: &str,
+    ) -> anyhow::Result<()> {
+        let columns = sqlx::query(&format!("PRAGMA table_info({})", table_name))
+            .fetch_all(pool)
+            .await?;
+        if columns.iter().any(|column| column.get::<String, _>("name") == column_name) {
+            return Ok(());
+        }
+
+        info!("Adding column {} to table {}", column_name, table_name);
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table_name, column_name, column_type
+        ))
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn insert_block_aggregates<'e, E>(
+        executor: E,
+        btc_address_type: &str,
+        block_aggregate: &BlockAggregateOutput,
+    ) -> anyhow::Result<u64>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
+        let result = sqlx::query(&format!(
+            "INSERT INTO {} (block_height, block_hash_big_endian, date, total_utxos, total_sats,
+            compressed_utxos, compressed_sats, uncompressed_utxos, uncompressed_sats,
+            coinbase_utxos, coinbase_sats, non_coinbase_utxos, non_coinbase_sats, cohort_utxos, cohort_sats,
+            coin_days_destroyed) VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16)",
+            table_name
+        ))
+            .bind(block_aggregate.block_height as i64)
+            .bind(&block_aggregate.block_hash_big_endian)
+            .bind(&block_aggregate.date)
+            .bind(block_aggregate.total_utxos as i64)
+            .bind(block_aggregate.total_sats)
+            .bind(block_aggregate.compressed_utxos.map(|utxos| utxos as i64))
+            .bind(block_aggregate.compressed_sats)
+            .bind(block_aggregate.uncompressed_utxos.map(|utxos| utxos as i64))
+            .bind(block_aggregate.uncompressed_sats)
+            .bind(block_aggregate.coinbase_utxos.map(|utxos| utxos as i64))
+            .bind(block_aggregate.coinbase_sats)
+            .bind(block_aggregate.non_coinbase_utxos.map(|utxos| utxos as i64))
+            .bind(block_aggregate.non_coinbase_sats)
+            .bind(block_aggregate.cohort_utxos.map(|utxos| utxos as i64))
+            .bind(block_aggregate.cohort_sats)
+            .bind(block_aggregate.coin_days_destroyed)
+            .execute(executor)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
     pub async fn new(pool_max_size: u32) -> anyhow::Result<Self> {
         let sqlite_absolute_path = env::var("SQLITE_ABSOLUTE_PATH")
             .unwrap_or_else(|_| String::from("/tmp/gabriel/gabriel_p2pk.db"));
+        Self::open(&sqlite_absolute_path, pool_max_size).await
+    }
 
+    /* Opens the database at the given path, creating it and its schema if needed. */
+    pub async fn open(sqlite_absolute_path: &str, pool_max_size: u32) -> anyhow::Result<Self> {
         // Create parent directories if they don't exist
-        if let Some(parent) = std::path::Path::new(&sqlite_absolute_path).parent() {
+        if let Some(parent) = std::path::Path::new(sqlite_absolute_path).parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        if !sqlx::Sqlite::database_exists(&sqlite_absolute_path).await? {
-            sqlx::Sqlite::create_database(&sqlite_absolute_path).await?;
+        if !sqlx::Sqlite::database_exists(sqlite_absolute_path).await? {
+            sqlx::Sqlite::create_database(sqlite_absolute_path).await?;
         }
 
         let pool = sqlx::sqlite::SqlitePoolOptions::new()
@@ -66,31 +390,892 @@ impl SQLitePersistence {
             sqlite_absolute_path, pool_max_size
         );
 
-        // Initialize schema for p2pk addresses
-        Self::initialize_schema(&pool, BtcAddressType::P2PK.as_str().to_string()).await?;
+        // Initialize schema for every tracked address type
+        for address_type in BtcAddressType::TRACKED {
+            Self::initialize_schema(&pool, address_type.as_str().to_string()).await?;
+        }
+
+        // Events waiting to be delivered to webhooks, and alert emails with their recipients in a mailto: URL.
+        // Rows are deleted once delivered; deliveries that were given up on are kept with their last error.
+        sqlx::query(
+            "create table if not exists webhook_outbox (
+                id integer primary key autoincrement,
+                url text not null,
+                event text not null,
+                body text not null,
+                attempts integer not null default 0,
+                next_attempt_at integer not null,
+                last_error text
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Each destination reads its own deliveries
+        sqlx::query("DROP INDEX IF EXISTS idx_webhook_outbox_next_attempt_at")
+            .execute(&pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_webhook_outbox_url_next_attempt_at ON webhook_outbox(url, next_attempt_at)")
+            .execute(&pool)
+            .await?;
 
         Ok(SQLitePersistence { pool })
     }
 
-    pub async fn persist_block_aggregates(
+    /* Returns a tracked outpoint if it is unspent. */
+    pub async fn get_unspent_output(
         &self,
         btc_address_type: String,
-        block_aggregate: &BlockAggregateOutput,
-    ) -> anyhow::Result<u64> {
-        let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
+        outpoint: &OutPoint,
+    ) -> anyhow::Result<Option<UnspentOutput>> {
         let result = sqlx::query(&format!(
-            "INSERT INTO {} VALUES(?1,?2,?3,?4,?5)",
-            table_name
+            "SELECT value, block_height, block_time, pubkey, is_coinbase, in_cohort = 1 FROM {}_utxos
+            WHERE txid = ? AND vout = ? AND spent_height IS NULL",
+            btc_address_type
         ))
-            .bind(block_aggregate.block_height as i64)
-            .bind(&block_aggregate.block_hash_big_endian)
-            .bind(&block_aggregate.date)
-            .bind(block_aggregate.total_utxos as i64)
-            .bind(block_aggregate.total_sats)
+        .bind(outpoint.txid.to_string())
+        .bind(outpoint.vout as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(result.map(|row| UnspentOutput {
+            value: row.get(0),
+            block_height: row.get::<Option<i64>, _>(1).map(|height| height as u64),
+            block_time: row.get::<Option<i64>, _>(2).map(|time| time as u32),
+            // P2MS outpoints record several comma separated keys, which aren't returned
+            pubkey: row
+                .get::<Option<String>, _>(3)
+                .and_then(|pubkey| Vec::<u8>::from_hex(&pubkey).ok()),
+            is_coinbase: row.get(4),
+            in_cohort: row.get::<Option<bool>, _>(5).unwrap_or(false),
+        }))
+    }
+
+    /* Applies the outpoint changes of a block and persists its aggregates, for every address type
+     * tracked in the block, in a single transaction along with the outbox messages of its events.
+     */
+    pub async fn commit_block(&self, commits: &[BlockCommit], outbox: &[OutboxMessage]) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for commit in commits {
+            let btc_address_type = commit.address_type.as_str();
+            let delta = &commit.delta;
+            let height = commit.aggregate.block_height as i64;
+
+            // Outpoints at an address whose public key the block reveals are exposed from now on
+            for (pubkey_hash, pubkey) in delta.revealed.iter() {
+                sqlx::query(&format!(
+                    "INSERT OR IGNORE INTO {}_revealed_pubkeys (pubkey_hash, pubkey, block_height) VALUES(?1,?2,?3)",
+                    btc_address_type
+                ))
+                .bind(pubkey_hash.to_hex())
+                .bind(pubkey.to_hex())
+                .bind(height)
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query(&format!(
+                    "UPDATE {}_utxos SET pubkey = ?1 WHERE pubkey_hash = ?2 AND pubkey IS NULL",
+                    btc_address_type
+                ))
+                .bind(pubkey.to_hex())
+                .bind(pubkey_hash.to_hex())
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            for (outpoint, output) in delta.created.iter() {
+                // Outputs sent to an address whose public key was already revealed get it right away
+                let pubkey = match output.pubkey_hash {
+                    Some(_) => format!(
+                        "COALESCE(?8, (SELECT pubkey FROM {}_revealed_pubkeys WHERE pubkey_hash = ?9))",
+                        btc_address_type
+                    ),
+                    None => "?8".to_string(),
+                };
+                // P2MS outputs record all of their keys
+                let pubkey_hex = match &output.multisig {
+                    Some(multisig) => Some(
+                        multisig.pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect::<Vec<_>>().join(","),
+                    ),
+                    None => output.pubkey.as_ref().map(|pubkey| pubkey.to_hex()),
+                };
+                // Duplicate coinbase txids (blocks 91842 and 91880) replace the earlier outpoint
+                sqlx::query(&format!(
+                    "INSERT OR REPLACE INTO {}_utxos (txid, vout, value, block_height, script_pubkey, block_time, is_coinbase,
+                    pubkey, pubkey_hash, required_sigs, key_count, exposed_keys, in_cohort)
+                    VALUES(?1,?2,?3,?4,?5,?6,?7,{},?9,?10,?11,?12,?13)",
+                    btc_address_type, pubkey
+                ))
+                .bind(outpoint.txid.to_string())
+                .bind(outpoint.vout as i64)
+                .bind(output.value)
+                .bind(height)
+                .bind(&output.script_pubkey)
+                .bind(delta.block_time as i64)
+                .bind(output.is_coinbase)
+                .bind(pubkey_hex)
+                .bind(output.pubkey_hash.as_ref().map(|pubkey_hash| pubkey_hash.to_hex()))
+                .bind(output.multisig.as_ref().map(|multisig| multisig.required_sigs as i64))
+                .bind(output.multisig.as_ref().map(|multisig| multisig.pubkeys.len() as i64))
+                .bind(output.multisig.as_ref().map(|multisig| multisig.exposed_keys() as i64))
+                .bind(output.in_cohort)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            for (outpoint, _) in delta.spent.iter() {
+                sqlx::query(&format!(
+                    "UPDATE {}_utxos SET spent_height = ?1 WHERE txid = ?2 AND vout = ?3",
+                    btc_address_type
+                ))
+                .bind(height)
+                .bind(outpoint.txid.to_string())
+                .bind(outpoint.vout as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            for spend in delta.spend_events.iter() {
+                sqlx::query(&format!(
+                    "INSERT INTO {}_spends (spending_txid, input_index, block_height, block_time, txid, vout, value,
+                    created_height, age_blocks, age_days, destination_script_types, high_priority)
+                    VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12)",
+                    btc_address_type
+                ))
+                .bind(&spend.spending_txid)
+                .bind(spend.input_index as i64)
+                .bind(spend.block_height as i64)
+                .bind(spend.block_time)
+                .bind(&spend.txid)
+                .bind(spend.vout as i64)
+                .bind(spend.value)
+                .bind(spend.created_height)
+                .bind(spend.age_blocks)
+                .bind(spend.age_days)
+                .bind(spend.destination_script_types.join(","))
+                .bind(spend.high_priority)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            // Spent outpoints are only needed until they are too deep to be reorged
+            sqlx::query(&format!(
+                "DELETE FROM {}_utxos WHERE spent_height <= ?",
+                btc_address_type
+            ))
+            .bind(height - MAX_REORG_DEPTH as i64)
+            .execute(&mut *tx)
+            .await?;
+
+            Self::insert_block_aggregates(&mut *tx, btc_address_type, &commit.aggregate).await?;
+
+            for alert in commit.alerts.iter() {
+                sqlx::query(&format!(
+                    "INSERT INTO {}_alerts (block_height, block_time, rule, severity, message, value_sats) VALUES(?1,?2,?3,?4,?5,?6)",
+                    btc_address_type
+                ))
+                .bind(alert.block_height as i64)
+                .bind(alert.block_time)
+                .bind(&alert.rule)
+                .bind(alert.severity.as_str())
+                .bind(&alert.message)
+                .bind(alert.value_sats)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            for (vintage, (utxos, sats)) in commit.vintages.iter() {
+                sqlx::query(&format!(
+                    "INSERT INTO {}_vintage_block_aggregates (block_height, vintage, vintage_start, total_utxos, total_sats)
+                    VALUES(?1,?2,?3,?4,?5)",
+                    btc_address_type
+                ))
+                .bind(height)
+                .bind(&vintage.label)
+                .bind(vintage.start as i64)
+                .bind(utxos)
+                .bind(*sats as f64)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            for (value_band, (utxos, sats)) in commit.value_bands.iter() {
+                sqlx::query(&format!(
+                    "INSERT INTO {}_value_band_block_aggregates (block_height, value_band, min_value, total_utxos, total_sats)
+                    VALUES(?1,?2,?3,?4,?5)",
+                    btc_address_type
+                ))
+                .bind(height)
+                .bind(value_band.as_str())
+                .bind(value_band.min_value())
+                .bind(utxos)
+                .bind(*sats as f64)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        Self::insert_outbox_messages(&mut tx, outbox).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /* Reverts the outpoint changes and deletes the aggregates of all blocks above the given height,
+     * in a single transaction.
+     */
+    pub async fn rollback_blocks_above(&self, btc_address_type: String, height: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::rollback_address_type(&mut tx, &btc_address_type, height).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /* Reverts the blocks above the given height for every tracked address type, in a single transaction
+     * along with the outbox messages announcing the reorg.
+     */
+    pub async fn rollback_reorg(&self, height: i64, outbox: &[OutboxMessage]) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for address_type in BtcAddressType::TRACKED {
+            Self::rollback_address_type(&mut tx, address_type.as_str(), height).await?;
+        }
+        Self::insert_outbox_messages(&mut tx, outbox).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rollback_address_type(
+        tx: &mut SqliteConnection,
+        btc_address_type: &str,
+        height: i64,
+    ) -> anyhow::Result<()> {
+
+        if btc_address_type == BtcAddressType::ReusedPkh.as_str() {
+            // Public keys revealed by the rolled back blocks are hidden again
+            sqlx::query(&format!(
+                "UPDATE {}_utxos SET pubkey = NULL
+                WHERE pubkey_hash IN (SELECT pubkey_hash FROM {}_revealed_pubkeys WHERE block_height > ?)",
+                btc_address_type, btc_address_type
+            ))
+            .bind(height)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query(&format!(
+                "DELETE FROM {}_revealed_pubkeys WHERE block_height > ?",
+                btc_address_type
+            ))
+            .bind(height)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query(&format!(
+            "DELETE FROM {}_utxos WHERE block_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "UPDATE {}_utxos SET spent_height = NULL WHERE spent_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "DELETE FROM {}_spends WHERE block_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "DELETE FROM {}_utxo_block_aggregates WHERE block_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "DELETE FROM {}_value_band_block_aggregates WHERE block_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            "DELETE FROM {}_alerts WHERE block_height > ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+
+        if Self::tracks_vintages(btc_address_type) {
+            sqlx::query(&format!(
+                "DELETE FROM {}_vintage_block_aggregates WHERE block_height > ?",
+                btc_address_type
+            ))
+            .bind(height)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /* Returns true if a spend from the address with the given pubkey hash has revealed its public key. */
+    pub async fn is_pubkey_revealed(&self, btc_address_type: String, pubkey_hash: &[u8]) -> anyhow::Result<bool> {
+        let result = sqlx::query(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {}_revealed_pubkeys WHERE pubkey_hash = ?)",
+            btc_address_type
+        ))
+        .bind(pubkey_hash.to_hex())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(result.get::<bool, _>(0))
+    }
+
+    /* Returns the change a block makes to the number of unspent outpoints at addresses whose public key
+     * has been revealed, and to the satoshis they hold, by value band.  Must be called before the block is committed.
+     */
+    pub async fn get_exposure_change(
+        &self,
+        btc_address_type: String,
+        delta: &BlockDelta,
+    ) -> anyhow::Result<HashMap<ValueBand, (i64, i64)>> {
+        let mut changes: HashMap<ValueBand, (i64, i64)> = HashMap::new();
+
+        // Outpoints sent to an address before the block revealed its public key
+        let mut newly_revealed = HashSet::new();
+        for pubkey_hash in delta.revealed.keys() {
+            if self.is_pubkey_revealed(btc_address_type.clone(), pubkey_hash).await? {
+                continue;
+            }
+            let rows = sqlx::query(&format!(
+                "SELECT value FROM {}_utxos WHERE pubkey_hash = ? AND spent_height IS NULL",
+                btc_address_type
+            ))
+            .bind(pubkey_hash.to_hex())
+            .fetch_all(&self.pool)
+            .await?;
+            for row in rows.iter() {
+                let value = row.get::<i64, _>(0);
+                let change = changes.entry(ValueBand::of(value)).or_default();
+                change.0 += 1;
+                change.1 += value;
+            }
+            newly_revealed.insert(pubkey_hash.to_hex());
+        }
+
+        for output in delta.created.values() {
+            let pubkey_hash = match &output.pubkey_hash {
+                Some(pubkey_hash) => pubkey_hash,
+                None => continue,
+            };
+            if newly_revealed.contains(&pubkey_hash.to_hex())
+                || self.is_pubkey_revealed(btc_address_type.clone(), pubkey_hash).await?
+            {
+                let change = changes.entry(ValueBand::of(output.value)).or_default();
+                change.0 += 1;
+                change.1 += output.value;
+            }
+        }
+
+        // Spent outpoints were counted if their public key was known before the block or revealed by it
+        for (outpoint, output) in delta.spent.iter() {
+            let row = sqlx::query(&format!(
+                "SELECT pubkey IS NOT NULL, pubkey_hash FROM {}_utxos WHERE txid = ? AND vout = ?",
+                btc_address_type
+            ))
+            .bind(outpoint.txid.to_string())
+            .bind(outpoint.vout as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+            let exposed = row.is_some_and(|row| {
+                row.get::<bool, _>(0)
+                    || row
+                        .get::<Option<String>, _>(1)
+                        .is_some_and(|pubkey_hash| newly_revealed.contains(&pubkey_hash))
+            });
+            if exposed {
+                let change = changes.entry(ValueBand::of(output.value)).or_default();
+                change.0 -= 1;
+                change.1 -= output.value;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /* Returns the number of unspent outpoints in the given subset, and the satoshis they hold. */
+    pub async fn get_unspent_subset_totals(
+        &self,
+        btc_address_type: String,
+        subset: UtxoSubset,
+    ) -> anyhow::Result<(i64, i64)> {
+        // Public keys are stored hex encoded
+        let condition = match subset {
+            UtxoSubset::Compressed => "length(pubkey) = 66",
+            UtxoSubset::Uncompressed => "length(pubkey) = 130",
+            UtxoSubset::Coinbase => "is_coinbase = 1",
+            UtxoSubset::NonCoinbase => "is_coinbase = 0",
+            UtxoSubset::Cohort => "in_cohort = 1",
+        };
+        let row = sqlx::query(&format!(
+            "SELECT COUNT(*), COALESCE(SUM(value), 0) FROM {}_utxos WHERE spent_height IS NULL AND {}",
+            btc_address_type, condition
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /* Returns the number of unspent outpoints in each value band, and the satoshis they hold.
+     * Only exposed outpoints are counted for P2PKH and P2WPKH.
+     */
+    pub async fn get_unspent_value_band_totals(
+        &self,
+        btc_address_type: String,
+    ) -> anyhow::Result<HashMap<ValueBand, (i64, i64)>> {
+        let exposed_filter = if btc_address_type == BtcAddressType::ReusedPkh.as_str() {
+            "AND pubkey IS NOT NULL"
+        } else {
+            ""
+        };
+        // The index of an outpoint's band is the number of bands above the first whose minimum it reaches
+        let rows = sqlx::query(&format!(
+            "SELECT (value >= ?1) + (value >= ?2) + (value >= ?3) AS band, COUNT(*), SUM(value)
+            FROM {}_utxos WHERE spent_height IS NULL {}
+            GROUP BY band",
+            btc_address_type, exposed_filter
+        ))
+        .bind(ValueBand::OneToTenBtc.min_value())
+        .bind(ValueBand::TenToFiftyBtc.min_value())
+        .bind(ValueBand::FiftyBtcOrMore.min_value())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (ValueBand::ALL[row.get::<i64, _>(0) as usize], (row.get(1), row.get(2))))
+            .collect())
+    }
+
+    /* Returns the number of unspent outpoints in each value band, and the satoshis they hold, as recorded with
+     * the block at the given height.  Empty if the block was scanned before value bands were recorded.
+     */
+    pub async fn get_block_value_band_totals(
+        &self,
+        btc_address_type: String,
+        height: i64,
+    ) -> anyhow::Result<HashMap<ValueBand, (i64, i64)>> {
+        let rows = sqlx::query(&format!(
+            "SELECT min_value, total_utxos, total_sats FROM {}_value_band_block_aggregates WHERE block_height = ?",
+            btc_address_type
+        ))
+        .bind(height)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (ValueBand::of(row.get(0)), (row.get(1), row.get::<f64, _>(2) as i64)))
+            .collect())
+    }
+
+    /* Returns the creation height, creation time and value of every unspent outpoint. */
+    pub async fn get_unspent_origins(
+        &self,
+        btc_address_type: String,
+    ) -> anyhow::Result<Vec<(Option<i64>, Option<i64>, i64)>> {
+        let rows = sqlx::query(&format!(
+            "SELECT block_height, block_time, value FROM {}_utxos WHERE spent_height IS NULL",
+            btc_address_type
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+    }
+
+    /* Tags the outpoints in the cohort as members, and untags those that no longer are.
+     * Returns the number of unspent members.
+     */
+    pub async fn tag_cohort(&self, btc_address_type: String, cohort: &Cohort) -> anyhow::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(&format!(
+            "UPDATE {}_utxos SET in_cohort = 0 WHERE in_cohort = 1",
+            btc_address_type
+        ))
+        .execute(&mut *tx)
+        .await?;
+
+        for height in cohort.heights.iter() {
+            sqlx::query(&format!(
+                "UPDATE {}_utxos SET in_cohort = 1 WHERE block_height = ? AND is_coinbase = 1",
+                btc_address_type
+            ))
+            .bind(*height as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for outpoint in cohort.outpoints.iter() {
+            sqlx::query(&format!(
+                "UPDATE {}_utxos SET in_cohort = 1 WHERE txid = ? AND vout = ?",
+                btc_address_type
+            ))
+            .bind(outpoint.txid.to_string())
+            .bind(outpoint.vout as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let result = sqlx::query(&format!(
+            "SELECT COUNT(*) FROM {}_utxos WHERE in_cohort = 1 AND spent_height IS NULL",
+            btc_address_type
+        ))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.get(0))
+    }
+
+    /* Returns true if no outpoints are tracked in SQLite. */
+    pub async fn utxos_is_empty(&self, btc_address_type: String) -> anyhow::Result<bool> {
+        let result = sqlx::query(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {}_utxos)",
+            btc_address_type
+        ))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(!result.get::<bool, _>(0))
+    }
+
+    /* Inserts outpoints migrated from another store.
+     * Only their value is known, along with the creation and spend heights of recent outpoints.
+     */
+    pub async fn import_outputs(
+        &self,
+        btc_address_type: String,
+        outputs: &[MigratedOutput],
+    ) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut imported = 0;
+
+        for output in outputs {
+            let result = sqlx::query(&format!(
+                "INSERT INTO {}_utxos (txid, vout, value, block_height, spent_height) VALUES(?1,?2,?3,?4,?5)",
+                btc_address_type
+            ))
+            .bind(output.outpoint.txid.to_string())
+            .bind(output.outpoint.vout as i64)
+            .bind(output.value)
+            .bind(output.block_height)
+            .bind(output.spent_height)
+            .execute(&mut *tx)
+            .await?;
+            imported += result.rows_affected();
+        }
+
+        tx.commit().await?;
+        Ok(imported)
+    }
+
+    /* Returns every outpoint record with its value and creation and spend heights, for rebuilding another store.
+     * Spent outpoints are only kept for the last MAX_REORG_DEPTH blocks.
+     */
+    pub async fn export_outputs(&self, btc_address_type: String) -> anyhow::Result<Vec<MigratedOutput>> {
+        let rows = sqlx::query(&format!(
+            "SELECT txid, vout, value, block_height, spent_height FROM {}_utxos",
+            btc_address_type
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(MigratedOutput {
+                    outpoint: OutPoint::new(Txid::from_str(row.get(0))?, row.get::<i64, _>(1) as u32),
+                    value: row.get(2),
+                    block_height: row.get(3),
+                    spent_height: row.get(4),
+                })
+            })
+            .collect()
+    }
+
+    /* Returns unspent outpoints ordered by creation height.
+     * Outpoints copied from sled without a known creation height come first.
+     * - min_value: Only return outpoints holding at least this many satoshis
+     * - limit / offset: Page through the results
+     */
+    pub async fn get_unspent_outputs(
+        &self,
+        btc_address_type: String,
+        min_value: i64,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<UtxoOutput>> {
+        // P2PKH and P2WPKH outpoints are only exposed once their address has revealed its public key
+        let exposed_filter = if btc_address_type == BtcAddressType::ReusedPkh.as_str() {
+            "AND pubkey IS NOT NULL"
+        } else {
+            ""
+        };
+        let rows = sqlx::query(&format!(
+            "SELECT txid, vout, value, block_height, block_time, is_coinbase, pubkey, pubkey_hash,
+                required_sigs, key_count, exposed_keys, in_cohort = 1
+            FROM {}_utxos
+            WHERE spent_height IS NULL AND value >= ?1 {}
+            ORDER BY block_height ASC, txid ASC, vout ASC
+            LIMIT ?2 OFFSET ?3",
+            btc_address_type, exposed_filter
+        ))
+        .bind(min_value)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| UtxoOutput {
+                txid: row.get(0),
+                vout: row.get::<i64, _>(1) as u32,
+                value: row.get(2),
+                block_height: row.get(3),
+                block_time: row.get(4),
+                is_coinbase: row.get(5),
+                pubkey: row.get(6),
+                pubkey_hash: row.get(7),
+                required_sigs: row.get(8),
+                key_count: row.get(9),
+                exposed_keys: row.get(10),
+                in_cohort: row.get::<Option<bool>, _>(11).unwrap_or(false),
+            })
+            .collect())
+    }
+
+    /* Returns spends of tracked outpoints, most recent first.
+     * - min_value: Only return spends of outpoints holding at least this many satoshis
+     * - limit / offset: Page through the results
+     */
+    pub async fn get_spends(
+        &self,
+        btc_address_type: String,
+        min_value: i64,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<SpendOutput>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {}
+            FROM {}_spends
+            WHERE value >= ?1
+            ORDER BY block_height DESC, spending_txid ASC, input_index ASC
+            LIMIT ?2 OFFSET ?3",
+            SPEND_COLUMNS, btc_address_type
+        ))
+        .bind(min_value)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(spend_from_row).collect())
+    }
+
+    /* Returns spends of tracked outpoints in blocks `from_height` to `to_height`, oldest first.
+     * - limit: Most spends returned
+     */
+    pub async fn get_spends_between(
+        &self,
+        btc_address_type: String,
+        from_height: i64,
+        to_height: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SpendOutput>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {}
+            FROM {}_spends
+            WHERE block_height BETWEEN ?1 AND ?2
+            ORDER BY block_height ASC, spending_txid ASC, input_index ASC
+            LIMIT ?3",
+            SPEND_COLUMNS, btc_address_type
+        ))
+        .bind(from_height)
+        .bind(to_height)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(spend_from_row).collect())
+    }
+
+    /* Queues messages in the webhook outbox, to be delivered right away. */
+    async fn insert_outbox_messages(tx: &mut SqliteConnection, messages: &[OutboxMessage]) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        for message in messages {
+            sqlx::query("INSERT INTO webhook_outbox (url, event, body, next_attempt_at) VALUES(?1,?2,?3,?4)")
+                .bind(&message.url)
+                .bind(&message.event)
+                .bind(&message.body)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /* Returns the distinct URLs of the messages waiting in the webhook outbox. */
+    pub async fn get_webhook_urls(&self) -> anyhow::Result<Vec<String>> {
+        let rows = sqlx::query("SELECT DISTINCT url FROM webhook_outbox")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /* Returns the messages of the webhook outbox to the given URL that are due for a delivery attempt, oldest first. */
+    pub async fn get_due_webhooks(
+        &self,
+        url: &str,
+        now: i64,
+        max_attempts: u32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<QueuedWebhook>> {
+        let rows = sqlx::query(
+            "SELECT id, attempts, url, event, body FROM webhook_outbox
+            WHERE url = ?1 AND next_attempt_at <= ?2 AND attempts < ?3
+            ORDER BY id ASC
+            LIMIT ?4",
+        )
+        .bind(url)
+        .bind(now)
+        .bind(max_attempts as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| QueuedWebhook {
+                id: row.get(0),
+                attempts: row.get::<i64, _>(1) as u32,
+                message: OutboxMessage {
+                    url: row.get(2),
+                    event: row.get(3),
+                    body: row.get(4),
+                },
+            })
+            .collect())
+    }
+
+    /* Removes a delivered message from the webhook outbox. */
+    pub async fn delete_webhook(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM webhook_outbox WHERE id = ?")
+            .bind(id)
             .execute(&self.pool)
             .await?;
+        Ok(())
+    }
 
-        Ok(result.rows_affected())
+    /* Records a failed delivery attempt and when to try again. */
+    pub async fn record_webhook_failure(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE webhook_outbox SET attempts = ?1, next_attempt_at = ?2, last_error = ?3 WHERE id = ?4")
+            .bind(attempts as i64)
+            .bind(next_attempt_at)
+            .bind(error)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /* Returns the satoshis held by the tracked outpoints spent by the blocks in the given range of heights. */
+    pub async fn get_spent_sats_between(
+        &self,
+        btc_address_type: String,
+        from_height: u64,
+        to_height: u64,
+    ) -> anyhow::Result<i64> {
+        let result = sqlx::query(&format!(
+            "SELECT COALESCE(SUM(value), 0) FROM {}_spends WHERE block_height BETWEEN ? AND ?",
+            btc_address_type
+        ))
+        .bind(from_height as i64)
+        .bind(to_height as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(result.get(0))
+    }
+
+    /* Returns alerts raised for blocks, most recent first.
+     * - min_severity: Only return alerts at least this severe
+     * - limit / offset: Page through the results
+     */
+    pub async fn get_alerts(
+        &self,
+        btc_address_type: BtcAddressType,
+        min_severity: Severity,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<Alert>> {
+        let severities = Severity::ALL
+            .iter()
+            .filter(|severity| **severity >= min_severity)
+            .map(|severity| format!("'{}'", severity.as_str()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let rows = sqlx::query(&format!(
+            "SELECT block_height, block_time, rule, severity, message, value_sats
+            FROM {}_alerts
+            WHERE severity IN ({})
+            ORDER BY block_height DESC, id DESC
+            LIMIT ?1 OFFSET ?2",
+            btc_address_type, severities
+        ))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(Alert {
+                    block_height: row.get::<i64, _>(0) as usize,
+                    block_time: row.get(1),
+                    address_type: btc_address_type.as_str().to_string(),
+                    rule: row.get(2),
+                    severity: row.get::<String, _>(3).parse().map_err(anyhow::Error::msg)?,
+                    message: row.get(4),
+                    value_sats: row.get(5),
+                })
+            })
+            .collect()
     }
 
     /*
@@ -120,12 +1305,12 @@ impl SQLitePersistence {
         // If it is, it uses num_blocks to calculate the range.
         // If num_blocks is 0, it effectively sets the condition to block_height > 0, which includes all records.
         let results = sqlx::query(&format!(
-            "SELECT date, block_height, block_hash_big_endian, total_utxos, total_sats 
+            "SELECT {} 
             FROM {} 
             WHERE block_height > (SELECT MAX(block_height) - CASE WHEN $1 > 0 THEN $1 ELSE MAX(block_height) END FROM {})
             AND block_height % $2 = 0
             ORDER BY block_height ASC",
-            table_name, table_name
+            BLOCK_AGGREGATE_COLUMNS, table_name, table_name
         ))
         .bind(num_latest_blocks)
         .bind(result_sampling_interval)
@@ -134,16 +1319,143 @@ impl SQLitePersistence {
 
         debug!("get_latest_block_aggregates: address_type = {}; num_latest_blocks = {}; result_sampling_interval = {}; total_results_count = {}", btc_address_type, num_latest_blocks, result_sampling_interval, results.len() );
 
-        Ok(results
-            .into_iter()
-            .map(|row| BlockAggregateOutput {
-                date: row.get::<String, _>(0),
-                block_height: row.get::<i64, _>(1) as usize,
-                block_hash_big_endian: row.get::<String, _>(2),
-                total_utxos: row.get::<i64, _>(3) as u32,
-                total_sats: row.get::<f64, _>(4),
-            })
-            .collect())
+        Ok(results.iter().map(block_aggregate_from_row).collect())
+    }
+
+    /*
+     * Returns the per-block vintage snapshots of the given address type, oldest block first.
+     * num_latest_blocks and result_sampling_interval work as for get_latest_block_aggregates.
+     */
+    pub async fn get_latest_block_vintages(
+        &self,
+        btc_address_type: BtcAddressType,
+        num_latest_blocks: Option<i64>,
+        result_sampling_interval: Option<i64>,
+    ) -> anyhow::Result<Vec<BlockVintagesOutput>> {
+        let table_name = format!("{}_vintage_block_aggregates", btc_address_type);
+        let rows = sqlx::query(&format!(
+            "SELECT block_height, vintage, total_utxos, total_sats
+            FROM {}
+            WHERE block_height > (SELECT MAX(block_height) - CASE WHEN $1 > 0 THEN $1 ELSE MAX(block_height) END FROM {})
+            AND block_height % $2 = 0
+            ORDER BY block_height ASC, vintage_start ASC",
+            table_name, table_name
+        ))
+        .bind(num_latest_blocks.unwrap_or(0))
+        .bind(result_sampling_interval.unwrap_or(10))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut blocks: Vec<BlockVintagesOutput> = Vec::new();
+        for row in rows.iter() {
+            let block_height = row.get::<i64, _>(0) as usize;
+            let vintage = VintageOutput {
+                vintage: row.get(1),
+                total_utxos: row.get::<i64, _>(2) as u32,
+                total_sats: row.get(3),
+            };
+            match blocks.last_mut() {
+                Some(block) if block.block_height == block_height => block.vintages.push(vintage),
+                _ => blocks.push(BlockVintagesOutput {
+                    block_height,
+                    vintages: vec![vintage],
+                }),
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /*
+     * Returns the per-block value band snapshots of the given address type, oldest block first.
+     * num_latest_blocks and result_sampling_interval work as for get_latest_block_aggregates.
+     */
+    pub async fn get_latest_block_value_bands(
+        &self,
+        btc_address_type: BtcAddressType,
+        num_latest_blocks: Option<i64>,
+        result_sampling_interval: Option<i64>,
+    ) -> anyhow::Result<Vec<BlockValueBandsOutput>> {
+        let table_name = format!("{}_value_band_block_aggregates", btc_address_type);
+        let rows = sqlx::query(&format!(
+            "SELECT block_height, value_band, total_utxos, total_sats
+            FROM {}
+            WHERE block_height > (SELECT MAX(block_height) - CASE WHEN $1 > 0 THEN $1 ELSE MAX(block_height) END FROM {})
+            AND block_height % $2 = 0
+            ORDER BY block_height ASC, min_value ASC",
+            table_name, table_name
+        ))
+        .bind(num_latest_blocks.unwrap_or(0))
+        .bind(result_sampling_interval.unwrap_or(10))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut blocks: Vec<BlockValueBandsOutput> = Vec::new();
+        for row in rows.iter() {
+            let block_height = row.get::<i64, _>(0) as usize;
+            let value_band = ValueBandOutput {
+                value_band: row.get(1),
+                total_utxos: row.get::<i64, _>(2) as u32,
+                total_sats: row.get(3),
+            };
+            match blocks.last_mut() {
+                Some(block) if block.block_height == block_height => block.value_bands.push(value_band),
+                _ => blocks.push(BlockValueBandsOutput {
+                    block_height,
+                    value_bands: vec![value_band],
+                }),
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /* Returns the aggregates of blocks `from_height` to `to_height`, oldest block first.
+     * - limit: Most blocks returned
+     */
+    pub async fn get_block_aggregates_between(
+        &self,
+        btc_address_type: String,
+        from_height: i64,
+        to_height: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<BlockAggregateOutput>> {
+        let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM {} WHERE block_height BETWEEN ?1 AND ?2 ORDER BY block_height ASC LIMIT ?3",
+            BLOCK_AGGREGATE_COLUMNS, table_name
+        ))
+        .bind(from_height)
+        .bind(to_height)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(block_aggregate_from_row).collect())
+    }
+
+    /* Returns the aggregates of the blocks above `height`, oldest block first.
+     * Only the latest `limit` blocks are returned when more are stored.
+     */
+    pub async fn get_block_aggregates_after(
+        &self,
+        btc_address_type: String,
+        height: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<BlockAggregateOutput>> {
+        let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
+        let rows = sqlx::query(&format!(
+            "SELECT * FROM (
+                SELECT {} FROM {} WHERE block_height > ?1 ORDER BY block_height DESC LIMIT ?2
+            ) ORDER BY block_height ASC",
+            BLOCK_AGGREGATE_COLUMNS, table_name
+        ))
+        .bind(height)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(block_aggregate_from_row).collect())
     }
 
     pub async fn get_block_by_hash(
@@ -153,24 +1465,14 @@ impl SQLitePersistence {
     ) -> anyhow::Result<Option<BlockAggregateOutput>> {
         let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
         let result = sqlx::query(&format!(
-            "SELECT date, block_height, block_hash_big_endian, total_utxos, total_sats 
-             FROM {} WHERE block_hash_big_endian = ?",
-            table_name
+            "SELECT {} FROM {} WHERE block_hash_big_endian = ?",
+            BLOCK_AGGREGATE_COLUMNS, table_name
         ))
         .bind(hash)
         .fetch_optional(&self.pool)
         .await?;
 
-        match result {
-            Some(row) => Ok(Some(BlockAggregateOutput {
-                date: row.get(0),
-                block_height: row.get::<i64, _>(1) as usize,
-                block_hash_big_endian: row.get(2),
-                total_utxos: row.get::<i64, _>(3) as u32,
-                total_sats: row.get::<f64, _>(4),
-            })),
-            None => Ok(None),
-        }
+        Ok(result.as_ref().map(block_aggregate_from_row))
     }
 
     pub async fn get_block_by_height(
@@ -180,24 +1482,14 @@ impl SQLitePersistence {
     ) -> anyhow::Result<Option<BlockAggregateOutput>> {
         let table_name = format!("{}_utxo_block_aggregates", btc_address_type);
         let result = sqlx::query(&format!(
-            "SELECT date, block_height, block_hash_big_endian, total_utxos, total_sats 
-             FROM {} WHERE block_height = ?",
-            table_name
+            "SELECT {} FROM {} WHERE block_height = ?",
+            BLOCK_AGGREGATE_COLUMNS, table_name
         ))
         .bind(height)
         .fetch_optional(&self.pool)
         .await?;
 
-        match result {
-            Some(row) => Ok(Some(BlockAggregateOutput {
-                date: row.get(0),
-                block_height: row.get::<i64, _>(1) as usize,
-                block_hash_big_endian: row.get(2),
-                total_utxos: row.get(3),
-                total_sats: row.get(4),
-            })),
-            None => Ok(None),
-        }
+        Ok(result.as_ref().map(block_aggregate_from_row))
     }
 
     /* Returns the last block height in the database.
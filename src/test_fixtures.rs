@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use nakamoto::common::bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1};
+use nakamoto::common::bitcoin::blockdata::script::Builder;
+use nakamoto::common::bitcoin::hashes::Hash;
+use nakamoto::common::bitcoin::{
+    Block, BlockHash, BlockHeader, OutPoint, PackedLockTime, PublicKey, Script, Sequence, Transaction, TxIn,
+    TxMerkleNode, TxOut, Witness,
+};
+use tempfile::TempDir;
+use tokio::sync::broadcast;
+
+use crate::alerts::AlertRule;
+use crate::block_source::{BlockSource, VecBlockSource};
+use crate::persistence::SQLitePersistence;
+use crate::utxo_store::UtxoStore;
+use crate::{load_tracked_types, process_blocks, BlockProcessed, StreamEvent};
+
+/// Timestamp of the genesis block.  Each following fixture block is ten minutes later.
+pub const GENESIS_TIME: u32 = 1231006505;
+
+/// Difficulty of the fixture blocks, the lowest that regtest allows.
+pub const REGTEST_BITS: u32 = 0x207fffff;
+
+/// The compressed public key of the secp256k1 generator point.
+pub const COMPRESSED_GENERATOR: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+/// The uncompressed public key of the secp256k1 generator point.
+pub const UNCOMPRESSED_GENERATOR: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+    483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+/// A P2PK script locked to the public key of the secp256k1 generator point.
+pub fn p2pk_script() -> Script {
+    let pubkey = PublicKey::from_str(COMPRESSED_GENERATOR).unwrap();
+    Script::new_p2pk(&pubkey)
+}
+
+/// A P2PK script locked to the uncompressed public key of the secp256k1 generator point.
+pub fn uncompressed_p2pk_script() -> Script {
+    let pubkey = PublicKey::from_str(UNCOMPRESSED_GENERATOR).unwrap();
+    Script::new_p2pk(&pubkey)
+}
+
+/// A P2PKH script locked to the hash of `pubkey`.
+pub fn p2pkh_script(pubkey: &PublicKey) -> Script {
+    Script::new_p2pkh(&pubkey.pubkey_hash())
+}
+
+/// A P2WPKH script locked to the hash of `pubkey`, which must be compressed.
+pub fn p2wpkh_script(pubkey: &PublicKey) -> Script {
+    Script::new_v0_p2wpkh(&pubkey.wpubkey_hash().unwrap())
+}
+
+/// A P2TR script locked to the x-only output key made of 32 `key_byte`s.
+pub fn p2tr_script(key_byte: u8) -> Script {
+    Builder::new().push_opcode(OP_PUSHNUM_1).push_slice(&[key_byte; 32]).into_script()
+}
+
+/// A bare `required_sigs`-of-n multisig script of `pubkeys`.
+pub fn multisig_script(required_sigs: i64, pubkeys: &[&[u8]]) -> Script {
+    let mut builder = Builder::new().push_int(required_sigs);
+    for pubkey in pubkeys {
+        builder = builder.push_slice(pubkey);
+    }
+    builder.push_int(pubkeys.len() as i64).push_opcode(OP_CHECKMULTISIG).into_script()
+}
+
+/// A coinbase transaction of the block at `height` paying `outputs`.
+/// The height is pushed in the script sig, so that coinbases of different blocks have different txids.
+pub fn coinbase(height: u64, outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_int(height as i64).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: outputs,
+    }
+}
+
+/// A transaction spending `inputs` to `outputs`.
+pub fn spend(inputs: &[OutPoint], outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: PackedLockTime::ZERO,
+        input: inputs
+            .iter()
+            .map(|outpoint| TxIn {
+                previous_output: *outpoint,
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            })
+            .collect(),
+        output: outputs,
+    }
+}
+
+/// An output of `value` satoshis locked to `script_pubkey`.
+pub fn output(value: u64, script_pubkey: Script) -> TxOut {
+    TxOut { value, script_pubkey }
+}
+
+/// A block at `height` building on `prev_blockhash`, with the given difficulty.
+pub fn block_with_bits(prev_blockhash: BlockHash, height: u64, bits: u32, txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: GENESIS_TIME + height as u32 * 600,
+            bits,
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    block
+}
+
+/// A block at `height` building on `prev_blockhash`.
+pub fn block(prev_blockhash: BlockHash, height: u64, txdata: Vec<Transaction>) -> Block {
+    block_with_bits(prev_blockhash, height, REGTEST_BITS, txdata)
+}
+
+/// A chain whose block at each height has a coinbase paying 50 BTC to `p2pk_script`,
+/// followed by the transactions `extra_txdata` returns for that height.
+pub fn chain(length: u64, extra_txdata: impl Fn(u64) -> Vec<Transaction>) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for height in 0..length {
+        let prev_blockhash = blocks.last().map_or(BlockHash::all_zeros(), Block::block_hash);
+        let mut txdata = vec![coinbase(height, vec![output(5_000_000_000, p2pk_script())])];
+        txdata.extend(extra_txdata(height));
+        blocks.push(block(prev_blockhash, height, txdata));
+    }
+    blocks
+}
+
+/// Opens an empty SQLite database in a temporary directory, which is deleted when the returned guard is dropped.
+pub async fn temporary_sqlite() -> (SQLitePersistence, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("gabriel.db");
+    let sqlite = SQLitePersistence::open(path.to_str().unwrap(), 1).await.unwrap();
+    (sqlite, dir)
+}
+
+/// Runs `process_blocks` over the blocks already requested from `block_source`, evaluating `alert_rules`,
+/// and returns its signals.
+pub async fn process(
+    block_source: VecBlockSource,
+    sqlite: &SQLitePersistence,
+    sse_sender: broadcast::Sender<StreamEvent>,
+    alert_rules: &[AlertRule],
+) -> Vec<BlockProcessed> {
+    let (block_processed_tx, block_processed_rx) = crossbeam_channel::unbounded();
+    let tracked_types = load_tracked_types(sqlite).await.unwrap();
+    block_source.clone().shutdown().unwrap();
+    process_blocks(
+        block_source,
+        UtxoStore::Sqlite(sqlite.clone()),
+        alert_rules,
+        block_processed_tx,
+        sse_sender,
+        tracked_types,
+        None,
+    )
+    .await
+    .unwrap();
+    block_processed_rx.try_iter().collect()
+}
+
+/// A request received by `serve_http`.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// Headers keyed by their lowercase name.
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// Serves HTTP/1.1 on a local port from background threads, answering every request with the status
+/// and JSON body returned by `respond`.  Returns the URL of the server.
+pub fn serve_http(respond: impl Fn(&HttpRequest) -> (u16, String) + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let respond = Arc::new(respond);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let respond = Arc::clone(&respond);
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                // Keep-alive connections carry several requests
+                while let Some(request) = read_http_request(&mut reader) {
+                    let (status, body) = respond(&request);
+                    if write_http_response(&stream, status, &body).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    url
+}
+
+fn read_http_request(reader: &mut BufReader<TcpStream>) -> Option<HttpRequest> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let content_length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8(body).ok()?,
+    })
+}
+
+fn write_http_response(mut stream: &TcpStream, status: u16, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// An email received by `serve_smtp`.
+#[derive(Clone, Debug, Default)]
+pub struct SmtpEmail {
+    pub from: String,
+    pub to: Vec<String>,
+    /// Headers and body of the email.
+    pub data: String,
+}
+
+/// Serves SMTP on a local port from background threads, passing every email to `receive`, which accepts it
+/// by returning true.  Returns the port of the server.
+pub fn serve_smtp(receive: impl Fn(SmtpEmail) -> bool + Send + Sync + 'static) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let receive = Arc::new(receive);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let receive = Arc::clone(&receive);
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let _ = serve_smtp_session(stream, receive.as_ref());
+            });
+        }
+    });
+    port
+}
+
+fn serve_smtp_session(mut stream: TcpStream, receive: &dyn Fn(SmtpEmail) -> bool) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut email = SmtpEmail::default();
+    write!(stream, "220 sink\r\n")?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end();
+        let command = line.to_uppercase();
+        if command.starts_with("MAIL FROM:") {
+            email.from = line[10..].trim_matches(|c| c == '<' || c == '>' || c == ' ').to_string();
+        } else if command.starts_with("RCPT TO:") {
+            email.to.push(line[8..].trim_matches(|c| c == '<' || c == '>' || c == ' ').to_string());
+        } else if command.starts_with("DATA") {
+            write!(stream, "354 go ahead\r\n")?;
+            loop {
+                let mut data_line = String::new();
+                if reader.read_line(&mut data_line)? == 0 {
+                    return Ok(());
+                }
+                if data_line.trim_end() == "." {
+                    break;
+                }
+                // Lines starting with a dot are sent with another in front
+                email.data.push_str(data_line.strip_prefix('.').unwrap_or(&data_line));
+            }
+            let accepted = receive(std::mem::take(&mut email));
+            write!(stream, "{}\r\n", if accepted { "250 queued" } else { "554 rejected" })?;
+            continue;
+        } else if command.starts_with("QUIT") {
+            return write!(stream, "221 bye\r\n");
+        } else if command.starts_with("RSET") {
+            email = SmtpEmail::default();
+        }
+        write!(stream, "250 ok\r\n")?;
+    }
+}
@@ -1,9 +1,15 @@
+use crate::alerts::Alert;
 use crate::AppError;
 use log::{info, error};
+use std::collections::HashSet;
 use std::env;
 use std::path::PathBuf;
 use std::process::Command;
 use anyhow::Result;
+use chrono::{Datelike, TimeZone, Utc};
+use nakamoto::common::bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use nakamoto::common::bitcoin::blockdata::script::Instruction;
+use nakamoto::common::bitcoin::{OutPoint, PublicKey, Script, TxIn};
 
 #[derive(Clone, Debug, serde::Serialize)]
 pub struct BlockAggregateOutput {
@@ -12,20 +18,621 @@ pub struct BlockAggregateOutput {
     pub block_hash_big_endian: String,
     pub total_utxos: u32,
     pub total_sats: f64,
+    /// P2PK totals split by the encoding of the public key: 33 byte compressed or 65 byte uncompressed.
+    /// Null for other address types.  Outpoints copied from a sled store without their public key are in neither.
+    pub compressed_utxos: Option<u32>,
+    pub compressed_sats: Option<f64>,
+    pub uncompressed_utxos: Option<u32>,
+    pub uncompressed_sats: Option<f64>,
+    /// Totals split by whether the outpoints were created by a coinbase transaction.
+    /// Null for reused_pkh.  Outpoints copied from a sled store without their origin are in neither.
+    pub coinbase_utxos: Option<u32>,
+    pub coinbase_sats: Option<f64>,
+    pub non_coinbase_utxos: Option<u32>,
+    pub non_coinbase_sats: Option<f64>,
+    /// Totals of the outpoints in the cohort loaded from COHORT_FILE.
+    /// Null for reused_pkh, and for every type when no cohort is loaded.
+    pub cohort_utxos: Option<u32>,
+    pub cohort_sats: Option<f64>,
+    /// Sum of the value in BTC times the age in days of the tracked outputs spent by the block.
+    /// Null for blocks scanned before it was recorded, and when only a subset of the totals is reported.
+    pub coin_days_destroyed: Option<f64>,
 }
 
+impl BlockAggregateOutput {
+    /// Aggregates of a block without any of the subset totals.
+    pub fn new(date: String, block_height: usize, block_hash_big_endian: String, total_utxos: u32, total_sats: f64) -> Self {
+        BlockAggregateOutput {
+            date,
+            block_height,
+            block_hash_big_endian,
+            total_utxos,
+            total_sats,
+            compressed_utxos: None,
+            compressed_sats: None,
+            uncompressed_utxos: None,
+            uncompressed_sats: None,
+            coinbase_utxos: None,
+            coinbase_sats: None,
+            non_coinbase_utxos: None,
+            non_coinbase_sats: None,
+            cohort_utxos: None,
+            cohort_sats: None,
+            coin_days_destroyed: None,
+        }
+    }
+
+    /// Returns the number of outpoints in `subset` and the satoshis they hold, if the aggregates split it out.
+    pub fn subset_totals(&self, subset: UtxoSubset) -> Option<(u32, f64)> {
+        let (utxos, sats) = match subset {
+            UtxoSubset::Compressed => (self.compressed_utxos, self.compressed_sats),
+            UtxoSubset::Uncompressed => (self.uncompressed_utxos, self.uncompressed_sats),
+            UtxoSubset::Coinbase => (self.coinbase_utxos, self.coinbase_sats),
+            UtxoSubset::NonCoinbase => (self.non_coinbase_utxos, self.non_coinbase_sats),
+            UtxoSubset::Cohort => (self.cohort_utxos, self.cohort_sats),
+        };
+        Some((utxos?, sats?))
+    }
+
+    pub fn set_subset_totals(&mut self, subset: UtxoSubset, utxos: u32, sats: f64) {
+        let (subset_utxos, subset_sats) = match subset {
+            UtxoSubset::Compressed => (&mut self.compressed_utxos, &mut self.compressed_sats),
+            UtxoSubset::Uncompressed => (&mut self.uncompressed_utxos, &mut self.uncompressed_sats),
+            UtxoSubset::Coinbase => (&mut self.coinbase_utxos, &mut self.coinbase_sats),
+            UtxoSubset::NonCoinbase => (&mut self.non_coinbase_utxos, &mut self.non_coinbase_sats),
+            UtxoSubset::Cohort => (&mut self.cohort_utxos, &mut self.cohort_sats),
+        };
+        *subset_utxos = Some(utxos);
+        *subset_sats = Some(sats);
+    }
+
+    /// Replaces the totals with those of `subset`, dropping the other splits which don't apply to it.
+    /// Returns None if the aggregates don't split out `subset`.
+    pub fn only_subset(self, subset: UtxoSubset) -> Option<Self> {
+        let (utxos, sats) = self.subset_totals(subset)?;
+        let mut aggregate = BlockAggregateOutput::new(self.date, self.block_height, self.block_hash_big_endian, utxos, sats);
+        aggregate.set_subset_totals(subset, utxos, sats);
+        Some(aggregate)
+    }
+}
+
+/// Subsets of the outpoints of an address type whose totals are split out in the block aggregates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UtxoSubset {
+    /// Outpoints locked to a 33 byte compressed public key.
+    Compressed,
+    /// Outpoints locked to a 65 byte uncompressed public key.
+    Uncompressed,
+    /// Outpoints created by a coinbase transaction.
+    Coinbase,
+    NonCoinbase,
+    /// Outpoints in the cohort loaded from COHORT_FILE.
+    Cohort,
+}
+
+impl UtxoSubset {
+    /// Returns the subsets split out in the block aggregates of `address_type`.
+    /// The totals of reused_pkh count exposed outpoints only, which aren't split.
+    /// The cohort is only split out when one is loaded.
+    pub fn split_out_for(address_type: BtcAddressType) -> &'static [UtxoSubset] {
+        match address_type {
+            BtcAddressType::P2PK => &[
+                UtxoSubset::Compressed,
+                UtxoSubset::Uncompressed,
+                UtxoSubset::Coinbase,
+                UtxoSubset::NonCoinbase,
+                UtxoSubset::Cohort,
+            ],
+            BtcAddressType::ReusedPkh => &[],
+            _ => &[UtxoSubset::Coinbase, UtxoSubset::NonCoinbase, UtxoSubset::Cohort],
+        }
+    }
+
+    /// Returns true if an outpoint with the given public key, origin and cohort membership is in the subset.
+    /// Outpoints whose public key or origin isn't known are in none of the subsets that depend on it.
+    pub fn contains(&self, pubkey: Option<&[u8]>, is_coinbase: Option<bool>, in_cohort: bool) -> bool {
+        match self {
+            UtxoSubset::Compressed => pubkey.is_some_and(|pubkey| pubkey.len() == 33),
+            UtxoSubset::Uncompressed => pubkey.is_some_and(|pubkey| pubkey.len() == 65),
+            UtxoSubset::Coinbase => is_coinbase == Some(true),
+            UtxoSubset::NonCoinbase => is_coinbase == Some(false),
+            UtxoSubset::Cohort => in_cohort,
+        }
+    }
+}
+
+/// Totals of the unspent outpoints created within one vintage, as of some block.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct VintageOutput {
+    /// Calendar year, or range of heights, in which the outpoints were created.  ie: "2009", "0-104999", "210000+"
+    pub vintage: String,
+    pub total_utxos: u32,
+    pub total_sats: f64,
+}
+
+/// Snapshot of the unspent outpoints of a block bucketed by vintage, as served by `/api/vintages/latest`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BlockVintagesOutput {
+    pub block_height: usize,
+    /// Vintages holding unspent outpoints, oldest first.
+    pub vintages: Vec<VintageOutput>,
+}
+
+/// A vintage bucket.  Ordered by the year or height at which it starts.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Vintage {
+    pub start: u64,
+    pub label: String,
+}
+
+/// How outpoints are bucketed by the block that created them.
+/// Selected with the VINTAGE_HEIGHT_RANGES environment variable.
+#[derive(Clone, Debug)]
+pub enum VintageBuckets {
+    /// By the calendar year (UTC) of the block's timestamp.
+    Years,
+    /// By ranges of heights, each starting at one of these heights.  Sorted, starting at 0.
+    HeightRanges(Vec<u64>),
+}
+
+impl VintageBuckets {
+    /// Reads VINTAGE_HEIGHT_RANGES, a comma separated list of the heights at which ranges start.
+    /// Buckets are calendar years when it isn't set.
+    pub fn from_env() -> Result<Self, AppError> {
+        let ranges = match env::var("VINTAGE_HEIGHT_RANGES") {
+            Ok(ranges) => ranges,
+            Err(_) => return Ok(VintageBuckets::Years),
+        };
+
+        let mut starts = ranges
+            .split(',')
+            .map(|start| {
+                start.trim().parse::<u64>().map_err(|_| {
+                    AppError::CustomError(format!("Invalid height in VINTAGE_HEIGHT_RANGES: {}", start))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // The first range covers the blocks before any of the given heights
+        starts.push(0);
+        starts.sort_unstable();
+        starts.dedup();
+
+        Ok(VintageBuckets::HeightRanges(starts))
+    }
+
+    /// Returns the vintage of outpoints created by the block at `height` with timestamp `time`.
+    pub fn vintage(&self, height: u64, time: u32) -> Vintage {
+        match self {
+            VintageBuckets::Years => {
+                let year = Utc.timestamp_opt(time as i64, 0).unwrap().year() as u64;
+                Vintage {
+                    start: year,
+                    label: year.to_string(),
+                }
+            }
+            VintageBuckets::HeightRanges(starts) => {
+                let index = starts.partition_point(|start| *start <= height) - 1;
+                let label = match starts.get(index + 1) {
+                    Some(next_start) => format!("{}-{}", starts[index], next_start - 1),
+                    None => format!("{}+", starts[index]),
+                };
+                Vintage {
+                    start: starts[index],
+                    label,
+                }
+            }
+        }
+    }
+}
+
+/// Coins of interest, such as those attributed to the Patoshi mining pattern.
+/// Coinbase outputs of one of the listed blocks, and outputs at one of the listed outpoints, are members.
+#[derive(Clone, Debug, Default)]
+pub struct Cohort {
+    /// Blocks whose coinbase outputs are members.  Other outputs created by these blocks are not.
+    pub heights: HashSet<u64>,
+    pub outpoints: HashSet<OutPoint>,
+}
+
+impl Cohort {
+    /// Reads the file named by COHORT_FILE, which lists one block height or `txid:vout` outpoint per line.
+    /// Blank lines and lines starting with '#' are skipped.  The cohort is empty when COHORT_FILE isn't set.
+    pub fn from_env() -> Result<Self, AppError> {
+        let path = match env::var("COHORT_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(Cohort::default()),
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| AppError::CustomError(format!("Failed to read COHORT_FILE {}: {}", path, e)))?;
+
+        let mut cohort = Cohort::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Ok(height) = line.parse::<u64>() {
+                cohort.heights.insert(height);
+            } else if let Ok(outpoint) = line.parse::<OutPoint>() {
+                cohort.outpoints.insert(outpoint);
+            } else {
+                return Err(AppError::CustomError(format!("Invalid entry in COHORT_FILE: {}", line)));
+            }
+        }
+        Ok(cohort)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heights.is_empty() && self.outpoints.is_empty()
+    }
+
+    /// Returns true if the output at `outpoint` is a member.
+    /// `coinbase_height` is the height of the block whose coinbase created the output, for coinbase outputs.
+    pub fn contains(&self, outpoint: &OutPoint, coinbase_height: Option<u64>) -> bool {
+        self.outpoints.contains(outpoint) || coinbase_height.is_some_and(|height| self.heights.contains(&height))
+    }
+}
+
+/// Bands of output values: under 1 BTC, 1 to 10 BTC, 10 to 50 BTC, and 50 BTC or more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValueBand {
+    UnderOneBtc,
+    OneToTenBtc,
+    TenToFiftyBtc,
+    FiftyBtcOrMore,
+}
+
+impl ValueBand {
+    /// Every band, smallest values first.
+    pub const ALL: &'static [ValueBand] = &[
+        ValueBand::UnderOneBtc,
+        ValueBand::OneToTenBtc,
+        ValueBand::TenToFiftyBtc,
+        ValueBand::FiftyBtcOrMore,
+    ];
+
+    /// Returns the band of an output holding `value` satoshis.
+    pub fn of(value: i64) -> Self {
+        ValueBand::ALL
+            .iter()
+            .rev()
+            .find(|band| value >= band.min_value())
+            .copied()
+            .unwrap_or(ValueBand::UnderOneBtc)
+    }
+
+    /// Smallest value, in satoshis, of the outputs in the band.
+    pub fn min_value(&self) -> i64 {
+        match self {
+            ValueBand::UnderOneBtc => 0,
+            ValueBand::OneToTenBtc => 100_000_000,
+            ValueBand::TenToFiftyBtc => 1_000_000_000,
+            ValueBand::FiftyBtcOrMore => 5_000_000_000,
+        }
+    }
+
+    /// Range of BTC values of the band.  ie: "0-1", "50+"
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueBand::UnderOneBtc => "0-1",
+            ValueBand::OneToTenBtc => "1-10",
+            ValueBand::TenToFiftyBtc => "10-50",
+            ValueBand::FiftyBtcOrMore => "50+",
+        }
+    }
+}
+
+/// Totals of the unspent outpoints within one value band, as of some block.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ValueBandOutput {
+    /// Range of BTC values of the outpoints.  ie: "0-1", "1-10", "10-50", "50+"
+    pub value_band: String,
+    pub total_utxos: u32,
+    pub total_sats: f64,
+}
+
+/// Snapshot of the unspent outpoints of a block bucketed by value, as served by `/api/value_bands/latest`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BlockValueBandsOutput {
+    pub block_height: usize,
+    /// Every value band, smallest values first.
+    pub value_bands: Vec<ValueBandOutput>,
+}
+
+/// An unspent output, as served by `/api/utxos/:address_type`.
+/// Everything but the outpoint and value is null for outpoints copied from a sled store.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct UtxoOutput {
+    pub txid: String,
+    pub vout: u32,
+    pub value: i64,
+    pub block_height: Option<i64>,
+    /// Unix timestamp of the block that created the output.
+    pub block_time: Option<i64>,
+    pub is_coinbase: Option<bool>,
+    /// Hex encoded public key the output is locked to.
+    /// The comma separated keys of a P2MS output.
+    pub pubkey: Option<String>,
+    /// Hex encoded hash160 of the public key, for P2PKH and P2WPKH outputs.
+    pub pubkey_hash: Option<String>,
+    /// m-of-n of a P2MS output, and how many of its keys are valid public keys.
+    pub required_sigs: Option<i64>,
+    pub key_count: Option<i64>,
+    pub exposed_keys: Option<i64>,
+    /// Whether the output is in the cohort loaded from COHORT_FILE.
+    pub in_cohort: bool,
+}
+
+/// The spend of a tracked output, as served by `/api/spends`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SpendOutput {
+    pub spending_txid: String,
+    pub input_index: u32,
+    pub block_height: usize,
+    /// Unix timestamp of the spending block.
+    pub block_time: i64,
+    /// Outpoint that was spent.
+    pub txid: String,
+    pub vout: u32,
+    pub value: i64,
+    /// Height of the block that created the spent output, and its age when spent.
+    /// Null for outputs copied from a sled store without their history.
+    pub created_height: Option<i64>,
+    pub age_blocks: Option<i64>,
+    pub age_days: Option<f64>,
+    /// Script type of each output of the spending transaction, ie: "p2pkh", "p2wpkh", "op_return".
+    pub destination_script_types: Vec<String>,
+    /// Set when the spent output is in the cohort loaded from COHORT_FILE.
+    pub high_priority: bool,
+}
+
+/// Describes blocks that were rolled back because of a chain reorganization.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ReorgOutput {
+    /// Height of the last block shared by the stale and the new chain.
+    pub fork_height: usize,
+    pub stale_tip_height: usize,
+    pub stale_tip_hash_big_endian: String,
+}
+
+/// The spend of a tracked output of any address type, as sent on the SSE stream.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SpendStreamOutput {
+    pub address_type: String,
+    #[serde(flatten)]
+    pub spend: SpendOutput,
+}
+
+/// How far the scan of the chain has got, sent on the SSE stream after every block.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SyncProgressOutput {
+    pub block_height: usize,
+    /// Height of the tip of the chain, as last reported by the block source.
+    pub tip_height: usize,
+    pub progress_percent: f64,
+}
+
+/// Events published to subscribers of `/api/blocks/stream` and `/api/ws`.
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    /// Aggregates of a newly scanned block, for one address type.  The SSE stream only carries P2PK ones.
+    Aggregate(BtcAddressType, BlockAggregateOutput),
+    Spend(SpendStreamOutput),
+    Alert(Alert),
+    Reorg(ReorgOutput),
+    SyncProgress(SyncProgressOutput),
+}
+
+impl StreamEvent {
+    /// Topics that SSE clients can subscribe to, named after the SSE event of each kind of event.
+    pub const TOPICS: &'static [&'static str] = &["aggregate", "spend", "alert", "reorg", "sync_progress"];
+
+    pub fn topic(&self) -> &'static str {
+        match self {
+            StreamEvent::Aggregate(..) => "aggregate",
+            StreamEvent::Spend(_) => "spend",
+            StreamEvent::Alert(_) => "alert",
+            StreamEvent::Reorg(_) => "reorg",
+            StreamEvent::SyncProgress(_) => "sync_progress",
+        }
+    }
+
+    /// Returns the address type the event is about, or None for events about the whole chain.
+    pub fn address_type(&self) -> Option<&str> {
+        match self {
+            StreamEvent::Aggregate(address_type, _) => Some(address_type.as_str()),
+            StreamEvent::Spend(spend) => Some(&spend.address_type),
+            StreamEvent::Alert(alert) => Some(&alert.address_type),
+            StreamEvent::Reorg(_) | StreamEvent::SyncProgress(_) => None,
+        }
+    }
+
+    /// Returns the data of the event, without the address type of aggregates.
+    pub fn data(&self) -> serde_json::Value {
+        match self {
+            StreamEvent::Aggregate(_, block) => serde_json::json!(block),
+            StreamEvent::Spend(spend) => serde_json::json!(spend),
+            StreamEvent::Alert(alert) => serde_json::json!(alert),
+            StreamEvent::Reorg(reorg) => serde_json::json!(reorg),
+            StreamEvent::SyncProgress(progress) => serde_json::json!(progress),
+        }
+    }
+}
+
+/// Returns a short name for the type of an output script.
+pub fn script_type(script_pubkey: &Script) -> &'static str {
+    if script_pubkey.is_p2pk() {
+        "p2pk"
+    } else if Multisig::from_script(script_pubkey).is_some() {
+        "p2ms"
+    } else if script_pubkey.is_p2pkh() {
+        "p2pkh"
+    } else if script_pubkey.is_p2sh() {
+        "p2sh"
+    } else if script_pubkey.is_v0_p2wpkh() {
+        "p2wpkh"
+    } else if script_pubkey.is_v0_p2wsh() {
+        "p2wsh"
+    } else if script_pubkey.is_v1_p2tr() {
+        "p2tr"
+    } else if script_pubkey.is_op_return() {
+        "op_return"
+    } else if script_pubkey.is_witness_program() {
+        "witness_unknown"
+    } else {
+        "nonstandard"
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum BtcAddressType {
     P2PK,
     P2TR,
+    /// P2PKH and P2WPKH outputs.  Only those sent to an address whose public key
+    /// was revealed by an earlier spend from it are counted as exposed.
+    ReusedPkh,
+    /// Bare multisig outputs, which expose every one of their public keys.
+    P2MS,
 }
 
 impl BtcAddressType {
+    /// Address types whose outputs are tracked by `process_blocks`.
+    pub const TRACKED: &'static [BtcAddressType] = &[
+        BtcAddressType::P2PK,
+        BtcAddressType::P2TR,
+        BtcAddressType::ReusedPkh,
+        BtcAddressType::P2MS,
+    ];
+
+    /// Returns true if per-block snapshots of the unspent outpoints of this type are bucketed by vintage.
+    pub fn tracks_vintages(&self) -> bool {
+        matches!(self, BtcAddressType::P2PK)
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             BtcAddressType::P2PK => "p2pk",
             BtcAddressType::P2TR => "p2tr",
+            BtcAddressType::ReusedPkh => "reused_pkh",
+            BtcAddressType::P2MS => "p2ms",
+        }
+    }
+
+    /// Returns the address type of an output script, if it is one that's tracked.
+    pub fn from_script(script_pubkey: &Script) -> Option<Self> {
+        if script_pubkey.is_p2pk() {
+            Some(BtcAddressType::P2PK)
+        } else if script_pubkey.is_v1_p2tr() {
+            Some(BtcAddressType::P2TR)
+        } else if script_pubkey.is_p2pkh() || script_pubkey.is_v0_p2wpkh() {
+            Some(BtcAddressType::ReusedPkh)
+        } else if Multisig::from_script(script_pubkey).is_some() {
+            Some(BtcAddressType::P2MS)
+        } else {
+            None
         }
     }
+
+    /// Returns the public key exposed by an output script of this type.
+    /// P2PKH and P2WPKH scripts only commit to the hash of their public key,
+    /// the keys of P2MS scripts are returned by `Multisig::from_script`.
+    pub fn exposed_pubkey<'a>(&self, script_pubkey: &'a Script) -> Option<&'a [u8]> {
+        let bytes = script_pubkey.as_bytes();
+        match self {
+            // <push 33 or 65 bytes> <pubkey> OP_CHECKSIG
+            BtcAddressType::P2PK => Some(&bytes[1..bytes.len() - 1]),
+            // OP_1 <push 32 bytes> <x-only output key>
+            BtcAddressType::P2TR => Some(&bytes[2..]),
+            BtcAddressType::ReusedPkh | BtcAddressType::P2MS => None,
+        }
+    }
+
+    /// Returns the hash160 of the public key an output script of this type is locked to.
+    pub fn pubkey_hash<'a>(&self, script_pubkey: &'a Script) -> Option<&'a [u8]> {
+        let bytes = script_pubkey.as_bytes();
+        match self {
+            // OP_DUP OP_HASH160 <push 20 bytes> <pubkey hash> OP_EQUALVERIFY OP_CHECKSIG
+            BtcAddressType::ReusedPkh if script_pubkey.is_p2pkh() => Some(&bytes[3..23]),
+            // OP_0 <push 20 bytes> <pubkey hash>
+            BtcAddressType::ReusedPkh => Some(&bytes[2..22]),
+            _ => None,
+        }
+    }
+}
+
+/// The keys of a bare multisig output script: `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`.
+#[derive(Clone, Debug)]
+pub struct Multisig {
+    pub required_sigs: u8,
+    pub pubkeys: Vec<Vec<u8>>,
+}
+
+impl Multisig {
+    /// Parses a bare multisig output script.  Returns None for any other script.
+    pub fn from_script(script_pubkey: &Script) -> Option<Self> {
+        if script_pubkey.as_bytes().last() != Some(&OP_CHECKMULTISIG.to_u8()) {
+            return None;
+        }
+        let instructions = script_pubkey
+            .instructions()
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        let (required_sigs, instructions) = instructions.split_first()?;
+        let (_, instructions) = instructions.split_last()?;
+        let (key_count, keys) = instructions.split_last()?;
+        let (required_sigs, key_count) = (small_int(required_sigs)?, small_int(key_count)?);
+
+        let pubkeys = keys
+            .iter()
+            .map(|key| match key {
+                Instruction::PushBytes(bytes) if bytes.len() == 33 || bytes.len() == 65 => Some(bytes.to_vec()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if pubkeys.len() != key_count as usize || required_sigs > key_count {
+            return None;
+        }
+
+        Some(Multisig { required_sigs, pubkeys })
+    }
+
+    /// Number of keys that are valid public keys.  Outputs that embed data in
+    /// multisig scripts often carry keys that are not points on the curve.
+    pub fn exposed_keys(&self) -> usize {
+        self.pubkeys
+            .iter()
+            .filter(|pubkey| PublicKey::from_slice(pubkey).is_ok())
+            .count()
+    }
+}
+
+/// Returns the value of an OP_1 to OP_16 instruction.
+fn small_int(instruction: &Instruction) -> Option<u8> {
+    match instruction {
+        Instruction::Op(op) if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+            Some(op.to_u8() - OP_PUSHNUM_1.to_u8() + 1)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the public key revealed by an input spending a P2PKH or P2WPKH output.
+/// Both carry `<signature> <pubkey>`, P2PKH in its scriptSig and P2WPKH in its witness.
+pub fn spent_pubkey(input: &TxIn) -> Option<&[u8]> {
+    let pubkey = if input.witness.is_empty() {
+        match input.script_sig.instructions().last() {
+            Some(Ok(Instruction::PushBytes(bytes))) => bytes,
+            _ => return None,
+        }
+    } else {
+        input.witness.last()?
+    };
+
+    // Compressed or uncompressed public keys only
+    match pubkey.len() {
+        33 | 65 => Some(pubkey),
+        _ => None,
+    }
 }
 
 use std::str::FromStr;
@@ -38,6 +645,8 @@ impl FromStr for BtcAddressType {
         match s.to_lowercase().as_str() {
             "p2pk" => Ok(BtcAddressType::P2PK),
             "p2tr" => Ok(BtcAddressType::P2TR),
+            "reused_pkh" => Ok(BtcAddressType::ReusedPkh),
+            "p2ms" => Ok(BtcAddressType::P2MS),
             _ => Err(format!("Unknown address type: {}", s))
         }
     }
@@ -68,3 +677,69 @@ pub async fn capture_p2pk_blocks_graph(block_height: usize) -> Result<(), AppErr
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto::common::bitcoin::blockdata::script::Builder;
+    use nakamoto::common::bitcoin::hashes::Hash;
+    use nakamoto::common::bitcoin::{Txid, Witness};
+
+    use crate::test_fixtures::{COMPRESSED_GENERATOR, UNCOMPRESSED_GENERATOR};
+
+    /// An input carrying `script_sig` and `witness`.
+    fn input(script_sig: Script, witness: Vec<Vec<u8>>) -> TxIn {
+        TxIn {
+            script_sig,
+            witness: Witness::from_vec(witness),
+            ..TxIn::default()
+        }
+    }
+
+    #[test]
+    fn cohort_heights_only_contain_coinbase_outputs() {
+        let listed = OutPoint::new(Txid::from_inner([2; 32]), 1);
+        let cohort = Cohort {
+            heights: HashSet::from([9]),
+            outpoints: HashSet::from([listed]),
+        };
+        let coinbase_output = OutPoint::new(Txid::from_inner([1; 32]), 0);
+        let other_output = OutPoint::new(Txid::from_inner([3; 32]), 0);
+
+        assert!(cohort.contains(&coinbase_output, Some(9)));
+        // A non-coinbase output created by block 9
+        assert!(!cohort.contains(&other_output, None));
+        assert!(!cohort.contains(&coinbase_output, Some(10)));
+        assert!(cohort.contains(&listed, None));
+    }
+
+    #[test]
+    fn spent_pubkey_is_the_last_push_of_a_p2pkh_script_sig() {
+        let signature = [0x30; 71];
+        for pubkey in [COMPRESSED_GENERATOR, UNCOMPRESSED_GENERATOR] {
+            let pubkey = PublicKey::from_str(pubkey).unwrap();
+            let script_sig = Builder::new().push_slice(&signature).push_key(&pubkey).into_script();
+            assert_eq!(spent_pubkey(&input(script_sig, Vec::new())), Some(&pubkey.to_bytes()[..]));
+        }
+
+        // Neither a signature alone, nor a push of another length, is a public key
+        let script_sig = Builder::new().push_slice(&signature).into_script();
+        assert_eq!(spent_pubkey(&input(script_sig, Vec::new())), None);
+        let script_sig = Builder::new().push_slice(&signature).push_slice(&[0x02; 32]).into_script();
+        assert_eq!(spent_pubkey(&input(script_sig, Vec::new())), None);
+        assert_eq!(spent_pubkey(&input(Script::new(), Vec::new())), None);
+    }
+
+    #[test]
+    fn spent_pubkey_is_the_last_element_of_a_p2wpkh_witness() {
+        let signature = vec![0x30; 72];
+        let pubkey = PublicKey::from_str(COMPRESSED_GENERATOR).unwrap().to_bytes();
+        let witness_input = input(Script::new(), vec![signature.clone(), pubkey.clone()]);
+        assert_eq!(spent_pubkey(&witness_input), Some(&pubkey[..]));
+
+        // The witness takes precedence over the script sig
+        let script_sig = Builder::new().push_slice(&[0x03; 33]).into_script();
+        assert_eq!(spent_pubkey(&input(script_sig, vec![signature.clone(), pubkey.clone()])), Some(&pubkey[..]));
+        assert_eq!(spent_pubkey(&input(Script::new(), vec![pubkey, signature])), None);
+    }
+}
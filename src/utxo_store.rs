@@ -0,0 +1,913 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use log::{info, warn};
+use nakamoto::common::bitcoin::OutPoint;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::Transactional;
+
+use crate::alerts::Alert;
+use crate::persistence::SQLitePersistence;
+use crate::util::{
+    BlockAggregateOutput, BtcAddressType, Multisig, SpendOutput, UtxoSubset, ValueBand, Vintage, VintageBuckets,
+};
+use crate::webhooks::OutboxMessage;
+use crate::AppError;
+
+/// Number of blocks for which undo data is kept.  Reorgs deeper than this can't be rolled back.
+pub const MAX_REORG_DEPTH: u64 = 100;
+
+/// An output tracked by the scanner.
+#[derive(Clone, Debug)]
+pub struct TrackedOutput {
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+    /// Whether the output was created by a coinbase transaction.
+    pub is_coinbase: bool,
+    /// The public key the output is locked to, unless the output only commits to its hash.
+    pub pubkey: Option<Vec<u8>>,
+    /// The hash160 of the public key, for P2PKH and P2WPKH outputs.
+    pub pubkey_hash: Option<Vec<u8>>,
+    /// The keys of a P2MS output.
+    pub multisig: Option<Multisig>,
+    /// Whether the output is in the cohort loaded from COHORT_FILE.
+    pub in_cohort: bool,
+}
+
+/// A tracked outpoint found unspent by `UtxoStore::get`.
+#[derive(Clone, Debug)]
+pub struct UnspentOutput {
+    pub value: i64,
+    /// Height and timestamp of the block that created the outpoint.
+    /// Unknown for outpoints copied from sled without their history.
+    pub block_height: Option<u64>,
+    pub block_time: Option<u32>,
+    /// The public key the outpoint is locked to and its origin, if they are known.
+    pub pubkey: Option<Vec<u8>>,
+    pub is_coinbase: Option<bool>,
+    /// Whether the outpoint was tagged as a member of the cohort loaded from COHORT_FILE.
+    pub in_cohort: bool,
+}
+
+/// Changes a single block makes to the set of tracked outpoints.
+#[derive(Debug, Default)]
+pub struct BlockDelta {
+    pub block_height: u64,
+    /// Timestamp of the block, recorded as the creation time of the outpoints it creates.
+    pub block_time: u32,
+    /// Outpoints created by the block and still unspent at the end of it.
+    pub created: HashMap<OutPoint, TrackedOutput>,
+    /// Outpoints created by earlier blocks and spent by this one.
+    pub spent: Vec<(OutPoint, UnspentOutput)>,
+    /// Every spend of a tracked outpoint by the block, including outpoints created by the block itself.
+    pub spend_events: Vec<SpendOutput>,
+    /// Public keys first revealed by spends in the block, keyed by their hash160.
+    pub revealed: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl BlockDelta {
+    pub fn new(block_height: u64, block_time: u32) -> Self {
+        BlockDelta {
+            block_height,
+            block_time,
+            ..Default::default()
+        }
+    }
+
+    /// Records a newly created outpoint.
+    pub fn create(&mut self, outpoint: OutPoint, output: TrackedOutput) {
+        self.created.insert(outpoint, output);
+    }
+
+    /// Net change in the number of tracked outpoints of each value band, and in the satoshis they hold, made by the block.
+    pub fn value_band_changes(&self) -> HashMap<ValueBand, (i64, i64)> {
+        let mut changes: HashMap<ValueBand, (i64, i64)> = HashMap::new();
+        for output in self.created.values() {
+            let change = changes.entry(ValueBand::of(output.value)).or_default();
+            change.0 += 1;
+            change.1 += output.value;
+        }
+        for (_, output) in self.spent.iter() {
+            let change = changes.entry(ValueBand::of(output.value)).or_default();
+            change.0 -= 1;
+            change.1 -= output.value;
+        }
+        changes
+    }
+
+    /// Net change in the number of tracked outpoints in `subset`, and in the satoshis they hold, made by the block.
+    pub fn subset_change(&self, subset: UtxoSubset) -> (i64, i64) {
+        let created = self
+            .created
+            .values()
+            .filter(|output| subset.contains(output.pubkey.as_deref(), Some(output.is_coinbase), output.in_cohort))
+            .map(|output| (1, output.value));
+        let spent = self
+            .spent
+            .iter()
+            .filter(|(_, output)| subset.contains(output.pubkey.as_deref(), output.is_coinbase, output.in_cohort))
+            .map(|(_, output)| (-1, -output.value));
+        created
+            .chain(spent)
+            .fold((0, 0), |(count, sats), (count_change, sats_change)| (count + count_change, sats + sats_change))
+    }
+
+    /// Net change in the number of tracked outpoints of each vintage, and in the satoshis they hold, made by the block.
+    /// Spent outpoints whose creation block isn't known aren't in any vintage.
+    pub fn vintage_changes(&self, buckets: &VintageBuckets) -> HashMap<Vintage, (i64, i64)> {
+        let mut changes: HashMap<Vintage, (i64, i64)> = HashMap::new();
+        let created_vintage = buckets.vintage(self.block_height, self.block_time);
+        for output in self.created.values() {
+            let change = changes.entry(created_vintage.clone()).or_default();
+            change.0 += 1;
+            change.1 += output.value;
+        }
+        for (_, output) in self.spent.iter() {
+            if let (Some(height), Some(time)) = (output.block_height, output.block_time) {
+                let change = changes.entry(buckets.vintage(height, time)).or_default();
+                change.0 -= 1;
+                change.1 -= output.value;
+            }
+        }
+        changes
+    }
+
+    /// Coin days destroyed by the block: the value in BTC times the age in days of every spent outpoint.
+    /// Outpoints whose creation time isn't known don't count.
+    pub fn coin_days_destroyed(&self) -> f64 {
+        self.spend_events
+            .iter()
+            .filter_map(|spend| spend.age_days.map(|age_days| spend.value as f64 / 100_000_000.0 * age_days))
+            .sum()
+    }
+
+    /// Records the spend of an outpoint returned by the store.
+    /// Outpoints created and spent within the same block never reach the store.
+    pub fn spend(&mut self, outpoint: OutPoint, output: UnspentOutput) {
+        if self.created.remove(&outpoint).is_none() {
+            self.spent.push((outpoint, output));
+        }
+    }
+}
+
+/// The changes a block makes for one tracked address type, along with the resulting aggregates.
+#[derive(Debug)]
+pub struct BlockCommit {
+    pub address_type: BtcAddressType,
+    pub delta: BlockDelta,
+    pub aggregate: BlockAggregateOutput,
+    /// Number of unspent outpoints of each vintage, and the satoshis they hold, after the block.
+    /// Empty for types that don't track vintages.
+    pub vintages: BTreeMap<Vintage, (i64, i64)>,
+    /// Number of unspent outpoints of each value band, and the satoshis they hold, after the block.
+    pub value_bands: BTreeMap<ValueBand, (i64, i64)>,
+    /// Alerts raised by the rules for the block.
+    pub alerts: Vec<Alert>,
+}
+
+/// Storage for the tracked outpoints and the block aggregates derived from them.
+/// Selected with the UTXO_STORE environment variable.
+///
+/// Outpoint records and aggregates are always kept in SQLite; the store only decides
+/// where outpoints are looked up while blocks are scanned.
+pub enum UtxoStore {
+    /// Outpoints looked up in sled (one store per tracked address type), records and aggregates in SQLite.
+    /// The two are committed one after the other and brought back in line by `reconcile` after a crash.
+    Sled {
+        utxos: HashMap<BtcAddressType, SledUtxoStore>,
+        sqlite: SQLitePersistence,
+    },
+    /// Everything in SQLite, committed in a single transaction per block.
+    Sqlite(SQLitePersistence),
+}
+
+impl UtxoStore {
+    /// Opens the sled store at `path` for every tracked address type.
+    pub fn open_sled(path: &str, sqlite: &SQLitePersistence) -> Result<Self, AppError> {
+        Ok(UtxoStore::Sled {
+            utxos: open_sled_stores(path)?,
+            sqlite: sqlite.clone(),
+        })
+    }
+
+    /// Returns `outpoint` if it is a tracked and unspent output of `address_type`.
+    /// Outpoints created earlier in the block being processed are looked up in `delta` first.
+    pub async fn get(
+        &self,
+        address_type: BtcAddressType,
+        delta: &BlockDelta,
+        outpoint: &OutPoint,
+    ) -> Result<Option<UnspentOutput>, AppError> {
+        if let Some(output) = delta.created.get(outpoint) {
+            return Ok(Some(UnspentOutput {
+                value: output.value,
+                block_height: Some(delta.block_height),
+                block_time: Some(delta.block_time),
+                pubkey: output.pubkey.clone(),
+                is_coinbase: Some(output.is_coinbase),
+                in_cohort: output.in_cohort,
+            }));
+        }
+        match self {
+            UtxoStore::Sled { utxos, sqlite } => {
+                let value = match utxos[&address_type].get(outpoint)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                // Sled only keeps values, the outpoint's origin comes from its SQLite record
+                let record = sqlite
+                    .get_unspent_output(address_type.as_str().to_string(), outpoint)
+                    .await?;
+                Ok(Some(UnspentOutput {
+                    value,
+                    block_height: record.as_ref().and_then(|record| record.block_height),
+                    block_time: record.as_ref().and_then(|record| record.block_time),
+                    pubkey: record.as_ref().and_then(|record| record.pubkey.clone()),
+                    is_coinbase: record.as_ref().and_then(|record| record.is_coinbase),
+                    in_cohort: record.is_some_and(|record| record.in_cohort),
+                }))
+            }
+            UtxoStore::Sqlite(sqlite) => Ok(sqlite
+                .get_unspent_output(address_type.as_str().to_string(), outpoint)
+                .await?),
+        }
+    }
+
+    /// Commits the outpoint changes of a block together with its aggregates and the outbox messages of its events.
+    pub async fn commit_block(&self, commits: &[BlockCommit], outbox: &[OutboxMessage]) -> Result<(), AppError> {
+        match self {
+            UtxoStore::Sled { utxos, sqlite } => {
+                // The UTXO store is committed first, so after a crash it is never behind SQLite
+                for commit in commits {
+                    utxos[&commit.address_type].apply_block(commit.delta.block_height, &commit.delta)?;
+                }
+                sqlite.commit_block(commits, outbox).await?;
+            }
+            UtxoStore::Sqlite(sqlite) => {
+                sqlite.commit_block(commits, outbox).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reverts all blocks above `fork_height`, for every tracked address type, and queues the outbox messages
+    /// announcing the reorg.
+    /// Fails without changing anything if a sled store lacks the undo data of any of those blocks.
+    pub async fn rollback_blocks_above(&self, fork_height: u64, outbox: &[OutboxMessage]) -> Result<(), AppError> {
+        if let UtxoStore::Sled { utxos, .. } = self {
+            // Stopping halfway through would leave the types at different blocks
+            for (address_type, utxos) in utxos.iter() {
+                if let Some(height) = utxos.first_missing_undo_above(fork_height)? {
+                    return Err(AppError::CustomError(format!(
+                        "{} UTXO store has no undo data for block {} and can't be rolled back to block {}. \
+                        It was created before undo data was kept: stop the scanner, delete the sled store \
+                        and the SQLite database, and rescan from genesis",
+                        address_type, height, fork_height
+                    )));
+                }
+            }
+        }
+
+        // SQLite is rolled back first, so after a crash the UTXO store is never behind it
+        self.sqlite().rollback_reorg(fork_height as i64, outbox).await?;
+        if let UtxoStore::Sled { utxos, .. } = self {
+            for utxos in utxos.values() {
+                utxos.rollback_blocks_above(fork_height)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the sled UTXO store and SQLite back to the same block after an unclean shutdown.
+    ///
+    /// Each block is committed to sled first and to SQLite second, and reorgs are rolled back from
+    /// SQLite first and from sled second, so after a crash sled can be ahead of SQLite; those blocks
+    /// are rolled back from sled.  Blocks above sled's tip, which stores written by older versions can
+    /// leave behind, are rolled back from SQLite so they get processed again.
+    /// An empty sled store, such as a new one next to an existing database, is rebuilt from SQLite.
+    /// The SQLite store commits atomically and needs no reconciliation.
+    pub async fn reconcile(&self) -> Result<(), AppError> {
+        match self {
+            UtxoStore::Sled { utxos, sqlite } => {
+                for (address_type, utxos) in utxos.iter() {
+                    reconcile_sled(*address_type, utxos, sqlite).await?;
+                    // Sled stores that predate outpoint records in SQLite
+                    import_sled_outputs(*address_type, utxos, sqlite).await?;
+                }
+                Ok(())
+            }
+            UtxoStore::Sqlite(_) => Ok(()),
+        }
+    }
+
+    /// Returns the SQLite database holding the records and aggregates.
+    pub fn sqlite(&self) -> &SQLitePersistence {
+        match self {
+            UtxoStore::Sled { sqlite, .. } => sqlite,
+            UtxoStore::Sqlite(sqlite) => sqlite,
+        }
+    }
+}
+
+/// Opens a sled store for every tracked address type in the sled database at `path`.
+fn open_sled_stores(path: &str) -> Result<HashMap<BtcAddressType, SledUtxoStore>, AppError> {
+    let db = sled::open(path)?;
+    BtcAddressType::TRACKED
+        .iter()
+        .map(|address_type| Ok((*address_type, SledUtxoStore::open(&db, *address_type)?)))
+        .collect()
+}
+
+async fn reconcile_sled(
+    address_type: BtcAddressType,
+    utxos: &SledUtxoStore,
+    sqlite: &SQLitePersistence,
+) -> Result<(), AppError> {
+    let sqlite_height = sqlite
+        .get_last_block_height(address_type.as_str().to_string())
+        .await?;
+
+    let store_height = match (utxos.tip_height()?, sqlite_height) {
+        (Some(store_height), _) => store_height as i64,
+        (None, None) => return Ok(()),
+        (None, Some(sqlite_height)) if !utxos.is_empty() && !utxos.has_undo_data() => {
+            // The store predates tip tracking, and undo data along with it
+            warn!(
+                "{} UTXO store has no recorded tip height, assuming it matches block {}. \
+                It has no undo data, so a reorg within the next {} blocks will stop the scanner \
+                until it is rescanned from genesis",
+                address_type, sqlite_height, MAX_REORG_DEPTH
+            );
+            utxos.set_tip_height(sqlite_height as u64)?;
+            return Ok(());
+        }
+        (None, Some(sqlite_height)) => {
+            // The store is new, or its rebuild was interrupted
+            return rebuild_sled(address_type, utxos, sqlite, sqlite_height).await;
+        }
+    };
+    let sqlite_height = sqlite_height.unwrap_or(-1);
+
+    if store_height > sqlite_height {
+        warn!(
+            "{} UTXO store is at block {} but aggregates end at block {}, rolling back UTXO store",
+            address_type, store_height, sqlite_height
+        );
+        for height in ((sqlite_height + 1)..=store_height).rev() {
+            utxos.rollback_block(height as u64)?;
+        }
+    } else if store_height < sqlite_height {
+        warn!(
+            "{} aggregates end at block {} but UTXO store is at block {}, rolling back SQLite to block {}",
+            address_type, sqlite_height, store_height, store_height
+        );
+        sqlite
+            .rollback_blocks_above(address_type.as_str().to_string(), store_height)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds an empty sled store from the outpoint records kept in SQLite, whose aggregates end at `sqlite_height`.
+/// Refuses if SQLite has no records of the outpoints its aggregates count, rather than rolling its history back.
+async fn rebuild_sled(
+    address_type: BtcAddressType,
+    utxos: &SledUtxoStore,
+    sqlite: &SQLitePersistence,
+    sqlite_height: i64,
+) -> Result<(), AppError> {
+    let outputs = sqlite.export_outputs(address_type.as_str().to_string()).await?;
+    if outputs.iter().all(|output| output.spent_height.is_some()) {
+        let aggregate = sqlite
+            .get_block_by_height(address_type.as_str().to_string(), sqlite_height)
+            .await?;
+        if let Some(aggregate) = aggregate.filter(|aggregate| aggregate.total_utxos > 0) {
+            return Err(AppError::CustomError(format!(
+                "{} UTXO store is empty, and SQLite has no records of the {} unspent outpoints its aggregates \
+                count at block {}. Restore the sled store, or delete the SQLite database and rescan from genesis",
+                address_type, aggregate.total_utxos, sqlite_height
+            )));
+        }
+    }
+
+    warn!(
+        "{} UTXO store is empty but aggregates end at block {}, rebuilding it from SQLite...",
+        address_type, sqlite_height
+    );
+    let unspent = utxos.rebuild(&outputs, sqlite_height as u64)?;
+    info!("Rebuilt {} UTXO store with {} unspent outpoint(s)", address_type, unspent);
+    Ok(())
+}
+
+/// Copies the outpoints of the sled store at `sled_path` into SQLite, so that UTXO_STORE
+/// can be switched to SQLite without rescanning from genesis.
+pub async fn migrate_sled_to_sqlite(sled_path: &str, sqlite: &SQLitePersistence) -> Result<(), AppError> {
+    for (address_type, utxos) in open_sled_stores(sled_path)?.iter() {
+        // Make sure sled is consistent with the aggregates before copying it
+        reconcile_sled(*address_type, utxos, sqlite).await?;
+        import_sled_outputs(*address_type, utxos, sqlite).await?;
+    }
+    Ok(())
+}
+
+/// Copies the outpoints of a sled store into SQLite.
+/// Outpoints created or spent within the last MAX_REORG_DEPTH blocks keep their heights,
+/// so those blocks can still be rolled back after the import.
+/// Does nothing if SQLite already tracks outpoints, so it only ever runs once.
+async fn import_sled_outputs(
+    address_type: BtcAddressType,
+    utxos: &SledUtxoStore,
+    sqlite: &SQLitePersistence,
+) -> Result<(), AppError> {
+    if utxos.is_empty()
+        || !sqlite
+            .utxos_is_empty(address_type.as_str().to_string())
+            .await?
+    {
+        return Ok(());
+    }
+
+    info!("Copying {} outpoints from sled store to SQLite...", address_type);
+    let outputs = utxos.migrated_outputs()?;
+    let imported = sqlite
+        .import_outputs(address_type.as_str().to_string(), &outputs)
+        .await?;
+    info!("Copied {} {} outpoint(s) from sled to SQLite", imported, address_type);
+
+    Ok(())
+}
+
+/// An outpoint copied from the sled store by `migrate_sled_to_sqlite`.
+#[derive(Debug)]
+pub struct MigratedOutput {
+    pub outpoint: OutPoint,
+    pub value: i64,
+    pub block_height: Option<i64>,
+    pub spent_height: Option<i64>,
+}
+
+impl MigratedOutput {
+    fn new(outpoint: OutPoint, value: i64) -> Self {
+        MigratedOutput {
+            outpoint,
+            value,
+            block_height: None,
+            spent_height: None,
+        }
+    }
+}
+
+/// Parses a sled key of the form `txid:vout`.
+fn parse_outpoint(key: &[u8]) -> Result<OutPoint, AppError> {
+    let key = String::from_utf8_lossy(key);
+    OutPoint::from_str(&key)
+        .map_err(|e| AppError::CustomError(format!("Invalid outpoint {} in sled store: {}", key, e)))
+}
+
+/// Undo data kept by the sled store for each block.
+/// Outpoints are keyed as `txid:vout` and map to their value in satoshis.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SledUndo {
+    created: HashMap<String, i64>,
+    spent: Vec<(String, i64)>,
+}
+
+/// Key under which the height of the last applied block is stored in the meta tree.
+const TIP_HEIGHT_KEY: &[u8] = b"tip_height";
+
+/// Set of tracked outpoints of one address type, persisted in sled along with per-block undo data.
+///
+/// Each block's changes, its undo data and the new tip height are committed in a single
+/// sled transaction and flushed to disk before returning, so the store is always at a
+/// block boundary whose height is known.
+pub struct SledUtxoStore {
+    db: sled::Db,
+    utxos: sled::Tree,
+    undo: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl SledUtxoStore {
+    pub fn open(db: &sled::Db, address_type: BtcAddressType) -> Result<Self, AppError> {
+        // P2PK outpoints were tracked before any other type and live in the default tree
+        let utxos = match address_type {
+            BtcAddressType::P2PK => (**db).clone(),
+            _ => db.open_tree(format!("{}_utxos", address_type))?,
+        };
+        let undo = db.open_tree(format!("{}_undo", address_type))?;
+        let meta = db.open_tree(format!("{}_meta", address_type))?;
+        Ok(SledUtxoStore {
+            db: db.clone(),
+            utxos,
+            undo,
+            meta,
+        })
+    }
+
+    /// Returns the value of `outpoint` if it is tracked and unspent.
+    pub fn get(&self, outpoint: &OutPoint) -> Result<Option<i64>, AppError> {
+        Ok(self
+            .utxos
+            .get(outpoint.to_string().as_bytes())?
+            .map(|value_bytes| i64::from_le_bytes(value_bytes.as_ref().try_into().unwrap())))
+    }
+
+    /// Returns all tracked outpoints along with those spent by the blocks that still have undo data.
+    /// Creation and spend heights are known only for blocks that still have undo data.
+    pub fn migrated_outputs(&self) -> Result<Vec<MigratedOutput>, AppError> {
+        let mut outputs: HashMap<OutPoint, MigratedOutput> = HashMap::new();
+        for entry in self.utxos.iter() {
+            let (key, value_bytes) = entry?;
+            let outpoint = parse_outpoint(&key)?;
+            let value = i64::from_le_bytes(value_bytes.as_ref().try_into().unwrap());
+            outputs.insert(outpoint, MigratedOutput::new(outpoint, value));
+        }
+
+        for entry in self.undo.iter() {
+            let (height_bytes, undo_bytes) = entry?;
+            let height = u64::from_be_bytes(height_bytes.as_ref().try_into().unwrap()) as i64;
+            let undo: SledUndo =
+                serde_json::from_slice(&undo_bytes).map_err(|e| AppError::Other(Box::new(e)))?;
+
+            for (key, value) in undo.created.iter() {
+                let outpoint = parse_outpoint(key.as_bytes())?;
+                outputs
+                    .entry(outpoint)
+                    .or_insert_with(|| MigratedOutput::new(outpoint, *value))
+                    .block_height = Some(height);
+            }
+            for (key, value) in undo.spent.iter() {
+                let outpoint = parse_outpoint(key.as_bytes())?;
+                outputs
+                    .entry(outpoint)
+                    .or_insert_with(|| MigratedOutput::new(outpoint, *value))
+                    .spent_height = Some(height);
+            }
+        }
+
+        Ok(outputs.into_values().collect())
+    }
+
+    /// Returns the height of the last applied block.
+    /// Stores created before the tip height was recorded return None.
+    pub fn tip_height(&self) -> Result<Option<u64>, AppError> {
+        Ok(self
+            .meta
+            .get(TIP_HEIGHT_KEY)?
+            .map(|height_bytes| u64::from_be_bytes(height_bytes.as_ref().try_into().unwrap())))
+    }
+
+    /// Records `height` as the last applied block without changing any outpoints.
+    pub fn set_tip_height(&self, height: u64) -> Result<(), AppError> {
+        self.meta.insert(TIP_HEIGHT_KEY, &height.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Returns true if the store doesn't track any outpoints.
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    /// Returns true if the store keeps undo data for any block.  Stores that predate undo data never do.
+    pub fn has_undo_data(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    /// Replaces the contents of the store with the unspent `outputs`, as of block `tip_height`,
+    /// and returns how many there are.  Undo data for the last MAX_REORG_DEPTH blocks is derived from
+    /// the creation and spend heights of the outputs.
+    pub fn rebuild(&self, outputs: &[MigratedOutput], tip_height: u64) -> Result<usize, AppError> {
+        let mut undo: BTreeMap<u64, SledUndo> = (tip_height.saturating_sub(MAX_REORG_DEPTH - 1)..=tip_height)
+            .map(|height| (height, SledUndo::default()))
+            .collect();
+        let mut utxos = sled::Batch::default();
+        let mut unspent = 0;
+        for output in outputs {
+            let key = output.outpoint.to_string();
+            if output.spent_height.is_none() {
+                utxos.insert(key.as_bytes(), output.value.to_le_bytes().to_vec());
+                unspent += 1;
+            }
+            if let Some(block_undo) = output.block_height.and_then(|height| undo.get_mut(&(height as u64))) {
+                block_undo.created.insert(key.clone(), output.value);
+            }
+            if let Some(block_undo) = output.spent_height.and_then(|height| undo.get_mut(&(height as u64))) {
+                block_undo.spent.push((key, output.value));
+            }
+        }
+        let mut undo_batch = sled::Batch::default();
+        for (height, block_undo) in undo.iter() {
+            let undo_bytes = serde_json::to_vec(block_undo).map_err(|e| AppError::Other(Box::new(e)))?;
+            undo_batch.insert(height.to_be_bytes().to_vec(), undo_bytes);
+        }
+
+        // Undo data is written first, which tells an interrupted rebuild apart from a store that predates undo data
+        self.utxos.clear()?;
+        self.undo.clear()?;
+        self.undo.apply_batch(undo_batch)?;
+        self.utxos.apply_batch(utxos)?;
+        self.set_tip_height(tip_height)?;
+        Ok(unspent)
+    }
+
+    /// Applies the changes made by the block at `height` and keeps them as undo data.
+    pub fn apply_block(&self, height: u64, delta: &BlockDelta) -> Result<(), AppError> {
+        let undo = SledUndo {
+            created: delta
+                .created
+                .iter()
+                .map(|(outpoint, output)| (outpoint.to_string(), output.value))
+                .collect(),
+            spent: delta
+                .spent
+                .iter()
+                .map(|(outpoint, output)| (outpoint.to_string(), output.value))
+                .collect(),
+        };
+        let undo_bytes = serde_json::to_vec(&undo).map_err(|e| AppError::Other(Box::new(e)))?;
+
+        (&self.utxos, &self.undo, &self.meta)
+            .transaction(|(utxos, undo_tree, meta)| -> ConflictableTransactionResult<(), ()> {
+                for (outpoint, value) in undo.created.iter() {
+                    utxos.insert(outpoint.as_bytes(), value.to_le_bytes().to_vec())?;
+                }
+                for (outpoint, _) in undo.spent.iter() {
+                    utxos.remove(outpoint.as_bytes())?;
+                }
+
+                undo_tree.insert(&height.to_be_bytes(), undo_bytes.as_slice())?;
+                if height >= MAX_REORG_DEPTH {
+                    undo_tree.remove(&(height - MAX_REORG_DEPTH).to_be_bytes())?;
+                }
+                meta.insert(TIP_HEIGHT_KEY, &height.to_be_bytes())?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the lowest block above `fork_height`, up to the tip, that has no undo data.
+    pub fn first_missing_undo_above(&self, fork_height: u64) -> Result<Option<u64>, AppError> {
+        let tip_height = match self.tip_height()? {
+            Some(tip_height) => tip_height,
+            None => return Ok(None),
+        };
+        for height in (fork_height + 1)..=tip_height {
+            if !self.undo.contains_key(height.to_be_bytes())? {
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reverts all blocks above `fork_height`, starting from the tip.
+    pub fn rollback_blocks_above(&self, fork_height: u64) -> Result<(), AppError> {
+        while let Some(tip_height) = self.tip_height()? {
+            if tip_height <= fork_height {
+                break;
+            }
+            self.rollback_block(tip_height)?;
+        }
+        Ok(())
+    }
+
+    /// Reverts the changes made by the block at `height`, which must be the tip.
+    pub fn rollback_block(&self, height: u64) -> Result<(), AppError> {
+        let undo_bytes = self.undo.get(height.to_be_bytes())?.ok_or_else(|| {
+            AppError::CustomError(format!("No undo data for block {}", height))
+        })?;
+        let undo: SledUndo =
+            serde_json::from_slice(&undo_bytes).map_err(|e| AppError::Other(Box::new(e)))?;
+
+        (&self.utxos, &self.undo, &self.meta)
+            .transaction(|(utxos, undo_tree, meta)| -> ConflictableTransactionResult<(), ()> {
+                for (outpoint, _) in undo.created.iter() {
+                    utxos.remove(outpoint.as_bytes())?;
+                }
+                for (outpoint, value) in undo.spent.iter() {
+                    utxos.insert(outpoint.as_bytes(), value.to_le_bytes().to_vec())?;
+                }
+
+                undo_tree.remove(&height.to_be_bytes())?;
+                match height.checked_sub(1) {
+                    Some(tip_height) => meta.insert(TIP_HEIGHT_KEY, &tip_height.to_be_bytes())?,
+                    None => meta.remove(TIP_HEIGHT_KEY)?,
+                };
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
+
+fn transaction_error(error: TransactionError<()>) -> AppError {
+    match error {
+        TransactionError::Storage(e) => AppError::SledError(e),
+        TransactionError::Abort(()) => AppError::CustomError("sled transaction aborted".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto::common::bitcoin::hashes::Hash;
+    use nakamoto::common::bitcoin::Txid;
+
+    use crate::test_fixtures;
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_inner([n; 32]), 0)
+    }
+
+    /// A P2PK block commit creating and spending 1 BTC outpoints, with `totals` outpoints after it.
+    fn p2pk_commit(height: u64, created: &[OutPoint], spent: &[OutPoint], totals: u32) -> BlockCommit {
+        let mut delta = BlockDelta::new(height, test_fixtures::GENESIS_TIME + height as u32 * 600);
+        for outpoint in created {
+            let script_pubkey = test_fixtures::p2pk_script();
+            delta.create(
+                *outpoint,
+                TrackedOutput {
+                    value: 100_000_000,
+                    pubkey: Some(script_pubkey.as_bytes()[1..34].to_vec()),
+                    script_pubkey: script_pubkey.to_bytes(),
+                    is_coinbase: false,
+                    pubkey_hash: None,
+                    multisig: None,
+                    in_cohort: false,
+                },
+            );
+        }
+        for outpoint in spent {
+            delta.spend(
+                *outpoint,
+                UnspentOutput {
+                    value: 100_000_000,
+                    block_height: None,
+                    block_time: None,
+                    pubkey: None,
+                    is_coinbase: Some(false),
+                    in_cohort: false,
+                },
+            );
+        }
+        BlockCommit {
+            address_type: BtcAddressType::P2PK,
+            delta,
+            aggregate: BlockAggregateOutput::new(
+                "2009-01-03".to_string(),
+                height as usize,
+                format!("{:064x}", height),
+                totals,
+                totals as f64 * 100_000_000.0,
+            ),
+            vintages: BTreeMap::new(),
+            value_bands: BTreeMap::new(),
+            alerts: Vec::new(),
+        }
+    }
+
+    async fn temporary_sled_store() -> (UtxoStore, tempfile::TempDir) {
+        let (sqlite, dir) = test_fixtures::temporary_sqlite().await;
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = BtcAddressType::TRACKED
+            .iter()
+            .map(|address_type| (*address_type, SledUtxoStore::open(&db, *address_type).unwrap()))
+            .collect();
+        (UtxoStore::Sled { utxos, sqlite }, dir)
+    }
+
+    async fn p2pk_height(store: &UtxoStore) -> (Option<u64>, Option<i64>) {
+        let sled_height = match store {
+            UtxoStore::Sled { utxos, .. } => utxos[&BtcAddressType::P2PK].tip_height().unwrap(),
+            UtxoStore::Sqlite(_) => None,
+        };
+        let sqlite_height = store
+            .sqlite()
+            .get_last_block_height(BtcAddressType::P2PK.as_str().to_string())
+            .await
+            .unwrap();
+        (sled_height, sqlite_height)
+    }
+
+    /// Commits blocks 1 to 3 to both stores: block 2 spends the output block 1 created, block 3 creates another.
+    async fn commit_three_blocks(store: &UtxoStore) {
+        store.commit_block(&[p2pk_commit(1, &[outpoint(1)], &[], 1)], &[]).await.unwrap();
+        store.commit_block(&[p2pk_commit(2, &[outpoint(2)], &[outpoint(1)], 1)], &[]).await.unwrap();
+        store.commit_block(&[p2pk_commit(3, &[outpoint(3)], &[], 2)], &[]).await.unwrap();
+    }
+
+    fn p2pk_sled(store: &UtxoStore) -> &SledUtxoStore {
+        match store {
+            UtxoStore::Sled { utxos, .. } => &utxos[&BtcAddressType::P2PK],
+            UtxoStore::Sqlite(_) => unreachable!(),
+        }
+    }
+
+    async fn p2pk_total_utxos(store: &UtxoStore, height: i64) -> Option<u32> {
+        let aggregate = store
+            .sqlite()
+            .get_block_by_height(BtcAddressType::P2PK.as_str().to_string(), height)
+            .await
+            .unwrap();
+        aggregate.map(|aggregate| aggregate.total_utxos)
+    }
+
+    #[tokio::test]
+    async fn reconcile_rolls_back_sqlite_blocks_missing_from_sled() {
+        let (store, _dir) = temporary_sled_store().await;
+        commit_three_blocks(&store).await;
+        // An interrupted reorg rolled block 3 back from sled only
+        p2pk_sled(&store).rollback_block(3).unwrap();
+
+        store.reconcile().await.unwrap();
+        assert_eq!(p2pk_height(&store).await, (Some(2), Some(2)));
+        assert_eq!(p2pk_total_utxos(&store, 2).await, Some(1));
+        assert_eq!(p2pk_total_utxos(&store, 3).await, None);
+        assert_eq!(p2pk_sled(&store).get(&outpoint(3)).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reconcile_rolls_back_sled_blocks_missing_from_sqlite() {
+        let (store, _dir) = temporary_sled_store().await;
+        commit_three_blocks(&store).await;
+        // A crash between the sled and the SQLite commit of block 4
+        let block_4 = p2pk_commit(4, &[outpoint(4)], &[outpoint(2)], 2);
+        p2pk_sled(&store).apply_block(4, &block_4.delta).unwrap();
+
+        store.reconcile().await.unwrap();
+        assert_eq!(p2pk_height(&store).await, (Some(3), Some(3)));
+        assert_eq!(p2pk_total_utxos(&store, 3).await, Some(2));
+        let sled = p2pk_sled(&store);
+        assert_eq!(sled.get(&outpoint(4)).unwrap(), None);
+        assert_eq!(sled.get(&outpoint(2)).unwrap(), Some(100_000_000));
+    }
+
+    #[tokio::test]
+    async fn reconcile_rebuilds_an_empty_sled_store_from_sqlite() {
+        let (store, _dir) = temporary_sled_store().await;
+        commit_three_blocks(&store).await;
+        let (empty_store, _empty_dir) = temporary_sled_store().await;
+        let UtxoStore::Sled { utxos, .. } = empty_store else { unreachable!() };
+        let store = UtxoStore::Sled { utxos, sqlite: store.sqlite().clone() };
+
+        store.reconcile().await.unwrap();
+        assert_eq!(p2pk_height(&store).await, (Some(3), Some(3)));
+        assert_eq!(p2pk_total_utxos(&store, 3).await, Some(2));
+        let sled = p2pk_sled(&store);
+        assert_eq!(sled.get(&outpoint(1)).unwrap(), None);
+        assert_eq!(sled.get(&outpoint(2)).unwrap(), Some(100_000_000));
+        assert_eq!(sled.get(&outpoint(3)).unwrap(), Some(100_000_000));
+
+        // The rebuilt undo data rolls blocks back like the original
+        store.rollback_blocks_above(1, &[]).await.unwrap();
+        assert_eq!(p2pk_height(&store).await, (Some(1), Some(1)));
+        assert_eq!(p2pk_total_utxos(&store, 1).await, Some(1));
+        let sled = p2pk_sled(&store);
+        assert_eq!(sled.get(&outpoint(1)).unwrap(), Some(100_000_000));
+        assert_eq!(sled.get(&outpoint(2)).unwrap(), None);
+        assert_eq!(sled.get(&outpoint(3)).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reconcile_refuses_an_empty_sled_store_when_sqlite_has_no_outpoint_records() {
+        let (store, _dir) = temporary_sled_store().await;
+        // Aggregates counting an outpoint that SQLite has no record of
+        store.sqlite().commit_block(&[p2pk_commit(1, &[], &[], 1)], &[]).await.unwrap();
+
+        let error = store.reconcile().await.unwrap_err();
+        assert!(error.to_string().contains("no records of the 1 unspent outpoints"), "{}", error);
+        assert_eq!(p2pk_height(&store).await, (None, Some(1)));
+    }
+
+    #[tokio::test]
+    async fn rollback_without_undo_data_fails_before_changing_anything() {
+        let (store, _dir) = temporary_sled_store().await;
+        let UtxoStore::Sled { utxos, .. } = &store else { unreachable!() };
+        // A store from before undo data: outpoints and, once reconciled, a tip height only
+        utxos[&BtcAddressType::P2PK]
+            .utxos
+            .insert(outpoint(1).to_string().as_bytes(), &100_000_000i64.to_le_bytes())
+            .unwrap();
+        for utxos in utxos.values() {
+            utxos.set_tip_height(5).unwrap();
+        }
+        store.commit_block(&[p2pk_commit(6, &[outpoint(2)], &[outpoint(1)], 1)], &[]).await.unwrap();
+
+        let error = store.rollback_blocks_above(4, &[]).await.unwrap_err();
+        assert!(error.to_string().contains("no undo data for block 5"), "{}", error);
+        assert_eq!(p2pk_height(&store).await, (Some(6), Some(6)));
+        assert_eq!(utxos[&BtcAddressType::P2PK].get(&outpoint(1)).unwrap(), None);
+
+        // Blocks that have undo data still roll back
+        store.rollback_blocks_above(5, &[]).await.unwrap();
+        assert_eq!(p2pk_height(&store).await, (Some(5), None));
+        assert_eq!(utxos[&BtcAddressType::P2PK].get(&outpoint(1)).unwrap(), Some(100_000_000));
+    }
+}
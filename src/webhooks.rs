@@ -0,0 +1,580 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use lettre::SmtpTransport;
+use log::{error, info, warn};
+use nakamoto::common::bitcoin::hashes::hex::ToHex;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::email::{self, EmailConfig, MAILTO_PREFIX};
+use crate::persistence::SQLitePersistence;
+use crate::util::BtcAddressType;
+use crate::AppError;
+
+/// Longest wait between two attempts to deliver the same event.
+const MAX_RETRY_DELAY_SECONDS: i64 = 3600;
+/// How often the outbox is checked for deliveries that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of deliveries to one URL read from the outbox at a time.
+const DELIVERY_BATCH_SIZE: i64 = 100;
+/// Longest a webhook request may take before it fails and is retried.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Kinds of events delivered to webhooks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// The aggregates of a newly scanned block, for one address type.
+    Aggregate,
+    /// The spend of a tracked output.
+    Spend,
+    /// An alert raised by a rule.
+    Alert,
+    /// Blocks rolled back by a chain reorganization.
+    Reorg,
+}
+
+impl WebhookEvent {
+    pub const ALL: &'static [WebhookEvent] =
+        &[WebhookEvent::Aggregate, WebhookEvent::Spend, WebhookEvent::Alert, WebhookEvent::Reorg];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Aggregate => "aggregate",
+            WebhookEvent::Spend => "spend",
+            WebhookEvent::Alert => "alert",
+            WebhookEvent::Reorg => "reorg",
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aggregate" => Ok(WebhookEvent::Aggregate),
+            "spend" => Ok(WebhookEvent::Spend),
+            "alert" => Ok(WebhookEvent::Alert),
+            "reorg" => Ok(WebhookEvent::Reorg),
+            _ => Err(format!("Unknown webhook event: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An event waiting in the outbox to be delivered to one URL.
+/// Alert emails share the outbox, with the recipients in a `mailto:` URL.
+#[derive(Clone, Debug)]
+pub struct OutboxMessage {
+    pub url: String,
+    pub event: String,
+    /// JSON body of the request.
+    pub body: String,
+}
+
+/// A message read back from the outbox, with the number of failed attempts to deliver it so far.
+#[derive(Clone, Debug)]
+pub struct QueuedWebhook {
+    pub id: i64,
+    pub attempts: u32,
+    pub message: OutboxMessage,
+}
+
+/// Where and how events are delivered.  Read from the WEBHOOK_* environment variables.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub events: Vec<WebhookEvent>,
+    /// Key of the HMAC-SHA256 signature sent with every delivery, if set.
+    pub secret: Option<String>,
+    /// Deliveries are given up after this many failed attempts.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled after every further failure.
+    pub retry_base_seconds: i64,
+    /// Events of blocks older than this, such as those scanned while catching up with the chain, aren't delivered.
+    pub max_block_age_seconds: Option<i64>,
+}
+
+impl WebhookConfig {
+    /// Reads WEBHOOK_URLS and WEBHOOK_EVENTS, comma separated lists of URLs and event types,
+    /// along with WEBHOOK_SECRET, WEBHOOK_MAX_ATTEMPTS, WEBHOOK_RETRY_BASE_SECONDS and WEBHOOK_MAX_BLOCK_AGE_HOURS.
+    /// Webhooks are disabled when WEBHOOK_URLS isn't set.
+    pub fn from_env() -> Result<Self, AppError> {
+        let urls = env::var("WEBHOOK_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let events = match env::var("WEBHOOK_EVENTS") {
+            Ok(events) => events
+                .split(',')
+                .map(|event| event.trim().parse::<WebhookEvent>().map_err(AppError::CustomError))
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => WebhookEvent::ALL.to_vec(),
+        };
+
+        let max_attempts = match env::var("WEBHOOK_MAX_ATTEMPTS") {
+            Ok(max_attempts) => max_attempts.trim().parse().map_err(|_| {
+                AppError::CustomError(format!("WEBHOOK_MAX_ATTEMPTS must be a number: {}", max_attempts))
+            })?,
+            Err(_) => 10,
+        };
+        let retry_base_seconds = match env::var("WEBHOOK_RETRY_BASE_SECONDS") {
+            Ok(seconds) => seconds.trim().parse().map_err(|_| {
+                AppError::CustomError(format!("WEBHOOK_RETRY_BASE_SECONDS must be a number: {}", seconds))
+            })?,
+            Err(_) => 2,
+        };
+        let max_block_age_hours: i64 = match env::var("WEBHOOK_MAX_BLOCK_AGE_HOURS") {
+            Ok(hours) => hours.trim().parse().map_err(|_| {
+                AppError::CustomError(format!("WEBHOOK_MAX_BLOCK_AGE_HOURS must be a number: {}", hours))
+            })?,
+            Err(_) => 24,
+        };
+
+        Ok(WebhookConfig {
+            urls,
+            events,
+            secret: env::var("WEBHOOK_SECRET").ok(),
+            max_attempts,
+            retry_base_seconds,
+            // 0 delivers the events of every block
+            max_block_age_seconds: (max_block_age_hours > 0).then_some(max_block_age_hours * 3600),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.urls.is_empty()
+    }
+
+    /// Returns true if the events of a block with timestamp `block_time` are delivered.
+    pub fn delivers_block(&self, block_time: u32) -> bool {
+        self.is_enabled() && self.is_recent(block_time)
+    }
+
+    /// Returns true if a block with timestamp `block_time` is recent enough for its events to be delivered.
+    pub fn is_recent(&self, block_time: u32) -> bool {
+        self.max_block_age_seconds
+            .is_none_or(|max_age| chrono::Utc::now().timestamp() - block_time as i64 <= max_age)
+    }
+
+    /// Returns the messages delivering an event to every URL, or none if the event type isn't subscribed to.
+    pub fn messages(
+        &self,
+        event: WebhookEvent,
+        address_type: Option<BtcAddressType>,
+        data: &impl Serialize,
+    ) -> Vec<OutboxMessage> {
+        if !self.events.contains(&event) {
+            return Vec::new();
+        }
+        let body = json!({
+            "event": event.as_str(),
+            "address_type": address_type.as_ref().map(BtcAddressType::as_str),
+            "data": data,
+        })
+        .to_string();
+        self.urls
+            .iter()
+            .map(|url| OutboxMessage {
+                url: url.clone(),
+                event: event.as_str().to_string(),
+                body: body.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns how long to wait before retrying a delivery that has failed `attempts` times.
+    fn retry_delay_seconds(&self, attempts: u32) -> i64 {
+        let exponent = attempts.saturating_sub(1).min(30);
+        self.retry_base_seconds
+            .saturating_mul(1 << exponent)
+            .min(MAX_RETRY_DELAY_SECONDS)
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of `body` keyed with `secret`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().to_hex()
+}
+
+/// Posts a message to its URL.  Any response other than 2xx is a failure.
+fn post(agent: &ureq::Agent, config: &WebhookConfig, webhook: &QueuedWebhook) -> Result<(), String> {
+    let mut request = agent
+        .post(&webhook.message.url)
+        .set("Content-Type", "application/json")
+        .set("X-Gabriel-Event", &webhook.message.event)
+        .set("X-Gabriel-Delivery", &webhook.id.to_string());
+    if let Some(secret) = &config.secret {
+        request = request.set("X-Gabriel-Signature", &format!("sha256={}", sign(secret, &webhook.message.body)));
+    }
+    match request.send_string(&webhook.message.body) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, _)) => Err(format!("HTTP status {}", code)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Delivers the messages queued in the outbox until the process exits.
+///
+/// Every destination, a webhook URL or the recipients of the alert emails, gets its own task delivering its
+/// messages in the order they were queued, so that a slow or unreachable endpoint only holds up its own.
+/// Each request times out after DELIVERY_TIMEOUT.
+/// Delivered messages are removed from the outbox.  Failed deliveries, including emails, are retried with
+/// exponential backoff and given up after the `max_attempts` of their config, staying in the outbox with
+/// their last error.
+/// Messages still queued when the process exits are delivered after the next start.
+pub async fn run_delivery(sqlite: SQLitePersistence, config: &'static WebhookConfig, email_config: &'static EmailConfig) {
+    info!(
+        "Delivering webhooks to {} URL(s) and alert emails to {} recipient(s)",
+        config.urls.len(),
+        email_config.recipients.len()
+    );
+    let agent = ureq::AgentBuilder::new().timeout(DELIVERY_TIMEOUT).build();
+    let mailer = match email_config.transport() {
+        Ok(mailer) => Some(mailer),
+        Err(e) => {
+            error!("Alert emails can't be sent: {}", e);
+            None
+        }
+    };
+
+    // Messages left over from the last run may be for destinations that are no longer configured
+    let mut urls = config.urls.clone();
+    if email_config.is_enabled() {
+        urls.push(email_config.url());
+    }
+    match sqlite.get_webhook_urls().await {
+        Ok(queued_urls) => urls.extend(queued_urls),
+        Err(e) => error!("Failed to read the webhook outbox: {}", e),
+    }
+    urls.sort();
+    urls.dedup();
+
+    let deliveries = urls
+        .into_iter()
+        .map(|url| {
+            let destination = Destination {
+                url,
+                sqlite: sqlite.clone(),
+                agent: agent.clone(),
+                mailer: mailer.clone(),
+                config,
+                email_config,
+            };
+            tokio::spawn(destination.run())
+        })
+        .collect::<Vec<_>>();
+    for delivery in deliveries {
+        if let Err(e) = delivery.await {
+            error!("Webhook delivery stopped: {}", e);
+        }
+    }
+}
+
+/// A URL the messages of the outbox are delivered to.
+struct Destination {
+    url: String,
+    sqlite: SQLitePersistence,
+    agent: ureq::Agent,
+    mailer: Option<SmtpTransport>,
+    config: &'static WebhookConfig,
+    email_config: &'static EmailConfig,
+}
+
+impl Destination {
+    /// Delivers the messages queued for the URL, oldest first, until the process exits.
+    async fn run(self) {
+        loop {
+            let now = chrono::Utc::now().timestamp();
+            let due = match self
+                .sqlite
+                .get_due_webhooks(&self.url, now, self.max_attempts(), DELIVERY_BATCH_SIZE)
+                .await
+            {
+                Ok(due) => due,
+                Err(e) => {
+                    error!("Failed to read the webhook outbox: {}", e);
+                    Vec::new()
+                }
+            };
+            if due.is_empty() {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+
+            for webhook in due {
+                self.deliver(webhook).await;
+            }
+        }
+    }
+
+    /// Deliveries to the URL are given up after this many failed attempts.
+    fn max_attempts(&self) -> u32 {
+        if self.url.starts_with(MAILTO_PREFIX) {
+            self.email_config.max_attempts
+        } else {
+            self.config.max_attempts
+        }
+    }
+
+    /// Makes one attempt to deliver a message, and removes it from the outbox or records the failure.
+    async fn deliver(&self, webhook: QueuedWebhook) {
+        let agent = self.agent.clone();
+        let mailer = self.mailer.clone();
+        let config = self.config;
+        let email_config = self.email_config;
+        let delivery = webhook.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            if delivery.message.url.starts_with(MAILTO_PREFIX) {
+                let mailer = mailer.ok_or("SMTP isn't configured")?;
+                email::send(&mailer, email_config, &delivery.message)
+            } else {
+                post(&agent, config, &delivery)
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+        let recorded = match result {
+            Ok(()) => self.sqlite.delete_webhook(webhook.id).await,
+            Err(e) => {
+                let attempts = webhook.attempts + 1;
+                if attempts >= self.max_attempts() {
+                    error!(
+                        "Giving up on {} webhook {} to {} after {} attempts: {}",
+                        webhook.message.event, webhook.id, webhook.message.url, attempts, e
+                    );
+                } else {
+                    warn!(
+                        "Failed to deliver {} webhook {} to {} (attempt {}): {}",
+                        webhook.message.event, webhook.id, webhook.message.url, attempts, e
+                    );
+                }
+                let next_attempt_at = chrono::Utc::now().timestamp() + self.config.retry_delay_seconds(attempts);
+                self.sqlite.record_webhook_failure(webhook.id, attempts, next_attempt_at, &e).await
+            }
+        };
+        if let Err(e) = recorded {
+            error!("Failed to update webhook {} in the outbox: {}", webhook.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use crate::alerts::{Alert, Severity};
+    use crate::email::SmtpSecurity;
+    use crate::test_fixtures::{self, serve_http, HttpRequest};
+
+    const SECRET: &str = "secret";
+
+    /// Requests received by a test server, with when each was answered.
+    type ReceivedRequests = Arc<Mutex<Vec<(Instant, HttpRequest)>>>;
+
+    fn webhook_config(urls: Vec<String>) -> &'static WebhookConfig {
+        Box::leak(Box::new(WebhookConfig {
+            urls,
+            events: WebhookEvent::ALL.to_vec(),
+            secret: Some(SECRET.to_string()),
+            max_attempts: 3,
+            retry_base_seconds: 2,
+            max_block_age_seconds: None,
+        }))
+    }
+
+    fn disabled_email_config() -> &'static EmailConfig {
+        Box::leak(Box::new(EmailConfig {
+            smtp_host: "localhost".to_string(),
+            smtp_port: 25,
+            security: SmtpSecurity::Plain,
+            credentials: None,
+            from: None,
+            recipients: Vec::new(),
+            min_severity: Severity::Critical,
+            subject_template: String::new(),
+            block_url_template: String::new(),
+            max_attempts: 10,
+            max_block_age_seconds: None,
+        }))
+    }
+
+    /// Serves webhook requests after `delay`, and answers them with the statuses in turn.
+    /// Every request after the last status is answered with 200.
+    fn serve_webhooks(statuses: Vec<u16>, delay: Duration) -> (String, ReceivedRequests) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        let url = serve_http(move |request| {
+            std::thread::sleep(delay);
+            let mut received = received.lock().unwrap();
+            received.push((Instant::now(), request.clone()));
+            (statuses.get(received.len() - 1).copied().unwrap_or(200), String::new())
+        });
+        (format!("{}/hook", url), requests)
+    }
+
+    /// Waits up to ten seconds for `condition` to hold.
+    async fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[test]
+    fn sign_is_hmac_sha256() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn run_delivery_signs_retries_and_removes_delivered_webhooks() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let (url, requests) = serve_webhooks(vec![500], Duration::ZERO);
+        let config = webhook_config(vec![url.clone()]);
+        let messages = config.messages(WebhookEvent::Reorg, None, &json!({ "fork_height": 1 }));
+        sqlite.commit_block(&[], &messages).await.unwrap();
+
+        let delivery = tokio::spawn(run_delivery(sqlite.clone(), config, disabled_email_config()));
+        wait_for(|| requests.lock().unwrap().len() == 2).await;
+        let mut queued = Vec::new();
+        for _ in 0..20 {
+            queued = sqlite.get_due_webhooks(&url, i64::MAX, u32::MAX, 10).await.unwrap();
+            if queued.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        delivery.abort();
+
+        // Delivered messages leave the outbox
+        assert!(queued.is_empty(), "{:?}", queued);
+        let requests = requests.lock().unwrap();
+        let (failed_at, failed) = &requests[0];
+        let (delivered_at, delivered) = &requests[1];
+        // The first retry waits retry_base_seconds
+        assert!(*delivered_at - *failed_at >= Duration::from_secs(2), "{:?}", *delivered_at - *failed_at);
+        for request in [failed, delivered] {
+            assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/hook"));
+            assert_eq!(request.body, messages[0].body);
+            assert_eq!(request.headers["x-gabriel-event"], "reorg");
+            assert_eq!(request.headers["x-gabriel-signature"], format!("sha256={}", sign(SECRET, &request.body)));
+        }
+        assert_eq!(failed.headers["x-gabriel-delivery"], delivered.headers["x-gabriel-delivery"]);
+    }
+
+    #[tokio::test]
+    async fn run_delivery_gives_up_after_max_attempts() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let (url, requests) = serve_webhooks(vec![500; 3], Duration::ZERO);
+        let config = Box::leak(Box::new(WebhookConfig {
+            retry_base_seconds: 0,
+            ..webhook_config(vec![url.clone()]).clone()
+        }));
+        sqlite
+            .commit_block(&[], &config.messages(WebhookEvent::Reorg, None, &json!({ "fork_height": 1 })))
+            .await
+            .unwrap();
+
+        let delivery = tokio::spawn(run_delivery(sqlite.clone(), config, disabled_email_config()));
+        wait_for(|| requests.lock().unwrap().len() == 3).await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        delivery.abort();
+
+        // The message stays in the outbox without being attempted again
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        let queued = sqlite.get_due_webhooks(&url, i64::MAX, u32::MAX, 10).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn run_delivery_gives_up_on_emails_after_their_own_max_attempts() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let rejected = Arc::new(Mutex::new(0));
+        let sink = Arc::clone(&rejected);
+        let smtp_port = test_fixtures::serve_smtp(move |_| {
+            *sink.lock().unwrap() += 1;
+            false
+        });
+        let config = Box::leak(Box::new(WebhookConfig {
+            max_attempts: 5,
+            retry_base_seconds: 0,
+            ..webhook_config(Vec::new()).clone()
+        }));
+        let email_config = Box::leak(Box::new(EmailConfig {
+            smtp_port,
+            from: Some("gabriel@example.com".parse().unwrap()),
+            recipients: vec!["oncall@example.com".parse().unwrap()],
+            max_attempts: 2,
+            ..disabled_email_config().clone()
+        }));
+        let alert = Alert {
+            block_height: 830000,
+            block_time: 1713571767,
+            address_type: "p2pk".to_string(),
+            rule: "cohort_spend".to_string(),
+            severity: Severity::Critical,
+            message: "Cohort output spent".to_string(),
+            value_sats: 5_000_000_000,
+        };
+        sqlite.commit_block(&[], &email_config.messages(&alert)).await.unwrap();
+
+        let delivery = tokio::spawn(run_delivery(sqlite.clone(), config, email_config));
+        wait_for(|| *rejected.lock().unwrap() == 2).await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        delivery.abort();
+
+        assert_eq!(*rejected.lock().unwrap(), 2);
+        let queued = sqlite.get_due_webhooks(&email_config.url(), i64::MAX, u32::MAX, 10).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn run_delivery_is_not_held_up_by_a_slow_url() {
+        let (sqlite, _dir) = test_fixtures::temporary_sqlite().await;
+        let (slow_url, slow_requests) = serve_webhooks(Vec::new(), Duration::from_secs(3));
+        let (fast_url, fast_requests) = serve_webhooks(Vec::new(), Duration::ZERO);
+        let config = webhook_config(vec![slow_url, fast_url]);
+        // Both events are queued for the slow URL first
+        for fork_height in 1..=2 {
+            sqlite
+                .commit_block(&[], &config.messages(WebhookEvent::Reorg, None, &json!({ "fork_height": fork_height })))
+                .await
+                .unwrap();
+        }
+
+        let started = Instant::now();
+        let delivery = tokio::spawn(run_delivery(sqlite.clone(), config, disabled_email_config()));
+        wait_for(|| fast_requests.lock().unwrap().len() == 2).await;
+        delivery.abort();
+
+        assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+        assert!(slow_requests.lock().unwrap().is_empty());
+    }
+}
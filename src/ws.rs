@@ -0,0 +1,423 @@
+use std::sync::Arc;
+
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::AppState;
+use crate::persistence::SQLitePersistence;
+use crate::util::{BtcAddressType, StreamEvent};
+use crate::ApiError;
+
+/// Number of records answering a backfill request when no limit is given.
+const DEFAULT_BACKFILL_LIMIT: i64 = 100;
+/// Upper bound on the limit of a backfill request.
+const MAX_BACKFILL_LIMIT: i64 = 1000;
+/// Topics whose history can be requested with a backfill message.
+const BACKFILL_TOPICS: &[&str] = &["aggregate", "spend"];
+
+/// Messages sent by clients of `/api/ws`, tagged by their `action`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Adds topics and address types to the subscription.
+    Subscribe {
+        #[serde(default)]
+        topics: Vec<String>,
+        #[serde(default)]
+        address_types: Vec<String>,
+    },
+    /// Removes topics and address types from the subscription.
+    Unsubscribe {
+        #[serde(default)]
+        topics: Vec<String>,
+        #[serde(default)]
+        address_types: Vec<String>,
+    },
+    /// Requests the stored records of a topic and address type in a range of blocks.
+    Backfill {
+        topic: String,
+        address_type: String,
+        from_height: i64,
+        to_height: Option<i64>,
+        limit: Option<i64>,
+    },
+}
+
+/// What a connection is sent of the live feed.  Events about an address type are only sent when that
+/// address type is subscribed to as well as their topic.
+struct Subscription {
+    topics: Vec<&'static str>,
+    address_types: Vec<BtcAddressType>,
+}
+
+impl Subscription {
+    /// Connections start without topics, and with every tracked address type.
+    fn new() -> Self {
+        Subscription {
+            topics: Vec::new(),
+            address_types: BtcAddressType::TRACKED.to_vec(),
+        }
+    }
+
+    fn includes(&self, event: &StreamEvent) -> bool {
+        self.topics.contains(&event.topic())
+            && event.address_type().is_none_or(|address_type| {
+                self.address_types.iter().any(|subscribed| subscribed.as_str() == address_type)
+            })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "subscription",
+            "topics": self.topics,
+            "address_types": self.address_types.iter().map(BtcAddressType::as_str).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Upgrades the connection to a WebSocket that streams the events published by block processing.
+/// Requests that aren't WebSocket handshakes are answered with the JSON error body of the other handlers.
+pub(crate) async fn stream_events(
+    State(state): State<Arc<AppState>>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    match ws {
+        Ok(ws) => ws.on_upgrade(move |socket| handle_socket(socket, state)),
+        Err(rejection) => ApiError {
+            status: rejection.status(),
+            message: rejection.body_text(),
+            param: None,
+        }
+        .into_response(),
+    }
+}
+
+async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
+    let mut rx = state.sender.subscribe();
+    let mut subscription = Subscription::new();
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_client_message(&state.db, &mut subscription, &text).await,
+                Some(Ok(Message::Binary(_))) => error_json("Messages must be JSON text".to_string()),
+                // Pings are answered by axum
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            event = rx.recv() => match event {
+                Ok(event) => match event_json(&subscription, &event) {
+                    Some(reply) => reply,
+                    None => continue,
+                },
+                // Tell the client, which can backfill what it missed
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client lagged behind by {} events", skipped);
+                    lagged_json(skipped)
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if socket.send(Message::Text(reply.to_string())).await.is_err() {
+            break;
+        }
+    }
+    debug!("WebSocket client disconnected");
+}
+
+/// Returns the message carrying a live event, or None if the connection isn't subscribed to it.
+fn event_json(subscription: &Subscription, event: &StreamEvent) -> Option<Value> {
+    subscription.includes(event).then(|| {
+        json!({
+            "type": "event",
+            "topic": event.topic(),
+            "address_type": event.address_type(),
+            "data": event.data(),
+        })
+    })
+}
+
+/// Returns the message telling a client that it missed `skipped` events.
+fn lagged_json(skipped: u64) -> Value {
+    json!({ "type": "lagged", "skipped": skipped })
+}
+
+/// Applies a message from the client and returns the reply.
+async fn handle_client_message(db: &SQLitePersistence, subscription: &mut Subscription, text: &str) -> Value {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => return error_json(format!("Invalid message: {}", e)),
+    };
+
+    match message {
+        ClientMessage::Subscribe { topics, address_types } => {
+            let (topics, address_types) =
+                match (parse_topics(&topics), parse_address_types("address_types", &address_types)) {
+                    (Ok(topics), Ok(address_types)) => (topics, address_types),
+                    (Err(e), _) | (_, Err(e)) => return error_json(e),
+                };
+            for topic in topics {
+                if !subscription.topics.contains(&topic) {
+                    subscription.topics.push(topic);
+                }
+            }
+            for address_type in address_types {
+                if !subscription.address_types.contains(&address_type) {
+                    subscription.address_types.push(address_type);
+                }
+            }
+            subscription.to_json()
+        }
+        ClientMessage::Unsubscribe { topics, address_types } => {
+            let (topics, address_types) =
+                match (parse_topics(&topics), parse_address_types("address_types", &address_types)) {
+                    (Ok(topics), Ok(address_types)) => (topics, address_types),
+                    (Err(e), _) | (_, Err(e)) => return error_json(e),
+                };
+            subscription.topics.retain(|topic| !topics.contains(topic));
+            subscription
+                .address_types
+                .retain(|address_type| !address_types.contains(address_type));
+            subscription.to_json()
+        }
+        ClientMessage::Backfill { topic, address_type, from_height, to_height, limit } => {
+            match backfill(db, &topic, &address_type, from_height, to_height, limit).await {
+                Ok(reply) => reply,
+                Err(e) => error_json(e),
+            }
+        }
+    }
+}
+
+/// Returns the stored records of `topic` for the blocks `from_height` to `to_height`, oldest first.
+async fn backfill(
+    db: &SQLitePersistence,
+    topic: &str,
+    address_type: &str,
+    from_height: i64,
+    to_height: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Value, String> {
+    let address_type = parse_address_types("address_type", &[address_type.to_string()])?[0];
+    if from_height < 0 {
+        return Err("from_height must be a non-negative integer".to_string());
+    }
+    let to_height = to_height.unwrap_or(i64::MAX);
+    let limit = match limit {
+        Some(limit) if limit < 0 => return Err("limit must be a non-negative integer".to_string()),
+        Some(limit) => limit.min(MAX_BACKFILL_LIMIT),
+        None => DEFAULT_BACKFILL_LIMIT,
+    };
+
+    let table_type = address_type.as_str().to_string();
+    let data = match topic {
+        "aggregate" => db
+            .get_block_aggregates_between(table_type, from_height, to_height, limit)
+            .await
+            .map(|blocks| json!(blocks)),
+        "spend" => db
+            .get_spends_between(table_type, from_height, to_height, limit)
+            .await
+            .map(|spends| json!(spends)),
+        _ => {
+            return Err(format!(
+                "topic must be one of {} to backfill: {}",
+                BACKFILL_TOPICS.join(", "),
+                topic
+            ))
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    Ok(json!({
+        "type": "backfill",
+        "topic": topic,
+        "address_type": address_type.as_str(),
+        "data": data,
+    }))
+}
+
+fn parse_topics(topics: &[String]) -> Result<Vec<&'static str>, String> {
+    topics
+        .iter()
+        .map(|topic| {
+            StreamEvent::TOPICS
+                .iter()
+                .find(|known| **known == topic.as_str())
+                .copied()
+                .ok_or_else(|| format!("topics must be among {}: {}", StreamEvent::TOPICS.join(", "), topic))
+        })
+        .collect()
+}
+
+/// Parses tracked address types, naming the `param` they were given in if one isn't.
+fn parse_address_types(param: &str, address_types: &[String]) -> Result<Vec<BtcAddressType>, String> {
+    address_types
+        .iter()
+        .map(|address_type| match address_type.parse::<BtcAddressType>() {
+            Ok(parsed) if BtcAddressType::TRACKED.contains(&parsed) => Ok(parsed),
+            _ => Err(format!(
+                "{} must be among {}: {}",
+                param,
+                BtcAddressType::TRACKED.iter().map(BtcAddressType::as_str).collect::<Vec<_>>().join(", "),
+                address_type
+            )),
+        })
+        .collect()
+}
+
+fn error_json(message: String) -> Value {
+    json!({ "type": "error", "message": message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto::common::bitcoin::{OutPoint, Script};
+    use tempfile::TempDir;
+    use tokio::sync::broadcast;
+
+    use crate::block_source::{BlockSource, VecBlockSource};
+    use crate::test_fixtures::{self, chain, coinbase, output, p2pk_script, process, spend};
+    use crate::util::{BlockAggregateOutput, ReorgOutput};
+
+    /// Number of P2PK outputs created by block 1 and spent by block 2.
+    const SPENT_OUTPUTS: u32 = 1001;
+
+    /// A database with the aggregates of blocks 1 and 2, and more spends by block 2 than a backfill returns.
+    async fn database_with_spends() -> (SQLitePersistence, TempDir) {
+        let (sqlite, dir) = test_fixtures::temporary_sqlite().await;
+        // Block 0 isn't scanned, so its coinbase output isn't tracked
+        let genesis_output = OutPoint::new(coinbase(0, vec![output(5_000_000_000, p2pk_script())]).txid(), 0);
+        let fan_out = spend(&[genesis_output], (0..SPENT_OUTPUTS).map(|_| output(1000, p2pk_script())).collect());
+        let fan_out_outputs = (0..SPENT_OUTPUTS).map(|vout| OutPoint::new(fan_out.txid(), vout)).collect::<Vec<_>>();
+        let blocks = chain(3, |height| match height {
+            1 => vec![fan_out.clone()],
+            2 => vec![spend(&fan_out_outputs, vec![output(1_001_000, Script::new())])],
+            _ => Vec::new(),
+        });
+
+        let block_source = VecBlockSource::new(blocks);
+        block_source.request_blocks(1..=2).unwrap();
+        process(block_source, &sqlite, broadcast::channel(100).0, &[]).await;
+        (sqlite, dir)
+    }
+
+    async fn reply(db: &SQLitePersistence, text: &str) -> Value {
+        handle_client_message(db, &mut Subscription::new(), text).await
+    }
+
+    fn aggregate(address_type: BtcAddressType) -> StreamEvent {
+        let date = "2009-01-09 03:54:25".to_string();
+        StreamEvent::Aggregate(address_type, BlockAggregateOutput::new(date, 2, "00".repeat(32), 1, 5_000_000_000.0))
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_answered_with_errors() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        let cases = [
+            (r#"{"action": "subscribe", "topics": ["blocks"]}"#, "topics must be among aggregate, spend"),
+            (r#"{"action": "subscribe", "address_types": ["p2sh"]}"#, "address_types must be among p2pk"),
+            (
+                r#"{"action": "backfill", "topic": "alert", "address_type": "p2pk", "from_height": 0}"#,
+                "topic must be one of aggregate, spend to backfill: alert",
+            ),
+            (
+                r#"{"action": "backfill", "topic": "spend", "address_type": "p2wsh", "from_height": 0}"#,
+                "address_type must be among p2pk",
+            ),
+            (r#"{"action": "backfill", "topic": "spend", "from_height": 0}"#, "missing field `address_type`"),
+            (
+                r#"{"action": "backfill", "topic": "spend", "address_type": "p2pk", "from_height": -1}"#,
+                "from_height must be a non-negative integer",
+            ),
+            (
+                r#"{"action": "backfill", "topic": "spend", "address_type": "p2pk", "from_height": 0, "limit": -1}"#,
+                "limit must be a non-negative integer",
+            ),
+        ];
+
+        for (message, error) in cases {
+            let reply = reply(&db, message).await;
+            assert_eq!(reply["type"], "error", "{}", message);
+            assert!(reply["message"].as_str().unwrap().contains(error), "{}: {}", message, reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn backfill_returns_the_records_of_the_address_type_up_to_the_limit() {
+        let (db, _dir) = database_with_spends().await;
+
+        let spends = |address_type: &str, limit: &str| {
+            format!(
+                r#"{{"action": "backfill", "topic": "spend", "address_type": "{}", "from_height": 0{}}}"#,
+                address_type, limit
+            )
+        };
+        let reply_count = |reply: Value| reply["data"].as_array().unwrap().len() as i64;
+        assert_eq!(reply_count(reply(&db, &spends("p2pk", "")).await), DEFAULT_BACKFILL_LIMIT);
+        assert_eq!(reply_count(reply(&db, &spends("p2pk", r#", "limit": 5000"#)).await), MAX_BACKFILL_LIMIT);
+        assert_eq!(reply_count(reply(&db, &spends("p2tr", "")).await), 0);
+
+        let aggregates = reply(
+            &db,
+            r#"{"action": "backfill", "topic": "aggregate", "address_type": "p2pk", "from_height": 2}"#,
+        )
+        .await;
+        assert_eq!(aggregates["type"], "backfill");
+        assert_eq!(aggregates["address_type"], "p2pk");
+        let blocks = aggregates["data"].as_array().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0]["block_height"], 2);
+    }
+
+    #[tokio::test]
+    async fn events_are_sent_for_subscribed_topics_and_address_types() {
+        let (db, _dir) = test_fixtures::temporary_sqlite().await;
+        let mut subscription = Subscription::new();
+        let reorg = StreamEvent::Reorg(ReorgOutput {
+            fork_height: 1,
+            stale_tip_height: 2,
+            stale_tip_hash_big_endian: "00".repeat(32),
+        });
+        assert!(event_json(&subscription, &aggregate(BtcAddressType::P2PK)).is_none());
+
+        handle_client_message(&db, &mut subscription, r#"{"action": "subscribe", "topics": ["aggregate"]}"#).await;
+        let reply = handle_client_message(
+            &db,
+            &mut subscription,
+            r#"{"action": "unsubscribe", "address_types": ["p2tr"]}"#,
+        )
+        .await;
+        assert_eq!(reply["topics"], json!(["aggregate"]));
+        assert_eq!(reply["address_types"], json!(["p2pk", "reused_pkh", "p2ms"]));
+
+        let event = event_json(&subscription, &aggregate(BtcAddressType::P2PK)).unwrap();
+        assert_eq!((&event["topic"], &event["address_type"]), (&json!("aggregate"), &json!("p2pk")));
+        assert_eq!(event["data"]["block_height"], 2);
+        assert!(event_json(&subscription, &aggregate(BtcAddressType::P2TR)).is_none());
+        assert!(event_json(&subscription, &reorg).is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged_clients_are_told_how_many_events_they_missed() {
+        let (sender, mut rx) = broadcast::channel(1);
+        for _ in 0..3 {
+            sender.send(aggregate(BtcAddressType::P2PK)).unwrap();
+        }
+
+        match rx.recv().await {
+            Err(RecvError::Lagged(skipped)) => {
+                assert_eq!(lagged_json(skipped), json!({ "type": "lagged", "skipped": 2 }))
+            }
+            other => panic!("expected the receiver to lag: {:?}", other.map(|event| event.topic())),
+        }
+    }
+}
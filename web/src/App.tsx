@@ -3,6 +3,7 @@ import { BrowserRouter as Router, Route, Routes } from 'react-router-dom';
 import { QueryClient, QueryClientProvider } from '@tanstack/react-query';
 import P2PKBlocksGraph from './components/P2PKBlocksGraph';
 import BlockStream from './components/BlockStream';
+import BlockDetails from './components/BlockDetails';
 import './App.css';
 
 const queryClient = new QueryClient();
@@ -33,6 +34,7 @@ function App() {
           </h1>
           <Routes>
             <Route path="/p2pk-blocks-graph" element={<P2PKBlocksGraph />} />
+            <Route path="/:addressType/block/:height" element={<BlockDetails />} />
             <Route path="/" element={
               <div 
                 className="grid gap-4" 
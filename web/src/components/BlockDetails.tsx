@@ -0,0 +1,64 @@
+import { useQuery } from '@tanstack/react-query';
+import axios from 'axios';
+import { useParams } from 'react-router-dom';
+import { API_ENDPOINTS } from '../config/api';
+
+/**
+ * Totals of one address type at a block, linked to by the alert emails.
+ */
+
+interface AddressTypeBlock {
+  date: string;
+  block_height: number;
+  block_hash: string;
+  total_utxos: number;
+  total_sats: number;
+  coin_days_destroyed: number | null;
+}
+
+interface ApiErrorBody {
+  error: string;
+}
+
+function BlockDetails() {
+  const { addressType = 'p2pk', height = '' } = useParams();
+
+  const { data, isLoading, error } = useQuery({
+    queryKey: ['block', addressType, height],
+    queryFn: async () => {
+      const response = await axios.get<AddressTypeBlock>(API_ENDPOINTS.addressTypeBlockByHeight(addressType, height), {
+        timeout: 20000,
+      });
+      return response.data;
+    },
+    retry: false,
+  });
+
+  if (isLoading) return <div>Loading...</div>;
+  if (error || !data) {
+    const message = axios.isAxiosError<ApiErrorBody>(error) ? error.response?.data?.error : undefined;
+    return <div>{message || 'Error loading block'}</div>;
+  }
+
+  return (
+    <div>
+      <h2 className="text-xl font-bold mb-4">{`${addressType.toUpperCase()} at block ${data.block_height}`}</h2>
+      <table>
+        <tbody>
+          <tr><th>Date</th><td>{data.date}</td></tr>
+          <tr><th>Block hash</th><td>{data.block_hash}</td></tr>
+          <tr><th>UTXOs</th><td>{data.total_utxos.toLocaleString()}</td></tr>
+          <tr>
+            <th>Value (BTC)</th>
+            <td>{(data.total_sats / 100000000).toLocaleString(undefined, { maximumFractionDigits: 8 })}</td>
+          </tr>
+          {data.coin_days_destroyed !== null && (
+            <tr><th>Coin days destroyed</th><td>{data.coin_days_destroyed.toLocaleString()}</td></tr>
+          )}
+        </tbody>
+      </table>
+    </div>
+  );
+}
+
+export default BlockDetails;
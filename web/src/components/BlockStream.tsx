@@ -1,4 +1,5 @@
 import { useEffect, useState } from 'react';
+import { useQueryClient } from '@tanstack/react-query';
 import { LineChart, Line, XAxis, YAxis, CartesianGrid, Tooltip, Legend } from 'recharts';
 import { API_ENDPOINTS } from '../config/api';
 
@@ -10,21 +11,36 @@ interface BlockAggregate {
   total_p2pk_value: number;
 }
 
+interface Reorg {
+  fork_height: number;
+  stale_tip_height: number;
+  stale_tip_hash_big_endian: string;
+}
+
 function BlockStream() {
   const [blocks, setBlocks] = useState<BlockAggregate[]>([]);
+  const queryClient = useQueryClient();
 
   useEffect(() => {
-    const eventSource = new EventSource(API_ENDPOINTS.blockStream);
+    const eventSource = new EventSource(`${API_ENDPOINTS.blockStream}?topics=aggregate,reorg`);
 
     eventSource.onopen = () => {
       console.log('Connected to SSE server successfully.');
     };
 
-    eventSource.onmessage = (event) => {
-      const newBlock = JSON.parse(event.data) as BlockAggregate;
+    eventSource.addEventListener('aggregate', (event) => {
+      const newBlock = JSON.parse((event as MessageEvent).data) as BlockAggregate;
       console.log('New block received:', newBlock);
       setBlocks((prevBlocks) => [...prevBlocks, newBlock].slice(-50)); // Keep last 50 blocks
-    };
+    });
+
+    // Blocks above the fork height were orphaned by a chain reorganization
+    eventSource.addEventListener('reorg', (event) => {
+      const reorg = JSON.parse((event as MessageEvent).data) as Reorg;
+      console.log('Chain reorganization:', reorg);
+      setBlocks((prevBlocks) => prevBlocks.filter((block) => block.block_height <= reorg.fork_height));
+      queryClient.invalidateQueries({ queryKey: ['aggregates'] });
+    });
 
     eventSource.onerror = (error) => {
       console.error('Error with SSE connection:', error);
@@ -33,7 +49,7 @@ function BlockStream() {
     return () => {
       eventSource.close();
     };
-  }, []);
+  }, [queryClient]);
 
   return (
     <div>
@@ -5,5 +5,7 @@ export const API_ENDPOINTS = {
     latestBlocks: `${API_BASE_URL}/api/blocks/latest`,
     blockByHash: (hash: string) => `${API_BASE_URL}/api/block/hash/${hash}`,
     blockByHeight: (height: number) => `${API_BASE_URL}/api/block/height/${height}`,
+    addressTypeBlockByHeight: (addressType: string, height: string) =>
+        `${API_BASE_URL}/api/${addressType}/block/height/${height}`,
     blockStream: `${API_BASE_URL}/api/blocks/stream`,
 }; 